@@ -68,7 +68,7 @@ def setup_tables() -> None:
     cur.execute("CREATE TABLE lists (uid text PRIMARY KEY, owner REFERENCES users, name text NOT NULL, readers REFERENCES teams, editors REFERENCES teams)")
     cur.execute("""CREATE TABLE teams (uid text PRIMARY KEY)""")
     cur.execute("CREATE TABLE subteams (child_team REFERENCES teams, parent_team REFERENCES teams)")
-    cur.execute("CREATE TABLE tasks (name text NOT NULL, state bool NOT NULL, list_uid REFERENCES lists)")
+    cur.execute("CREATE TABLE tasks (name text NOT NULL, state integer NOT NULL, list_uid REFERENCES lists)")
     con.commit()
 
 class User:
@@ -100,8 +100,8 @@ class Task:
     def __init__(self, name: str) -> None:
         self.name = name
 
-    def to_tuple(self, lst_id: uuid.UUID) -> Tuple[str, bool, str]:
-        return (self.name, False, str(lst_id))
+    def to_tuple(self, lst_id: uuid.UUID) -> Tuple[str, int, str]:
+        return (self.name, 0, str(lst_id))
 
     def to_dict(self, i: int) -> dict:
         return {
@@ -16,22 +16,59 @@
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
-use serde::{Deserialize, Serialize, Serializer};
+use cedar_policy::{Decision, ParseErrors};
+use miette::Diagnostic;
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::sync::{mpsc, oneshot};
-use warp::Filter;
+use tracing::error;
+use warp::{Filter, Reply};
 
 use crate::{
-    context::{AppQuery, AppQueryKind, AppResponse, Error},
-    objects::{List, TaskState},
-    util::{EntityUid, ListUid, Lists, UserOrTeamUid, UserUid},
+    audit::AuditEvent,
+    context::{AppQuery, AppQueryKind, AppResponse, CancellationToken, Error},
+    encoding::{JsonEncoder, MsgPackEncoder, ResponseEncoder},
+    objects::{Application, List, ListSummary, Task, TaskComment, TaskState},
+    stats::RequestTiming,
+    util::{EntityUid, FolderUid, ListUid, Lists, Pagination, PolicySetSrc, TeamUid, UserOrTeamUid, UserUid},
 };
 
-type AppChannel = mpsc::Sender<AppQuery>;
+pub type AppChannel = mpsc::Sender<AppQuery>;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetList {
     pub uid: UserUid,
     pub list: ListUid,
+    /// When false, only the number of tasks is returned
+    #[serde(default = "default_true")]
+    pub include_tasks: bool,
+    /// The `version` of the client's copy of the list, which isn't sent again if it is still current
+    #[serde(default)]
+    pub if_none_match: Option<String>,
+    /// Only return these fields of the list, e.g. `name` and `total_tasks`, without its tasks.
+    /// In a query string, the fields are separated by commas.
+    #[serde(default, deserialize_with = "deserialize_fields")]
+    pub fields: Option<Vec<String>>,
+}
+
+fn deserialize_fields<'de, D>(d: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Fields {
+        List(Vec<String>),
+        Csv(String),
+    }
+
+    Ok(Option::<Fields>::deserialize(d)?.map(|fields| match fields {
+        Fields::List(fields) => fields,
+        Fields::Csv(fields) => fields.split(',').map(|field| field.trim().to_string()).collect(),
+    }))
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl From<GetList> for AppQueryKind {
@@ -40,10 +77,16 @@ impl From<GetList> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateList {
     pub uid: UserUid,
     pub name: String,
+    /// The application the list belongs to, `Application::"TinyTodo"` by default
+    #[serde(default)]
+    pub tenant: Option<EntityUid>,
+    /// Only check that the list could be created, without creating it
+    #[serde(default)]
+    pub validate_only: bool,
 }
 
 impl From<CreateList> for AppQueryKind {
@@ -52,11 +95,13 @@ impl From<CreateList> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateList {
     pub uid: UserUid,
     pub list: ListUid,
     pub name: String,
+    #[serde(default)]
+    pub priority_threshold: Option<i64>,
 }
 
 impl From<UpdateList> for AppQueryKind {
@@ -65,12 +110,138 @@ impl From<UpdateList> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateFolder {
+    pub uid: UserUid,
+    pub name: String,
+}
+
+impl From<CreateFolder> for AppQueryKind {
+    fn from(v: CreateFolder) -> AppQueryKind {
+        AppQueryKind::CreateFolder(v)
+    }
+}
+
+/// Put `list` in `folder`, or take it out of its folder if `folder` is missing.
+/// The caller must be able to update the list and add to the folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoveListToFolder {
+    pub uid: UserUid,
+    pub list: ListUid,
+    #[serde(default)]
+    pub folder: Option<FolderUid>,
+}
+
+impl From<MoveListToFolder> for AppQueryKind {
+    fn from(v: MoveListToFolder) -> AppQueryKind {
+        AppQueryKind::MoveListToFolder(v)
+    }
+}
+
+/// Why `list` is missing from the caller's lists: the forbid policies denying them `GetList` on it,
+/// or `no permit matched`. Empty if they may read the list after all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExplainListExclusion {
+    pub uid: UserUid,
+    pub list: ListUid,
+}
+
+impl From<ExplainListExclusion> for AppQueryKind {
+    fn from(v: ExplainListExclusion) -> AppQueryKind {
+        AppQueryKind::ExplainListExclusion(v)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+/// Render a list and its tasks for use outside the app
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportList {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub format: ExportFormat,
+}
+
+impl From<ExportList> for AppQueryKind {
+    fn from(v: ExportList) -> AppQueryKind {
+        AppQueryKind::ExportList(v)
+    }
+}
+
+/// Create a list owned by `uid` from a Markdown checklist, like the ones `ExportList` produces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportList {
+    pub uid: UserUid,
+    pub name: String,
+    pub markdown: String,
+}
+
+impl From<ImportList> for AppQueryKind {
+    fn from(v: ImportList) -> AppQueryKind {
+        AppQueryKind::ImportList(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedList {
+    pub list: ListUid,
+    /// Lines which weren't tasks, or whose task names were invalid
+    pub skipped: usize,
+}
+
+/// Attach display metadata (e.g. a color) to a list. `metadata` must be a JSON object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetListMetadata {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub metadata: serde_json::Value,
+}
+
+impl From<SetListMetadata> for AppQueryKind {
+    fn from(v: SetListMetadata) -> AppQueryKind {
+        AppQueryKind::SetListMetadata(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetListTemplate {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub is_template: bool,
+}
+
+impl From<SetListTemplate> for AppQueryKind {
+    fn from(v: SetListTemplate) -> AppQueryKind {
+        AppQueryKind::SetListTemplate(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateList {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub name: Option<String>, // defaults to the name of the source list
+}
+
+impl From<DuplicateList> for AppQueryKind {
+    fn from(v: DuplicateList) -> AppQueryKind {
+        AppQueryKind::DuplicateList(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddShare {
     pub uid: UserUid,
     pub list: ListUid,
     pub share_with: UserOrTeamUid,
-    pub role: ShareRole,
+    /// Defaults to `AppConfig::default_share_role`
+    #[serde(default)]
+    pub role: Option<ShareRole>,
 }
 
 impl From<AddShare> for AppQueryKind {
@@ -79,13 +250,53 @@ impl From<AddShare> for AppQueryKind {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddShares {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub share_with: Vec<UserOrTeamUid>,
+    /// Defaults to `AppConfig::default_share_role`
+    #[serde(default)]
+    pub role: Option<ShareRole>,
+    #[serde(default)]
+    pub partial: bool,
+}
+
+impl From<AddShares> for AppQueryKind {
+    fn from(v: AddShares) -> AppQueryKind {
+        AppQueryKind::AddShares(v)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub enum ShareRole {
     Reader,
     Editor,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Whether `user` can access `list` in `role` now, and whether they could after sharing it with `share_with`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewShare {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub share_with: UserOrTeamUid,
+    pub role: ShareRole,
+    pub user: UserUid,
+}
+
+impl From<PreviewShare> for AppQueryKind {
+    fn from(v: PreviewShare) -> AppQueryKind {
+        AppQueryKind::PreviewShare(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SharePreview {
+    pub has_access: bool,
+    pub would_have_access: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeleteShare {
     pub uid: UserUid,
     pub list: ListUid,
@@ -99,7 +310,34 @@ impl From<DeleteShare> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Give `owner` the same rights over `list` as its primary owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddOwner {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub owner: UserUid,
+}
+
+impl From<AddOwner> for AppQueryKind {
+    fn from(v: AddOwner) -> AppQueryKind {
+        AppQueryKind::AddOwner(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveOwner {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub owner: UserUid,
+}
+
+impl From<RemoveOwner> for AppQueryKind {
+    fn from(v: RemoveOwner) -> AppQueryKind {
+        AppQueryKind::RemoveOwner(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeleteList {
     pub uid: UserUid,
     pub list: ListUid,
@@ -111,9 +349,59 @@ impl From<DeleteList> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Stop `list` and its tasks from being changed, while leaving them readable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockList {
+    pub uid: UserUid,
+    pub list: ListUid,
+}
+
+impl From<LockList> for AppQueryKind {
+    fn from(v: LockList) -> AppQueryKind {
+        AppQueryKind::LockList(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnlockList {
+    pub uid: UserUid,
+    pub list: ListUid,
+}
+
+impl From<UnlockList> for AppQueryKind {
+    fn from(v: UnlockList) -> AppQueryKind {
+        AppQueryKind::UnlockList(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetLists {
     pub uid: UserUid,
+    /// Only return lists belonging to this application, `Application::"TinyTodo"` by default
+    #[serde(default)]
+    pub tenant: Option<EntityUid>,
+    /// Only return lists whose name contains this
+    #[serde(default)]
+    pub name: Option<String>,
+    /// With either of `offset` or `limit`, only return that page of lists, along with the paging details
+    #[serde(default)]
+    pub offset: Option<usize>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Also return the lists' display names, as `labels`
+    #[serde(default)]
+    pub labels: bool,
+    /// By default, lists are sorted by uid
+    #[serde(default)]
+    pub sort: Option<ListSort>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListSort {
+    Uid,
+    /// By name, then by uid for lists with the same name
+    Name,
 }
 
 impl From<GetLists> for AppQueryKind {
@@ -122,13 +410,461 @@ impl From<GetLists> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Identifies the set of lists captured by the first page of a `GetListsPage` enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ResultSetToken(pub u64);
+
+/// Page through the lists `uid` may read. The first request (without `result_set`) captures the
+/// uids of the authorized lists; passing the returned token on later requests pages through that same set,
+/// so shares made mid-enumeration don't shift pages. Only the set is kept, not a database snapshot:
+/// a list deleted since the first page is still listed, and anything looked up per list is current.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetListsPage {
+    pub uid: UserUid,
+    #[serde(default)]
+    pub result_set: Option<ResultSetToken>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl From<GetListsPage> for AppQueryKind {
+    fn from(v: GetListsPage) -> AppQueryKind {
+        AppQueryKind::GetListsPage(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListsPage {
+    pub result_set: ResultSetToken,
+    pub total: usize,
+    pub lists: Lists,
+}
+
+/// The teams `user` (by default, the caller) belongs to, including through subteams
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetMyTeams {
+    pub uid: UserUid,
+    #[serde(default)]
+    pub user: Option<UserUid>,
+}
+
+impl From<GetMyTeams> for AppQueryKind {
+    fn from(v: GetMyTeams) -> AppQueryKind {
+        AppQueryKind::GetMyTeams(v)
+    }
+}
+
+/// The lists the caller is the primary owner of, out of those they may read
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetMyOwnedLists {
+    pub uid: UserUid,
+}
+
+impl From<GetMyOwnedLists> for AppQueryKind {
+    fn from(v: GetMyOwnedLists) -> AppQueryKind {
+        AppQueryKind::GetMyOwnedLists(v)
+    }
+}
+
+/// Like `GetLists`, but also returns each list's name, owner, and reader/editor teams
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetListsDetailed {
+    pub uid: UserUid,
+}
+
+impl From<GetListsDetailed> for AppQueryKind {
+    fn from(v: GetListsDetailed) -> AppQueryKind {
+        AppQueryKind::GetListsDetailed(v)
+    }
+}
+
+/// Which of the application-wide actions (`CreateList`, `GetLists`) the caller may perform
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetApplicationCapabilities {
+    pub uid: UserUid,
+}
+
+impl From<GetApplicationCapabilities> for AppQueryKind {
+    fn from(v: GetApplicationCapabilities) -> AppQueryKind {
+        AppQueryKind::GetApplicationCapabilities(v)
+    }
+}
+
+/// Admins only: the authorization decisions made for `target` as the principal, newest first,
+/// at most `MAX_AUDIT_PAGE` at a time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetUserActivity {
+    pub uid: UserUid,
+    pub target: UserUid,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl From<GetUserActivity> for AppQueryKind {
+    fn from(v: GetUserActivity) -> AppQueryKind {
+        AppQueryKind::GetUserActivity(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserActivity {
+    pub events: Vec<AuditEvent>,
+    pub pagination: Pagination,
+}
+
+/// Admins only: the SQL `GetLists` would run for `user` (by default, the caller), without running it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetListsSql {
+    pub uid: UserUid,
+    #[serde(default)]
+    pub user: Option<UserUid>,
+}
+
+impl From<GetListsSql> for AppQueryKind {
+    fn from(v: GetListsSql) -> AppQueryKind {
+        AppQueryKind::GetListsSql(v)
+    }
+}
+
+/// Operator command: maintain a SQL view named `<application>_<view_name>` containing the lists `principal` may read
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAuthorizedView {
+    pub principal: UserUid,
+    pub view_name: String,
+}
+
+impl From<CreateAuthorizedView> for AppQueryKind {
+    fn from(v: CreateAuthorizedView) -> AppQueryKind {
+        AppQueryKind::CreateAuthorizedView(v)
+    }
+}
+
+/// Operator command: preview which lists `principal` would gain or lose read access to under `policies`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewPolicy {
+    pub principal: UserUid,
+    pub policies: PolicySetSrc,
+}
+
+impl From<PreviewPolicy> for AppQueryKind {
+    fn from(v: PreviewPolicy) -> AppQueryKind {
+        AppQueryKind::PreviewPolicy(v)
+    }
+}
+
+/// Run `queries` in order, in a single round trip. Only queries made by a user may be batched.
+/// With `atomic`, they share a transaction, and any failure rolls back the whole batch and is returned alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Batch {
+    pub queries: Vec<AppQueryKind>,
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+impl From<Batch> for AppQueryKind {
+    fn from(v: Batch) -> AppQueryKind {
+        AppQueryKind::Batch(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorpusRequest {
+    pub principal: EntityUid,
+    pub action: EntityUid,
+    pub resource: EntityUid,
+}
+
+/// Where a policy set failed to parse. `line` and `col` count from 1, and are
+/// missing for errors the parser couldn't place in the source.
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyParseError {
+    pub message: String,
+    pub line: Option<usize>,
+    pub col: Option<usize>,
+}
+
+impl PolicyParseError {
+    pub fn from_parse_errors(src: &str, errors: &ParseErrors) -> Vec<Self> {
+        errors
+            .iter()
+            .map(|error| {
+                let offset = error.labels().and_then(|mut labels| labels.next()).map(|label| label.offset());
+                let (line, col) = offset.map(|offset| line_col(src, offset)).unzip();
+                Self { message: error.to_string(), line, col }
+            })
+            .collect()
+    }
+}
+
+// The 1-based line and column of byte `offset` in `src`. An offset inside a multi-byte character,
+// or past the end, is moved back to the start of the character, or to the end.
+fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let offset = (0..=offset.min(src.len())).rev().find(|i| src.is_char_boundary(*i)).unwrap_or(0);
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    (line, col)
+}
+
+/// Operator command: decide each of `requests`, under `policies` if given and the current policies otherwise.
+/// Re-running a saved corpus after a policy edit shows which decisions changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvaluateCorpus {
+    pub requests: Vec<CorpusRequest>,
+    #[serde(default)]
+    pub policies: Option<PolicySetSrc>,
+}
+
+impl From<EvaluateCorpus> for AppQueryKind {
+    fn from(v: EvaluateCorpus) -> AppQueryKind {
+        AppQueryKind::EvaluateCorpus(v)
+    }
+}
+
+/// Operator command: decide `request` under the current policies and under `policies`,
+/// showing which policies account for any change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiffAuthorization {
+    pub request: CorpusRequest,
+    pub policies: PolicySetSrc,
+}
+
+impl From<DiffAuthorization> for AppQueryKind {
+    fn from(v: DiffAuthorization) -> AppQueryKind {
+        AppQueryKind::DiffAuthorization(v)
+    }
+}
+
+/// Operator command: decide a request under the current policies, reporting the entities
+/// the authorizer saw while doing so. Nothing is audited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExplainRequest {
+    pub principal: EntityUid,
+    pub action: EntityUid,
+    pub resource: EntityUid,
+}
+
+impl From<ExplainRequest> for AppQueryKind {
+    fn from(v: ExplainRequest) -> AppQueryKind {
+        AppQueryKind::ExplainRequest(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub decision: Decision,
+    pub reasons: Vec<String>,
+    pub errors: Vec<String>,
+    /// Every entity loaded for the request, with its ancestors. An entity the policies refer to
+    /// which is missing here was not found in the database.
+    pub entities: Vec<LoadedEntity>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadedEntity {
+    pub uid: EntityUid,
+    pub ancestors: Vec<EntityUid>,
+}
+
+/// Operator command: create every list in `lists` as `ImportList` would, in a single bulk load.
+/// Nothing is authorized, and if any list fails none is imported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkImport {
+    pub lists: Vec<ImportList>,
+}
+
+impl From<BulkImport> for AppQueryKind {
+    fn from(v: BulkImport) -> AppQueryKind {
+        AppQueryKind::BulkImport(v)
+    }
+}
+
+/// Operator command: go back to a policy set from `GetPolicyHistory`, by default the one in use
+/// before the current one. The policy file is left alone, so editing it later still takes effect.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestorePolicies {
+    #[serde(default)]
+    pub version: Option<u64>,
+}
+
+impl From<RestorePolicies> for AppQueryKind {
+    fn from(v: RestorePolicies) -> AppQueryKind {
+        AppQueryKind::RestorePolicies(v)
+    }
+}
+
+/// A policy set which was once in use, numbered in the order they were loaded
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicySnapshot {
+    pub version: u64,
+    pub policies: PolicySetSrc,
+}
+
+/// Operator command: delete `team`, which must not be the readers or editors of any list.
+/// Its members are removed from it if `detach_members` is set; otherwise it must have none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteTeam {
+    pub team: TeamUid,
+    #[serde(default)]
+    pub detach_members: bool,
+}
+
+impl From<DeleteTeam> for AppQueryKind {
+    fn from(v: DeleteTeam) -> AppQueryKind {
+        AppQueryKind::DeleteTeam(v)
+    }
+}
+
+/// Operator command: the teams `principal` is in and the lists they may read, as a graph, see `AppContext::principal_graph`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPrincipalGraph {
+    pub principal: UserUid,
+}
+
+impl From<GetPrincipalGraph> for AppQueryKind {
+    fn from(v: GetPrincipalGraph) -> AppQueryKind {
+        AppQueryKind::GetPrincipalGraph(v)
+    }
+}
+
+/// Operator command: the lists each of `principals` may perform `action` on (`GetList` by default),
+/// e.g. for an admin overview of who can see what
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetListsForPrincipals {
+    pub principals: Vec<EntityUid>,
+    #[serde(default)]
+    pub action: Option<EntityUid>,
+}
+
+impl From<GetListsForPrincipals> for AppQueryKind {
+    fn from(v: GetListsForPrincipals) -> AppQueryKind {
+        AppQueryKind::GetListsForPrincipals(v)
+    }
+}
+
+/// How a request's decision differs between two policy sets.
+/// The reasons are the ids of the policies that determined each decision.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthDiff {
+    pub old: Decision,
+    pub new: Decision,
+    pub changed: bool,
+    /// Reasons for the old decision which no longer apply
+    pub removed_reasons: Vec<String>,
+    /// Reasons for the new decision which didn't apply before
+    pub added_reasons: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PolicyImpact {
+    pub granted: Vec<ListUid>,
+    pub revoked: Vec<ListUid>,
+}
+
+/// The result of validating the current policies against the schema
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    pub passed: bool,
+    pub errors: Vec<ValidationIssue>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationIssue {
+    pub policy_id: String,
+    pub message: String,
+}
+
+/// Check whether the caller may perform `action` on `resource`, and what they must do if so
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Authorize {
+    pub uid: UserUid,
+    pub action: EntityUid,
+    pub resource: EntityUid,
+}
+
+impl From<Authorize> for AppQueryKind {
+    fn from(v: Authorize) -> AppQueryKind {
+        AppQueryKind::Authorize(v)
+    }
+}
+
+/// An `@obligation("...")` annotation on one of the policies which allowed a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Obligation {
+    pub policy_id: String,
+    pub obligation: String,
+}
+
+/// The policies still to be decided for `principal` performing `action` on some resource of
+/// `resource_type`, so a client holding the resources can finish authorization itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetResidual {
+    pub principal: UserUid,
+    pub action: EntityUid,
+    pub resource_type: String,
+}
+
+impl From<GetResidual> for AppQueryKind {
+    fn from(v: GetResidual) -> AppQueryKind {
+        AppQueryKind::GetResidual(v)
+    }
+}
+
+/// Either the decision, if it doesn't depend on the resource, or the residual policies as Cedar
+/// source text. Evaluating the residual with the resource bound gives the decision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Residual {
+    Decision(Decision),
+    Policies(PolicySetSrc),
+}
+
+/// The lists the caller viewed most recently which they may still read, most recent first.
+/// At most `limit`, or all of the `MAX_RECENT_VIEWS` remembered, are returned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRecentLists {
+    pub uid: UserUid,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl From<GetRecentLists> for AppQueryKind {
+    fn from(v: GetRecentLists) -> AppQueryKind {
+        AppQueryKind::GetRecentLists(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTemplates {
+    pub uid: UserUid,
+}
+
+impl From<GetTemplates> for AppQueryKind {
+    fn from(v: GetTemplates) -> AppQueryKind {
+        AppQueryKind::GetTemplates(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateTask {
     pub uid: UserUid,
     pub list: ListUid,
     pub task: i64,
-    pub name: Option<String>, // currently ignored
+    pub name: Option<String>,
     pub state: Option<TaskState>,
+    #[serde(default)]
+    pub visibility: Option<TaskVisibility>,
+}
+
+/// Who can see a task, out of those who can see its list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskVisibility {
+    Everyone,
+    Team(TeamUid),
 }
 
 impl From<UpdateTask> for AppQueryKind {
@@ -137,11 +873,14 @@ impl From<UpdateTask> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateTask {
     pub uid: UserUid,
     pub list: ListUid,
     pub name: String,
+    /// Only check that the task could be created, without creating it
+    #[serde(default)]
+    pub validate_only: bool,
 }
 
 impl From<CreateTask> for AppQueryKind {
@@ -150,7 +889,22 @@ impl From<CreateTask> for AppQueryKind {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateTasks {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub names: Vec<String>,
+    #[serde(default)]
+    pub partial: bool,
+}
+
+impl From<CreateTasks> for AppQueryKind {
+    fn from(v: CreateTasks) -> AppQueryKind {
+        AppQueryKind::CreateTasks(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeleteTask {
     pub uid: UserUid,
     pub list: ListUid,
@@ -163,6 +917,88 @@ impl From<DeleteTask> for AppQueryKind {
     }
 }
 
+/// Set every task in `list` to `state` at once, e.g. to check off the whole list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetAllTaskStates {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub state: TaskState,
+    /// Return the numbers of the tasks which changed, rather than just how many did
+    #[serde(default)]
+    pub return_tasks: bool,
+}
+
+impl From<SetAllTaskStates> for AppQueryKind {
+    fn from(v: SetAllTaskStates) -> AppQueryKind {
+        AppQueryKind::SetAllTaskStates(v)
+    }
+}
+
+/// The tasks changed by a bulk update, as a count unless the numbers were asked for
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ChangedTasks {
+    Count(usize),
+    Tasks(Vec<i64>),
+}
+
+/// Add a comment to the discussion on `task`, as the caller
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddTaskComment {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub task: i64,
+    pub body: String,
+}
+
+impl From<AddTaskComment> for AppQueryKind {
+    fn from(v: AddTaskComment) -> AppQueryKind {
+        AppQueryKind::AddTaskComment(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetTaskComments {
+    pub uid: UserUid,
+    pub list: ListUid,
+    pub task: i64,
+}
+
+impl From<GetTaskComments> for AppQueryKind {
+    fn from(v: GetTaskComments) -> AppQueryKind {
+        AppQueryKind::GetTaskComments(v)
+    }
+}
+
+/// The tasks `uid` may see across every list they may read, ordered by list and then task number,
+/// so that paging with `offset` is stable while the lists don't change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetMyTasks {
+    pub uid: UserUid,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl From<GetMyTasks> for AppQueryKind {
+    fn from(v: GetMyTasks) -> AppQueryKind {
+        AppQueryKind::GetMyTasks(v)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListTask {
+    pub list: ListUid,
+    #[serde(flatten)]
+    pub task: Task,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskFeed {
+    pub tasks: Vec<ListTask>,
+    pub pagination: Pagination,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Empty {
     message: &'static str,
@@ -174,31 +1010,120 @@ impl Default for Empty {
     }
 }
 
+/// What a create route answers: the new id, or `Empty` if the request was only validated
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Created<T> {
+    Id(T),
+    Validated(Empty),
+}
+
+/// The outcome of a single item in a bulk operation run with `partial: true`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemResult<T> {
+    Ok(T),
+    // Boxed so a bulk response of mostly successes isn't sized for an error per item
+    Error(Box<Error>),
+}
+
+impl<T> From<Result<T, Error>> for ItemResult<T> {
+    fn from(r: Result<T, Error>) -> Self {
+        match r {
+            Ok(v) => ItemResult::Ok(v),
+            Err(e) => ItemResult::Error(Box::new(e)),
+        }
+    }
+}
+
 pub async fn serve_api(chan: AppChannel, port: u16) {
-    let filter = warp::path("api").and(
+    let s = warp::serve(routes(chan));
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    s.run(socket).await
+}
+
+fn routes(chan: AppChannel) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    warp::path("api").and(
         // List CRUD
         (warp::path("list").and(
             (warp::path("get")
                 .and(warp::get())
                 .and(with_app(chan.clone()))
                 .and(warp::query::query::<GetList>())
-                .and_then(simple_query::<GetList, List>))
+                .and(warp::header::optional::<String>("if-none-match"))
+                .and_then(get_list_query))
             .or(warp::path("create")
                 .and(warp::post())
                 .and(with_app(chan.clone()))
                 .and(warp::body::json())
-                .and_then(simple_query::<CreateList, EntityUid>))
+                .and_then(simple_query::<CreateList, Created<EntityUid>>))
+            .or(warp::path("validate")
+                .and(warp::post())
+                .and(with_app(chan.clone()))
+                .and(warp::body::json().map(|r: CreateList| CreateList { validate_only: true, ..r }))
+                .and_then(simple_query::<CreateList, Empty>))
             .or(warp::path("update")
                 .and(warp::post())
                 .and(with_app(chan.clone()))
                 .and(warp::body::json())
                 .and_then(simple_query::<UpdateList, Empty>))
+            .or(warp::path("template")
+                .and(warp::post())
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<SetListTemplate, Empty>))
+            .or(warp::path("import")
+                .and(warp::post())
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<ImportList, ImportedList>))
+            .or(warp::path("export")
+                .and(warp::get())
+                .and(with_app(chan.clone()))
+                .and(warp::query::query::<ExportList>())
+                .and_then(simple_query::<ExportList, String>))
+            .or(warp::path("metadata")
+                .and(warp::post())
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<SetListMetadata, Empty>))
+            .or(warp::path("duplicate")
+                .and(warp::post())
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<DuplicateList, EntityUid>))
+            .or(warp::path("folder")
+                .and(warp::post())
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<MoveListToFolder, Empty>))
+            .or(warp::path("exclusion")
+                .and(warp::get())
+                .and(with_app(chan.clone()))
+                .and(warp::query::query::<ExplainListExclusion>())
+                .and_then(simple_query::<ExplainListExclusion, Vec<String>>))
+            .or(warp::path("lock").and(
+                (warp::post()
+                    .and(with_app(chan.clone()))
+                    .and(warp::body::json())
+                    .and_then(simple_query::<LockList, Empty>))
+                .or(warp::delete()
+                    .and(with_app(chan.clone()))
+                    .and(warp::body::json())
+                    .and_then(simple_query::<UnlockList, Empty>)),
+            ))
             .or(warp::path("delete")
                 .and(warp::delete())
                 .and(with_app(chan.clone()))
                 .and(warp::body::json())
                 .and_then(simple_query::<DeleteList, Empty>)),
         ))
+        .or(warp::path("folder")
+            .and(warp::path("create"))
+            .and(warp::post())
+            .and(with_app(chan.clone()))
+            .and(warp::body::json())
+            .and_then(simple_query::<CreateFolder, EntityUid>))
         .or(
             // Task CRUD
             warp::path("task").and(
@@ -206,7 +1131,17 @@ pub async fn serve_api(chan: AppChannel, port: u16) {
                     .and(warp::post())
                     .and(with_app(chan.clone()))
                     .and(warp::body::json())
-                    .and_then(simple_query::<CreateTask, i64>))
+                    .and_then(simple_query::<CreateTask, Created<i64>>))
+                .or(warp::path("validate")
+                    .and(warp::post())
+                    .and(with_app(chan.clone()))
+                    .and(warp::body::json().map(|r: CreateTask| CreateTask { validate_only: true, ..r }))
+                    .and_then(simple_query::<CreateTask, Empty>))
+                .or(warp::path("create_many")
+                    .and(warp::post())
+                    .and(with_app(chan.clone()))
+                    .and(warp::body::json())
+                    .and_then(simple_query::<CreateTasks, Vec<ItemResult<i64>>>))
                 .or(warp::path("update")
                     .and(warp::post())
                     .and(with_app(chan.clone()))
@@ -216,7 +1151,27 @@ pub async fn serve_api(chan: AppChannel, port: u16) {
                     .and(warp::delete())
                     .and(with_app(chan.clone()))
                     .and(warp::body::json())
-                    .and_then(simple_query::<DeleteTask, Empty>)),
+                    .and_then(simple_query::<DeleteTask, Empty>))
+                .or(warp::path("update_all")
+                    .and(warp::post())
+                    .and(with_app(chan.clone()))
+                    .and(warp::body::json())
+                    .and_then(simple_query::<SetAllTaskStates, ChangedTasks>))
+                .or(warp::path("comment")
+                    .and(warp::post())
+                    .and(with_app(chan.clone()))
+                    .and(warp::body::json())
+                    .and_then(simple_query::<AddTaskComment, i64>))
+                .or(warp::path("comments")
+                    .and(warp::get())
+                    .and(with_app(chan.clone()))
+                    .and(warp::query::query::<GetTaskComments>())
+                    .and_then(simple_query::<GetTaskComments, Vec<TaskComment>>))
+                .or(warp::path("mine")
+                    .and(warp::get())
+                    .and(with_app(chan.clone()))
+                    .and(warp::query::query::<GetMyTasks>())
+                    .and_then(simple_query::<GetMyTasks, TaskFeed>)),
             ),
         )
         .or(warp::path("lists")
@@ -224,6 +1179,47 @@ pub async fn serve_api(chan: AppChannel, port: u16) {
             .and(with_app(chan.clone()))
             .and(warp::query::query::<GetLists>())
             .and_then(simple_query::<GetLists, Lists>))
+        .or(warp::path("lists")
+            .and(warp::path("page"))
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetListsPage>())
+            .and_then(simple_query::<GetListsPage, ListsPage>))
+        .or(warp::path("lists")
+            .and(warp::path("detailed"))
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetListsDetailed>())
+            .and_then(simple_query::<GetListsDetailed, Vec<ListSummary>>))
+        .or(warp::path("lists")
+            .and(warp::path("owned"))
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetMyOwnedLists>())
+            .and_then(simple_query::<GetMyOwnedLists, Lists>))
+        .or(warp::path("activity")
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetUserActivity>())
+            .and_then(simple_query::<GetUserActivity, UserActivity>))
+        .or(warp::path("lists")
+            .and(warp::path("sql"))
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetListsSql>())
+            .and_then(simple_query::<GetListsSql, String>))
+        .or(warp::path("lists")
+            .and(warp::path("recent"))
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetRecentLists>())
+            .and_then(simple_query::<GetRecentLists, Lists>))
+        .or(warp::path("lists")
+            .and(warp::path("templates"))
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetTemplates>())
+            .and_then(simple_query::<GetTemplates, Lists>))
+        .or(warp::path("share")
+            .and(warp::path("preview"))
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<PreviewShare>())
+            .and_then(simple_query::<PreviewShare, SharePreview>))
         .or(warp::path("share").and(
             (warp::post()
                 .and(with_app(chan.clone()))
@@ -233,42 +1229,134 @@ pub async fn serve_api(chan: AppChannel, port: u16) {
                 .and(with_app(chan.clone()))
                 .and(warp::body::json())
                 .and_then(simple_query::<DeleteShare, Empty>)),
-        )),
-    );
+        ))
+        .or(warp::path("owner").and(
+            (warp::post()
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<AddOwner, Empty>))
+            .or(warp::delete()
+                .and(with_app(chan.clone()))
+                .and(warp::body::json())
+                .and_then(simple_query::<RemoveOwner, Empty>)),
+        ))
+        .or(warp::path("batch")
+            .and(warp::post())
+            .and(with_app(chan.clone()))
+            .and(warp::body::json())
+            .and_then(simple_query::<Batch, Vec<ItemResult<AppResponse>>>))
+        .or(warp::path("shares")
+            .and(warp::post())
+            .and(with_app(chan.clone()))
+            .and(warp::body::json())
+            .and_then(simple_query::<AddShares, Vec<ItemResult<()>>>))
+        .or(warp::path("teams")
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetMyTeams>())
+            .and_then(simple_query::<GetMyTeams, Vec<TeamUid>>))
+        .or(warp::path("authorize")
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<Authorize>())
+            .and_then(simple_query::<Authorize, Vec<Obligation>>))
+        .or(warp::path("residual")
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetResidual>())
+            .and_then(simple_query::<GetResidual, Residual>))
+        .or(warp::path("application")
+            .and(warp::path("capabilities"))
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and(warp::query::query::<GetApplicationCapabilities>())
+            .and_then(simple_query::<GetApplicationCapabilities, Vec<EntityUid>>))
+        .or(warp::path("application")
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and_then(|app| simple_query::<_, Application>(app, AppQueryKind::GetApplication)))
+        .or(warp::path("policies")
+            .and(warp::path("version"))
+            .and(warp::get())
+            .and(with_app(chan.clone()))
+            .and_then(|app| simple_query::<_, u64>(app, AppQueryKind::GetPolicyVersion))),
+    )
+}
 
-    let s = warp::serve(filter);
-    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
-    s.run(socket).await
+/// The application channel, along with the user named by an `X-On-Behalf-Of` header, if any,
+/// and whether the `include_timing` query parameter was set
+#[derive(Debug, Clone)]
+pub struct AppHandle {
+    chan: AppChannel,
+    on_behalf_of: Option<String>,
+    include_timing: bool,
+    encoder: &'static dyn ResponseEncoder,
+}
+
+// Accepted by every route, alongside any parameters of the route itself
+#[derive(Debug, Deserialize)]
+struct TimingFlag {
+    #[serde(default)]
+    include_timing: bool,
 }
 
 pub fn with_app(
     chan: AppChannel,
-) -> impl Filter<Extract = (AppChannel,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || chan.clone())
+) -> impl Filter<Extract = (AppHandle,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-on-behalf-of")
+        .and(warp::query::query::<TimingFlag>())
+        .and(warp::header::optional::<String>("accept"))
+        .map(move |on_behalf_of, flag: TimingFlag, accept: Option<String>| AppHandle {
+            chan: chan.clone(),
+            on_behalf_of,
+            include_timing: flag.include_timing,
+            encoder: encoder_for(accept.as_deref()),
+        })
+}
+
+// Responses are JSON unless the client asks for MessagePack
+fn encoder_for(accept: Option<&str>) -> &'static dyn ResponseEncoder {
+    match accept {
+        Some(accept) if accept.contains(MsgPackEncoder.content_type()) => &MsgPackEncoder,
+        _ => &JsonEncoder,
+    }
 }
 
 #[derive(Serialize)]
 struct ErrorMsg {
-    #[serde(serialize_with = "serialize_error")]
     error: Error,
 }
 
-fn serialize_error<S>(e: &Error, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_str(&format!("{}", e))
+// The usual response body, along with where the server spent its time on it
+#[derive(Serialize)]
+struct Timed<T> {
+    response: T,
+    timing: RequestTiming,
 }
 
-fn respond(msg: Result<impl Serialize, Error>) -> impl warp::Reply {
+fn encode(encoder: &dyn ResponseEncoder, body: impl Serialize, timing: Option<RequestTiming>) -> warp::reply::Response {
+    let encoded = match timing {
+        Some(timing) => encoder.encode(Timed { response: body, timing }),
+        None => encoder.encode(body),
+    };
+    match encoded {
+        Ok(bytes) => warp::reply::with_header(bytes, "content-type", encoder.content_type()).into_response(),
+        Err(e) => {
+            error!("Failed to encode a response: {e}");
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn respond(encoder: &dyn ResponseEncoder, msg: Result<impl Serialize, Error>, timing: Option<RequestTiming>) -> warp::reply::Response {
     match msg {
-        Ok(msg) => Ok(serde_json::to_string(&msg).unwrap()),
-        Err(error) => Ok(serde_json::to_string(&ErrorMsg { error }).unwrap()),
+        Ok(msg) => encode(encoder, msg, timing),
+        Err(error) => encode(encoder, ErrorMsg { error }, timing),
     }
 }
 
 pub async fn simple_query<I, R>(
-    app: mpsc::Sender<AppQuery>,
+    app: AppHandle,
     q: I,
 ) -> Result<impl warp::Reply, warp::Rejection>
 where
@@ -276,23 +1364,189 @@ where
     AppResponse: TryInto<R, Error = Error>,
     R: Serialize,
 {
-    let result = simple_query_inner::<R>(app, q).await;
-    Ok(respond(result))
+    let encoder = app.encoder;
+    let (result, timing) = simple_query_inner::<R>(app, q).await;
+    Ok(respond(encoder, result, timing))
+}
+
+/// `GetList` with HTTP caching: the list's version is sent as its `ETag`,
+/// and an `If-None-Match` header naming the current version gets a `304 Not Modified`
+async fn get_list_query(
+    app: AppHandle,
+    mut q: GetList,
+    if_none_match: Option<String>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let encoder = app.encoder;
+    // A projection isn't a whole list, so it has no version to cache by
+    if q.fields.is_some() {
+        let (result, timing) = simple_query_inner::<serde_json::Map<String, serde_json::Value>>(app, q).await;
+        return Ok(respond(encoder, result, timing));
+    }
+    if let Some(etag) = if_none_match {
+        q.if_none_match = Some(etag.trim_matches('"').to_string());
+    }
+    let requested = q.if_none_match.clone();
+    let (result, timing) = simple_query_inner::<Option<List>>(app, q).await;
+    let reply = match result {
+        Ok(Some(list)) => {
+            let etag = format!("\"{}\"", list.get_version());
+            warp::reply::with_header(encode(encoder, &list, timing), "etag", etag).into_response()
+        }
+        Ok(None) => {
+            let etag = format!("\"{}\"", requested.unwrap_or_default());
+            let not_modified = warp::reply::with_status(warp::reply(), warp::http::StatusCode::NOT_MODIFIED);
+            warp::reply::with_header(not_modified, "etag", etag).into_response()
+        }
+        Err(e) => respond(encoder, Err::<List, _>(e), timing),
+    };
+    Ok(reply)
 }
 
+/// Run `q`, also returning where the time went if the client asked with `include_timing`
 pub async fn simple_query_inner<R>(
-    app: mpsc::Sender<AppQuery>,
+    app: AppHandle,
     q: impl Into<AppQueryKind>,
-) -> Result<R, Error>
+) -> (Result<R, Error>, Option<RequestTiming>)
 where
     AppResponse: TryInto<R, Error = Error>,
     R: Serialize,
 {
+    let on_behalf_of = app
+        .on_behalf_of
+        .map(|uid| {
+            uid.parse::<EntityUid>()
+                .ok()
+                .and_then(|euid| UserUid::try_from(euid).ok())
+                .ok_or_else(|| Error::InvalidInput(format!("`{uid}` is not a User uid")))
+        })
+        .transpose();
+    let on_behalf_of = match on_behalf_of {
+        Ok(on_behalf_of) => on_behalf_of,
+        Err(e) => return (Err(e), None),
+    };
+    let (resp, timing) = send_timed_query(&app.chan, q.into(), on_behalf_of, app.include_timing).await;
+    (resp.and_then(TryInto::try_into), timing)
+}
+
+/// Send `kind` to the application, optionally to be run as `on_behalf_of` rather than its own principal
+pub async fn send_query(
+    app: &AppChannel,
+    kind: AppQueryKind,
+    on_behalf_of: Option<UserUid>,
+) -> Result<AppResponse, Error> {
+    send_timed_query(app, kind, on_behalf_of, false).await.0
+}
+
+/// Like `send_query`, also returning the query's `RequestTiming` if `include_timing` is set
+pub async fn send_timed_query(
+    app: &AppChannel,
+    kind: AppQueryKind,
+    on_behalf_of: Option<UserUid>,
+    include_timing: bool,
+) -> (Result<AppResponse, Error>, Option<RequestTiming>) {
     let (send, recv) = oneshot::channel();
-    let kind = q.into();
-    let q = AppQuery::new(kind, send);
-    app.send(q).await?;
-    let resp = recv.await??;
-    let resp = resp.try_into()?;
-    Ok(resp)
+    let cancel = CancellationToken::new();
+    // warp drops this future when the client disconnects, which aborts the query if it is still running
+    let _guard = CancelOnDrop(cancel.clone());
+    let q = AppQuery::new(kind, send).on_behalf_of(on_behalf_of).cancellable(cancel);
+    let (q, timing) = if include_timing {
+        let (q, timing) = q.timed();
+        (q, Some(timing))
+    } else {
+        (q, None)
+    };
+    if let Err(e) = app.send(q).await {
+        return (Err(e.into()), None);
+    }
+    let resp = recv.await.map_err(Error::from).and_then(|resp| resp);
+    // Sent before the response, so it's already waiting unless the query never ran
+    let timing = match timing {
+        Some(timing) => timing.await.ok(),
+        None => None,
+    };
+    (resp, timing)
+}
+
+/// Queue `kind` without waiting for it to run. Fails only if the application can't take it right now.
+pub fn send_query_detached(
+    app: &AppChannel,
+    kind: AppQueryKind,
+    on_behalf_of: Option<UserUid>,
+) -> Result<(), Error> {
+    let q = AppQuery::without_response(kind).on_behalf_of(on_behalf_of);
+    app.try_send(q).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => Error::Busy,
+        mpsc::error::TrySendError::Closed(q) => mpsc::error::SendError(q).into(),
+    })
+}
+
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let src = "permit(\n  principal == User::\"zoë\",";
+        assert_eq!(line_col(src, 0), (1, 1));
+        assert_eq!(line_col(src, 10), (2, 3));
+        // Inside the two bytes of `ë`
+        let inside = src.find('ë').unwrap() + 1;
+        assert_eq!(line_col(src, inside), (2, 25));
+        assert_eq!(line_col(src, src.len() + 5), (2, 28));
+    }
+
+    #[tokio::test]
+    async fn test_create_routes_answer_validate_only() {
+        let (chan, mut recv) = mpsc::channel::<AppQuery>(4);
+        tokio::spawn(async move {
+            while let Some(q) = recv.recv().await {
+                q.answer_with(|kind| match kind {
+                    AppQueryKind::CreateList(CreateList { validate_only: true, .. })
+                    | AppQueryKind::CreateTask(CreateTask { validate_only: true, .. }) => Ok(AppResponse::Validated),
+                    kind => panic!("unexpected query: {kind:?}"),
+                });
+            }
+        });
+
+        let filter = routes(chan);
+        let body = serde_json::json!({ "uid": "User::\"alice\"", "name": "Groceries", "validate_only": true });
+        let response = warp::test::request().method("POST").path("/api/list/create").json(&body).reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), br#"{"message":"ok"}"#);
+
+        let body = serde_json::json!({ "uid": "User::\"alice\"", "list": "List::\"0\"", "name": "Milk", "validate_only": true });
+        let response = warp::test::request().method("POST").path("/api/task/create").json(&body).reply(&filter).await;
+        assert_eq!(response.body().as_ref(), br#"{"message":"ok"}"#);
+
+        let response = warp::test::request().method("POST").path("/api/task/validate").json(&body).reply(&filter).await;
+        assert_eq!(response.body().as_ref(), br#"{"message":"ok"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_include_timing() {
+        let (chan, mut recv) = mpsc::channel::<AppQuery>(4);
+        tokio::spawn(async move {
+            while let Some(q) = recv.recv().await {
+                q.answer_with(|_| Ok(AppResponse::Unit(())));
+            }
+        });
+
+        let filter = routes(chan);
+        let body = serde_json::json!({ "uid": "User::\"alice\"", "list": "List::\"0\"" });
+        let response = warp::test::request().method("DELETE").path("/api/list/delete").json(&body).reply(&filter).await;
+        assert_eq!(response.body().as_ref(), br#"{"message":"ok"}"#);
+
+        let response = warp::test::request().method("DELETE").path("/api/list/delete?include_timing=true").json(&body).reply(&filter).await;
+        let timed: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(timed["response"], serde_json::json!({ "message": "ok" }));
+        assert_eq!(timed["timing"], serde_json::json!({ "total_micros": 0, "authorization_micros": 0, "sql_micros": 0 }));
+    }
 }
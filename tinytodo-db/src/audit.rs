@@ -0,0 +1,134 @@
+/*
+ * Copyright 2022-2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cedar_policy::Decision;
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing::error;
+
+use crate::{context::Error, entitystore::EntityStore, util::EntityUid};
+
+/// Destination for authorization events.
+/// Sinks are called synchronously from the application thread, so they shouldn't block.
+pub trait AuditSink: Debug + Send {
+    fn record(&self, store: &EntityStore, event: &AuditEvent) -> Result<(), Error>;
+}
+
+/// Writes events to the `audit_log` table
+#[derive(Debug, Default)]
+pub struct DbAuditSink;
+
+impl AuditSink for DbAuditSink {
+    fn record(&self, store: &EntityStore, event: &AuditEvent) -> Result<(), Error> {
+        store.record_audit(event)
+    }
+}
+
+/// Forwards events over a channel, e.g. to a task publishing them to an external log pipeline
+#[derive(Debug)]
+pub struct ChannelAuditSink(pub UnboundedSender<AuditEvent>);
+
+impl ChannelAuditSink {
+    /// Spawns a task writing each event to stdout as a line of JSON
+    pub fn stdout() -> Self {
+        let (send, mut recv) = unbounded_channel::<AuditEvent>();
+        tokio::spawn(async move {
+            while let Some(event) = recv.recv().await {
+                match serde_json::to_string(&event) {
+                    Ok(line) => println!("{line}"),
+                    Err(e) => error!("Failed to serialize audit event: {e}"),
+                }
+            }
+        });
+        Self(send)
+    }
+}
+
+impl AuditSink for ChannelAuditSink {
+    fn record(&self, _: &EntityStore, event: &AuditEvent) -> Result<(), Error> {
+        self.0
+            .send(event.clone())
+            .map_err(|_| Error::Audit("audit channel closed".to_string()))
+    }
+}
+
+/// A single authorization event, as stored in the `audit_log` table
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    /// Seconds since the unix epoch
+    pub timestamp: i64,
+    pub principal: EntityUid,
+    pub action: EntityUid,
+    pub resource: EntityUid,
+    pub decision: Decision,
+    /// Set when a denial was overridden because the action is configured to fail open
+    pub fail_open: bool,
+    /// The real caller, when `principal` is being impersonated
+    pub impersonator: Option<EntityUid>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+        decision: Decision,
+    ) -> Self {
+        Self {
+            timestamp: now(),
+            principal: principal.as_ref().clone(),
+            action: action.as_ref().clone(),
+            resource: resource.as_ref().clone(),
+            decision,
+            fail_open: false,
+            impersonator: None,
+        }
+    }
+
+    pub fn impersonated_by(self, impersonator: Option<impl AsRef<EntityUid>>) -> Self {
+        Self {
+            impersonator: impersonator.map(|i| i.as_ref().clone()),
+            ..self
+        }
+    }
+
+    pub fn fail_open(self) -> Self {
+        Self {
+            fail_open: true,
+            ..self
+        }
+    }
+}
+
+pub fn decision_str(decision: Decision) -> &'static str {
+    match decision {
+        Decision::Allow => "allow",
+        Decision::Deny => "deny",
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
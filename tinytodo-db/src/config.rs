@@ -0,0 +1,190 @@
+/*
+ * Copyright 2022-2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{collections::HashSet, time::Duration};
+
+use cedar_policy::Authorizer;
+
+use crate::{
+    api::ShareRole,
+    audit::{AuditSink, DbAuditSink},
+    context::APPLICATION_TINY_TODO,
+    hooks::AuthorizationHook,
+    util::{fnv1a, EntityUid, TYPE_USER},
+};
+
+/// Runtime knobs for the application server.
+/// `AppConfig::default()` gives the standard TinyTodo behaviour.
+#[derive(Debug)]
+pub struct AppConfig {
+    /// Actions which are allowed even when the policies deny them.
+    /// This is a temporary escape hatch for policy migrations: every overridden denial
+    /// is logged as a warning and recorded in the audit log.
+    pub fail_open_actions: HashSet<EntityUid>,
+    /// Where authorization decisions are recorded. Defaults to the `audit_log` table.
+    /// A decision which can't be recorded is logged as an error, and the request goes ahead.
+    pub audit_sink: Box<dyn AuditSink>,
+    /// Also audit the `ViewTask` checks made to filter the restricted tasks out of a list.
+    /// Off by default, since every read of a list makes one per restricted task.
+    pub audit_task_filtering: bool,
+    /// Upper bound on the number of conditions, joins and subqueries in the SQL generated
+    /// from a residual policy. Queries beyond this are rejected instead of being sent to SQLite.
+    pub max_query_complexity: usize,
+    /// Longest list or task name accepted, in bytes
+    pub max_name_length: usize,
+    /// Largest list metadata object accepted, in bytes of JSON
+    pub max_metadata_size: usize,
+    /// The authorizer used for every request. `None` uses `Authorizer::new()`.
+    pub authorizer: Option<Authorizer>,
+    /// How user ids appear in logs. The audit log always records them in full.
+    pub log_redaction: LogRedaction,
+    /// Memory settings for the database connection. Unset values keep SQLite's defaults.
+    pub sqlite: SqliteTuning,
+    /// Values used in place of NULL in attribute columns. By default, users and lists without a name get an empty one.
+    pub attribute_defaults: Vec<AttributeDefault>,
+    /// The role given by an `AddShare` which doesn't name one
+    pub default_share_role: ShareRole,
+    /// How long to reuse the decision for a request without a context. `None` disables the cache.
+    /// Cached decisions are dropped when the policies or any entity change.
+    pub decision_cache_ttl: Option<Duration>,
+    /// How long to reuse a denial, overriding `decision_cache_ttl` for denials only.
+    /// A short TTL here keeps a client repeating a denied request from loading the authorizer,
+    /// even with allows uncached.
+    pub deny_cache_ttl: Option<Duration>,
+    /// Match names ignoring ASCII case, as SQLite's `COLLATE NOCASE` does
+    pub case_insensitive_names: bool,
+    /// Refuse to add a task whose name, ignoring surrounding whitespace, is already used in its list.
+    /// Names are compared ignoring case too if `case_insensitive_names` is set.
+    pub dedup_task_names: bool,
+    /// Refuse to create a list, or rename one, with a name its owner already uses for another list.
+    /// Names are compared as for `dedup_task_names`.
+    pub unique_list_names: bool,
+    /// Serve a read replica: the database is opened read-only and the server starts in maintenance mode.
+    /// Audit events can't be written to the replica, so this needs an `audit_sink` other than `DbAuditSink`.
+    pub read_only: bool,
+    /// How many times to re-run a query which failed because another connection had the database busy or locked.
+    /// While this is non-zero, every mutation runs in its own transaction, so a failed attempt leaves nothing behind.
+    pub request_retries: u32,
+    /// The application entity at the root of the hierarchy: the parent of every user and team,
+    /// the tenant of lists created without one, and the resource of application-wide actions.
+    pub application: EntityUid,
+    /// Remember each user's teams between requests, until a share or team change.
+    /// Worthwhile when users sit deep in a team hierarchy.
+    pub membership_cache: bool,
+    /// Load each list's tasks when authorizing it, filling in its `tasks` attribute.
+    /// Off by default: no TinyTodo policy reads `resource.tasks`, and loading them costs a query per list.
+    pub eager_task_loading: bool,
+    /// Fail a denied request with `NoSuchEntity` rather than `AuthDenied` if its resource doesn't exist.
+    /// Callers can then tell a typo from a missing permission, but also learn which uids exist.
+    pub report_missing_resources: bool,
+    /// Serve `application` as one tenant of the database, sharing a single connection with every other
+    /// context started with this set on the same file. Only the tenant's own lists are visible.
+    pub share_connection: bool,
+    /// How often to checkpoint the write-ahead log and truncate it. SQLite's own checkpoints never shrink
+    /// the file, so without this it stays as large as the biggest burst of writes. `None` disables it.
+    pub wal_checkpoint_interval: Option<Duration>,
+    /// How long to keep a deleted list before purging it, with its tasks and teams. Until then it is
+    /// hidden from every query, but still takes up space. `None` purges lists as soon as they're deleted.
+    pub deleted_list_retention: Option<Duration>,
+    /// Run around every authorization check, in order, see `AuthorizationHook`
+    pub authorization_hooks: Vec<Box<dyn AuthorizationHook>>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            fail_open_actions: HashSet::new(),
+            audit_sink: Box::new(DbAuditSink),
+            audit_task_filtering: false,
+            max_query_complexity: 1000,
+            max_name_length: 256,
+            max_metadata_size: 4096,
+            authorizer: None,
+            log_redaction: LogRedaction::None,
+            sqlite: SqliteTuning::default(),
+            attribute_defaults: AttributeDefault::standard(),
+            default_share_role: ShareRole::Reader,
+            decision_cache_ttl: None,
+            deny_cache_ttl: None,
+            case_insensitive_names: false,
+            dedup_task_names: false,
+            unique_list_names: false,
+            read_only: false,
+            request_retries: 0,
+            application: APPLICATION_TINY_TODO.clone(),
+            membership_cache: false,
+            eager_task_loading: false,
+            report_missing_resources: false,
+            share_connection: false,
+            wal_checkpoint_interval: None,
+            deleted_list_retention: None,
+            authorization_hooks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SqliteTuning {
+    /// `PRAGMA cache_size`: a number of pages if positive, or of KiB if negative
+    pub cache_size: Option<i64>,
+    /// `PRAGMA mmap_size`, in bytes. 0 turns memory-mapped I/O off.
+    pub mmap_size: Option<u64>,
+}
+
+/// A value used in place of NULL in an attribute column, since an entity missing an attribute the schema
+/// declares makes any policy reading it fail to evaluate. Entities are read through the `<table>_with_defaults`
+/// views, which apply these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeDefault {
+    pub table: String,
+    pub column: String,
+    /// An SQL literal, e.g. `''` for the empty string
+    pub value: String,
+}
+
+impl AttributeDefault {
+    pub fn new(table: &str, column: &str, value: &str) -> Self {
+        Self { table: table.to_string(), column: column.to_string(), value: value.to_string() }
+    }
+
+    /// The defaults TinyTodo's own data needs: an empty name for users and lists without one
+    pub fn standard() -> Vec<Self> {
+        vec![Self::new("users", "name", "''"), Self::new("lists", "name", "''")]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRedaction {
+    None,
+    /// Replace user ids with a hash, so that one user's requests can still be correlated
+    Hash,
+    /// Leave user ids out entirely
+    Redact,
+}
+
+impl LogRedaction {
+    /// Render `uid` for a log line. Only users are masked, since other ids don't identify a person.
+    pub fn redact(self, uid: &EntityUid) -> String {
+        if *uid.type_name() != *TYPE_USER {
+            return uid.to_string();
+        }
+        match self {
+            LogRedaction::None => uid.to_string(),
+            LogRedaction::Hash => format!("{}::#{:016x}", uid.type_name(), fnv1a(uid.id().as_ref().as_bytes())),
+            LogRedaction::Redact => format!("{}::<redacted>", uid.type_name()),
+        }
+    }
+}
@@ -14,17 +14,34 @@
  * limitations under the License.
  */
 
-use cedar_db_example::expr_to_query::{translate_response, InByTable};
+use cedar_db_example::{
+    expr_to_query::{translate_response, InByTable},
+    sqlite::EntitySQLId,
+};
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use sea_query::{Alias, Query, SqliteQueryBuilder, SelectStatement};
-use std::path::PathBuf;
-use tracing::{info, trace};
+use sea_query::{
+    Alias, Asterisk, Condition, Expr, Func, IntoColumnRef, Order, Query, SelectStatement, SimpleExpr,
+    SqliteQueryBuilder,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{error, info, trace, warn};
 
 use cedar_policy::{
-    Authorizer, Context, Decision, Diagnostics, ParseErrors, PolicySet, Request,
-    Schema, SchemaError, ValidationMode, Validator, CachedEntities,
+    Authorizer, Context, Decision, Diagnostics, EntityTypeName, ParseErrors, ParsedEntity, PolicySet,
+    Request, Response, RestrictedExpression, Schema, SchemaError, ValidationMode, ValidationResult, Validator,
+    CachedEntities,
 };
+use serde::{Deserialize, Serialize, Serializer};
 use thiserror::Error;
 use tokio::sync::{
     mpsc::{Receiver, Sender},
@@ -33,24 +50,82 @@ use tokio::sync::{
 
 use crate::{
     api::{
-        AddShare, CreateList, CreateTask, DeleteList, DeleteShare, DeleteTask, Empty, GetList,
-        GetLists, UpdateList, UpdateTask,
+        AddOwner, AddShare, AddShares, AddTaskComment, AuthDiff, Authorize, Batch, BulkImport,
+        ChangedTasks, CorpusRequest, CreateAuthorizedView, Created, CreateFolder, CreateList, CreateTask,
+        CreateTasks, DeleteList, DeleteShare, DeleteTask, DeleteTeam, DiffAuthorization,
+        DuplicateList, Empty, EvaluateCorpus, ExplainListExclusion, ExplainRequest, Explanation,
+        ExportFormat, ExportList, GetApplicationCapabilities, GetList, GetLists, GetListsDetailed,
+        GetListsForPrincipals, GetListsPage, GetListsSql, GetMyOwnedLists, GetMyTasks, GetMyTeams,
+        GetPrincipalGraph, GetRecentLists, GetResidual, GetTaskComments, GetTemplates,
+        GetUserActivity, ImportList, ImportedList, ItemResult, ListSort, ListTask, ListsPage,
+        LoadedEntity, LockList, MoveListToFolder, Obligation, PolicyImpact, PolicyParseError,
+        PolicySnapshot, PreviewPolicy, PreviewShare, RemoveOwner, Residual, RestorePolicies,
+        ResultSetToken, SetAllTaskStates, SetListMetadata, SetListTemplate, SharePreview, ShareRole,
+        TaskFeed, TaskVisibility, UnlockList, UpdateList, UpdateTask, UserActivity, ValidationIssue,
+        ValidationReport,
     },
-    entitystore::{EntityDecodeError, EntityStore},
-    objects::List,
+    audit::AuditEvent,
+    config::{AppConfig, LogRedaction},
+    entitystore::{BulkList, EntityDecodeError, EntityStore, MemoizedEntities, SharedConnection, LISTS_VIEW, MAX_AUDIT_PAGE, MAX_RECENT_VIEWS},
+    objects::{parse_markdown_checklist, Application, List, ListField, ListSummary, Task, TaskComment, TaskState},
     policy_store,
-    util::{EntityUid, Lists, TYPE_USER, TYPE_TEAM},
+    stats::{DecisionCacheStats, LatencyHistogram, QueryStats, RequestTiming, StoreStats},
+    util::{
+        EntityUid, ListUid, Lists, Pagination, PolicySetSrc, TeamUid, UserOrTeamUid, UserUid,
+        TYPE_APP, TYPE_FOLDER, TYPE_LIST, TYPE_TEAM, TYPE_USER,
+    },
 };
 
 // There's almost certainly a nicer way to do this than having separate `sender` fields
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
 pub enum AppResponse {
     GetList(Box<List>),
+    ListFields(serde_json::Map<String, serde_json::Value>),
+    Application(Application),
+    Text(String),
+    ImportedList(ImportedList),
+    ImportedLists(Vec<ImportedList>),
+    Teams(Vec<TeamUid>),
+    Decisions(Vec<Decision>),
     Euid(EntityUid),
+    Actions(Vec<EntityUid>),
     Lists(Lists),
+    ListSummaries(Vec<ListSummary>),
+    ListsPage(ListsPage),
     TaskId(i64),
+    CommentId(i64),
+    ChangedTasks(ChangedTasks),
+    TaskComments(Vec<TaskComment>),
+    Stats(Vec<QueryStats>),
+    DecisionCacheStats(DecisionCacheStats),
+    StoreStats(StoreStats),
+    Validation(ValidationReport),
+    ValidationWarnings(Vec<ValidationIssue>),
+    Obligations(Vec<Obligation>),
+    Residual(Residual),
+    SharePreview(SharePreview),
+    AuthDiff(AuthDiff),
+    Explanation(Explanation),
+    Reasons(Vec<String>),
+    // Empty if the policy set was parsed and applied
+    PolicyParseErrors(Vec<PolicyParseError>),
+    ListsByPrincipal(HashMap<EntityUid, Vec<ListUid>>),
+    Graph(serde_json::Value),
+    PolicyHistory(Vec<PolicySnapshot>),
+    PolicyVersion(u64),
+    UserActivity(UserActivity),
+    TaskFeed(TaskFeed),
+    PolicyImpact(PolicyImpact),
+    TaskIds(Vec<Result<i64>>),
+    ItemResults(Vec<Result<()>>),
+    Batch(Vec<Result<AppResponse>>),
     Unit(()),
+    // A `validate_only` request would have succeeded
+    Validated,
+    // The client's copy, named by `GetList::if_none_match`, is current
+    NotModified,
 }
 
 impl AppResponse {
@@ -64,7 +139,249 @@ impl TryInto<i64> for AppResponse {
 
     fn try_into(self) -> std::result::Result<i64, Self::Error> {
         match self {
-            AppResponse::TaskId(id) => Ok(id),
+            AppResponse::TaskId(id) | AppResponse::CommentId(id) => Ok(id),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<ChangedTasks> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<ChangedTasks, Self::Error> {
+        match self {
+            AppResponse::ChangedTasks(changed) => Ok(changed),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<ItemResult<i64>>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<ItemResult<i64>>, Self::Error> {
+        match self {
+            AppResponse::TaskIds(ids) => Ok(ids.into_iter().map(ItemResult::from).collect()),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<ItemResult<()>>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<ItemResult<()>>, Self::Error> {
+        match self {
+            AppResponse::ItemResults(results) => Ok(results.into_iter().map(ItemResult::from).collect()),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<ItemResult<AppResponse>>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<ItemResult<AppResponse>>, Self::Error> {
+        match self {
+            AppResponse::Batch(results) => Ok(results.into_iter().map(ItemResult::from).collect()),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<u64> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<u64, Self::Error> {
+        match self {
+            AppResponse::PolicyVersion(version) => Ok(version),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<String>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<String>, Self::Error> {
+        match self {
+            AppResponse::Reasons(reasons) => Ok(reasons),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<QueryStats>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<QueryStats>, Self::Error> {
+        match self {
+            AppResponse::Stats(stats) => Ok(stats),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<EntityUid>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<EntityUid>, Self::Error> {
+        match self {
+            AppResponse::Actions(actions) => Ok(actions),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<TeamUid>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<TeamUid>, Self::Error> {
+        match self {
+            AppResponse::Teams(teams) => Ok(teams),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<ImportedList> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<ImportedList, Self::Error> {
+        match self {
+            AppResponse::ImportedList(imported) => Ok(imported),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<String> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<String, Self::Error> {
+        match self {
+            AppResponse::Text(text) => Ok(text),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<UserActivity> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<UserActivity, Self::Error> {
+        match self {
+            AppResponse::UserActivity(activity) => Ok(activity),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<TaskFeed> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<TaskFeed, Self::Error> {
+        match self {
+            AppResponse::TaskFeed(feed) => Ok(feed),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<SharePreview> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<SharePreview, Self::Error> {
+        match self {
+            AppResponse::SharePreview(preview) => Ok(preview),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Residual> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Residual, Self::Error> {
+        match self {
+            AppResponse::Residual(residual) => Ok(residual),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<Obligation>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<Obligation>, Self::Error> {
+        match self {
+            AppResponse::Obligations(obligations) => Ok(obligations),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Application> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Application, Self::Error> {
+        match self {
+            AppResponse::Application(app) => Ok(app),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<ListsPage> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<ListsPage, Self::Error> {
+        match self {
+            AppResponse::ListsPage(page) => Ok(page),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<ListSummary>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<ListSummary>, Self::Error> {
+        match self {
+            AppResponse::ListSummaries(lists) => Ok(lists),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Vec<TaskComment>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Vec<TaskComment>, Self::Error> {
+        match self {
+            AppResponse::TaskComments(comments) => Ok(comments),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<ValidationReport> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<ValidationReport, Self::Error> {
+        match self {
+            AppResponse::Validation(report) => Ok(report),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<PolicyImpact> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<PolicyImpact, Self::Error> {
+        match self {
+            AppResponse::PolicyImpact(impact) => Ok(impact),
             _ => Err(Error::Type),
         }
     }
@@ -81,6 +398,29 @@ impl TryInto<List> for AppResponse {
     }
 }
 
+impl TryInto<serde_json::Map<String, serde_json::Value>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<serde_json::Map<String, serde_json::Value>, Self::Error> {
+        match self {
+            AppResponse::ListFields(fields) => Ok(fields),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Option<List>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Option<List>, Self::Error> {
+        match self {
+            AppResponse::GetList(l) => Ok(Some(*l)),
+            AppResponse::NotModified => Ok(None),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
 impl TryInto<EntityUid> for AppResponse {
     type Error = Error;
     fn try_into(self) -> std::result::Result<EntityUid, Self::Error> {
@@ -91,12 +431,36 @@ impl TryInto<EntityUid> for AppResponse {
     }
 }
 
+impl TryInto<Created<EntityUid>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Created<EntityUid>, Self::Error> {
+        match self {
+            AppResponse::Euid(e) => Ok(Created::Id(e)),
+            AppResponse::Validated => Ok(Created::Validated(Empty::default())),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
+impl TryInto<Created<i64>> for AppResponse {
+    type Error = Error;
+
+    fn try_into(self) -> std::result::Result<Created<i64>, Self::Error> {
+        match self {
+            AppResponse::TaskId(id) => Ok(Created::Id(id)),
+            AppResponse::Validated => Ok(Created::Validated(Empty::default())),
+            _ => Err(Error::Type),
+        }
+    }
+}
+
 impl TryInto<Empty> for AppResponse {
     type Error = Error;
 
     fn try_into(self) -> std::result::Result<Empty, Self::Error> {
         match self {
-            AppResponse::Unit(()) => Ok(Empty::default()),
+            AppResponse::Unit(()) | AppResponse::Validated => Ok(Empty::default()),
             _ => Err(Error::Type),
         }
     }
@@ -112,39 +476,341 @@ impl TryInto<Lists> for AppResponse {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params")]
 pub enum AppQueryKind {
     // List CRUD
     CreateList(CreateList),
     GetList(GetList),
     UpdateList(UpdateList),
     DeleteList(DeleteList),
+    LockList(LockList),
+    UnlockList(UnlockList),
+    SetListTemplate(SetListTemplate),
+    SetListMetadata(SetListMetadata),
+    CreateFolder(CreateFolder),
+    MoveListToFolder(MoveListToFolder),
+    ExplainListExclusion(ExplainListExclusion),
+    DuplicateList(DuplicateList),
+    ExportList(ExportList),
+    ImportList(ImportList),
 
     // Task CRUD
     CreateTask(CreateTask),
+    CreateTasks(CreateTasks),
     UpdateTask(UpdateTask),
     DeleteTask(DeleteTask),
+    SetAllTaskStates(SetAllTaskStates),
+    AddTaskComment(AddTaskComment),
+    GetTaskComments(GetTaskComments),
 
     // Lists
     GetLists(GetLists),
+    GetListsDetailed(GetListsDetailed),
+    GetMyOwnedLists(GetMyOwnedLists),
+    GetListsPage(GetListsPage),
+    GetTemplates(GetTemplates),
+    GetRecentLists(GetRecentLists),
+    GetMyTasks(GetMyTasks),
+    GetMyTeams(GetMyTeams),
+    GetListsSql(GetListsSql),
+    GetUserActivity(GetUserActivity),
+    GetApplicationCapabilities(GetApplicationCapabilities),
+    Authorize(Authorize),
+    GetResidual(GetResidual),
+    CreateAuthorizedView(CreateAuthorizedView),
 
     // Shares
     AddShare(AddShare),
+    AddShares(AddShares),
     DeleteShare(DeleteShare),
+    PreviewShare(PreviewShare),
+    AddOwner(AddOwner),
+    RemoveOwner(RemoveOwner),
 
     // Policy Set Updates
-    UpdatePolicySet(PolicySet),
+    UpdatePolicySet(PolicySetSrc),
+    // Like `UpdatePolicySet`, but parse errors are reported with their location in the source
+    UpdatePolicySetSrc(String),
+    Batch(Batch),
+    PreviewPolicy(PreviewPolicy),
+    EvaluateCorpus(EvaluateCorpus),
+    DiffAuthorization(DiffAuthorization),
+    GetListsForPrincipals(GetListsForPrincipals),
+    GetPrincipalGraph(GetPrincipalGraph),
+    ExplainRequest(ExplainRequest),
+    DeleteTeam(DeleteTeam),
+    BulkImport(BulkImport),
+    // The policy set in use, as Cedar source, which `UpdatePolicySet` accepts back
+    SnapshotPolicies,
+    // The policy sets replaced most recently, newest first
+    GetPolicyHistory,
+    RestorePolicies(RestorePolicies),
+
+    // Latency of each kind of query so far
+    GetStats,
+    GetDecisionCacheStats,
+    // How many users, teams, lists, tasks and memberships the database holds
+    GetStoreStats,
+    Validate,
+    // The warnings from validating the policies the server started with, see `AppContext::spawn`
+    GetValidationWarnings,
+    // While enabled, queries that change lists, tasks or shares are rejected
+    SetMaintenance(bool),
+    // Sent every `AppConfig::wal_checkpoint_interval`, between queries so no transaction is open
+    CheckpointWal,
+    // Sent every `DELETED_LIST_PURGE_INTERVAL` if `AppConfig::deleted_list_retention` is set
+    PurgeDeletedLists,
+
+    // The root `Application` entity, which anyone may fetch
+    GetApplication,
+    // The version of the policy set in use, which anyone may fetch. It changes whenever the policies do,
+    // so a client caching decisions knows to drop them.
+    GetPolicyVersion,
+}
+
+impl AppQueryKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppQueryKind::CreateList(_) => "CreateList",
+            AppQueryKind::GetList(_) => "GetList",
+            AppQueryKind::UpdateList(_) => "UpdateList",
+            AppQueryKind::CreateFolder(_) => "CreateFolder",
+            AppQueryKind::MoveListToFolder(_) => "MoveListToFolder",
+            AppQueryKind::ExplainListExclusion(_) => "ExplainListExclusion",
+            AppQueryKind::DeleteList(_) => "DeleteList",
+            AppQueryKind::LockList(_) => "LockList",
+            AppQueryKind::UnlockList(_) => "UnlockList",
+            AppQueryKind::SetListTemplate(_) => "SetListTemplate",
+            AppQueryKind::SetListMetadata(_) => "SetListMetadata",
+            AppQueryKind::DuplicateList(_) => "DuplicateList",
+            AppQueryKind::ExportList(_) => "ExportList",
+            AppQueryKind::ImportList(_) => "ImportList",
+            AppQueryKind::CreateTask(_) => "CreateTask",
+            AppQueryKind::CreateTasks(_) => "CreateTasks",
+            AppQueryKind::UpdateTask(_) => "UpdateTask",
+            AppQueryKind::DeleteTask(_) => "DeleteTask",
+            AppQueryKind::SetAllTaskStates(_) => "SetAllTaskStates",
+            AppQueryKind::AddTaskComment(_) => "AddTaskComment",
+            AppQueryKind::GetTaskComments(_) => "GetTaskComments",
+            AppQueryKind::GetLists(_) => "GetLists",
+            AppQueryKind::GetListsDetailed(_) => "GetListsDetailed",
+            AppQueryKind::GetMyOwnedLists(_) => "GetMyOwnedLists",
+            AppQueryKind::GetListsPage(_) => "GetListsPage",
+            AppQueryKind::GetTemplates(_) => "GetTemplates",
+            AppQueryKind::GetRecentLists(_) => "GetRecentLists",
+            AppQueryKind::GetMyTasks(_) => "GetMyTasks",
+            AppQueryKind::GetMyTeams(_) => "GetMyTeams",
+            AppQueryKind::GetListsSql(_) => "GetListsSql",
+            AppQueryKind::GetUserActivity(_) => "GetUserActivity",
+            AppQueryKind::GetApplicationCapabilities(_) => "GetApplicationCapabilities",
+            AppQueryKind::Authorize(_) => "Authorize",
+            AppQueryKind::GetResidual(_) => "GetResidual",
+            AppQueryKind::CreateAuthorizedView(_) => "CreateAuthorizedView",
+            AppQueryKind::AddShare(_) => "AddShare",
+            AppQueryKind::AddShares(_) => "AddShares",
+            AppQueryKind::DeleteShare(_) => "DeleteShare",
+            AppQueryKind::PreviewShare(_) => "PreviewShare",
+            AppQueryKind::AddOwner(_) => "AddOwner",
+            AppQueryKind::RemoveOwner(_) => "RemoveOwner",
+            AppQueryKind::UpdatePolicySet(_) => "UpdatePolicySet",
+            AppQueryKind::UpdatePolicySetSrc(_) => "UpdatePolicySetSrc",
+            AppQueryKind::Batch(_) => "Batch",
+            AppQueryKind::PreviewPolicy(_) => "PreviewPolicy",
+            AppQueryKind::EvaluateCorpus(_) => "EvaluateCorpus",
+            AppQueryKind::DiffAuthorization(_) => "DiffAuthorization",
+            AppQueryKind::GetListsForPrincipals(_) => "GetListsForPrincipals",
+            AppQueryKind::GetPrincipalGraph(_) => "GetPrincipalGraph",
+            AppQueryKind::ExplainRequest(_) => "ExplainRequest",
+            AppQueryKind::DeleteTeam(_) => "DeleteTeam",
+            AppQueryKind::SnapshotPolicies => "SnapshotPolicies",
+            AppQueryKind::GetPolicyHistory => "GetPolicyHistory",
+            AppQueryKind::BulkImport(_) => "BulkImport",
+            AppQueryKind::RestorePolicies(_) => "RestorePolicies",
+            AppQueryKind::GetStats => "GetStats",
+            AppQueryKind::GetDecisionCacheStats => "GetDecisionCacheStats",
+            AppQueryKind::GetStoreStats => "GetStoreStats",
+            AppQueryKind::Validate => "Validate",
+            AppQueryKind::GetValidationWarnings => "GetValidationWarnings",
+            AppQueryKind::SetMaintenance(_) => "SetMaintenance",
+            AppQueryKind::CheckpointWal => "CheckpointWal",
+            AppQueryKind::PurgeDeletedLists => "PurgeDeletedLists",
+            AppQueryKind::GetApplication => "GetApplication",
+            AppQueryKind::GetPolicyVersion => "GetPolicyVersion",
+        }
+    }
+
+    /// The user this query acts as, for queries made by a user
+    pub fn principal_mut(&mut self) -> Option<&mut UserUid> {
+        match self {
+            AppQueryKind::CreateList(r) => Some(&mut r.uid),
+            AppQueryKind::GetList(r) => Some(&mut r.uid),
+            AppQueryKind::UpdateList(r) => Some(&mut r.uid),
+            AppQueryKind::CreateFolder(r) => Some(&mut r.uid),
+            AppQueryKind::MoveListToFolder(r) => Some(&mut r.uid),
+            AppQueryKind::ExplainListExclusion(r) => Some(&mut r.uid),
+            AppQueryKind::DeleteList(r) => Some(&mut r.uid),
+            AppQueryKind::LockList(r) => Some(&mut r.uid),
+            AppQueryKind::UnlockList(r) => Some(&mut r.uid),
+            AppQueryKind::SetListTemplate(r) => Some(&mut r.uid),
+            AppQueryKind::SetListMetadata(r) => Some(&mut r.uid),
+            AppQueryKind::DuplicateList(r) => Some(&mut r.uid),
+            AppQueryKind::ExportList(r) => Some(&mut r.uid),
+            AppQueryKind::ImportList(r) => Some(&mut r.uid),
+            AppQueryKind::CreateTask(r) => Some(&mut r.uid),
+            AppQueryKind::CreateTasks(r) => Some(&mut r.uid),
+            AppQueryKind::UpdateTask(r) => Some(&mut r.uid),
+            AppQueryKind::DeleteTask(r) => Some(&mut r.uid),
+            AppQueryKind::SetAllTaskStates(r) => Some(&mut r.uid),
+            AppQueryKind::AddTaskComment(r) => Some(&mut r.uid),
+            AppQueryKind::GetTaskComments(r) => Some(&mut r.uid),
+            AppQueryKind::GetLists(r) => Some(&mut r.uid),
+            AppQueryKind::GetListsDetailed(r) => Some(&mut r.uid),
+            AppQueryKind::GetMyOwnedLists(r) => Some(&mut r.uid),
+            AppQueryKind::GetListsPage(r) => Some(&mut r.uid),
+            AppQueryKind::GetTemplates(r) => Some(&mut r.uid),
+            AppQueryKind::GetRecentLists(r) => Some(&mut r.uid),
+            AppQueryKind::GetMyTasks(r) => Some(&mut r.uid),
+            AppQueryKind::GetMyTeams(r) => Some(&mut r.uid),
+            AppQueryKind::GetListsSql(r) => Some(&mut r.uid),
+            AppQueryKind::GetUserActivity(r) => Some(&mut r.uid),
+            AppQueryKind::GetApplicationCapabilities(r) => Some(&mut r.uid),
+            AppQueryKind::Authorize(r) => Some(&mut r.uid),
+            AppQueryKind::GetResidual(r) => Some(&mut r.principal),
+            AppQueryKind::AddShare(r) => Some(&mut r.uid),
+            AppQueryKind::AddShares(r) => Some(&mut r.uid),
+            AppQueryKind::DeleteShare(r) => Some(&mut r.uid),
+            AppQueryKind::PreviewShare(r) => Some(&mut r.uid),
+            AppQueryKind::AddOwner(r) => Some(&mut r.uid),
+            AppQueryKind::RemoveOwner(r) => Some(&mut r.uid),
+            AppQueryKind::CreateAuthorizedView(_)
+            | AppQueryKind::UpdatePolicySet(_)
+            | AppQueryKind::UpdatePolicySetSrc(_)
+            | AppQueryKind::Batch(_)
+            | AppQueryKind::PreviewPolicy(_)
+            | AppQueryKind::EvaluateCorpus(_)
+            | AppQueryKind::DiffAuthorization(_)
+            | AppQueryKind::GetListsForPrincipals(_)
+            | AppQueryKind::GetPrincipalGraph(_)
+            | AppQueryKind::ExplainRequest(_)
+            | AppQueryKind::DeleteTeam(_)
+            | AppQueryKind::SnapshotPolicies
+            | AppQueryKind::GetPolicyHistory
+            | AppQueryKind::RestorePolicies(_)
+            | AppQueryKind::BulkImport(_)
+            | AppQueryKind::GetStats
+            | AppQueryKind::GetDecisionCacheStats
+            | AppQueryKind::GetStoreStats
+            | AppQueryKind::Validate
+            | AppQueryKind::GetValidationWarnings
+            | AppQueryKind::SetMaintenance(_)
+            | AppQueryKind::CheckpointWal
+            | AppQueryKind::PurgeDeletedLists
+            | AppQueryKind::GetApplication
+            | AppQueryKind::GetPolicyVersion => None,
+        }
+    }
+
+    /// Whether this query writes to the entity store. Each query in a `Batch` is judged on its own.
+    pub fn is_mutation(&self) -> bool {
+        matches!(
+            self,
+            AppQueryKind::CreateList(_)
+                | AppQueryKind::UpdateList(_)
+                | AppQueryKind::DeleteList(_)
+                | AppQueryKind::LockList(_)
+                | AppQueryKind::UnlockList(_)
+                | AppQueryKind::SetListTemplate(_)
+                | AppQueryKind::SetListMetadata(_)
+                | AppQueryKind::CreateFolder(_)
+                | AppQueryKind::MoveListToFolder(_)
+                | AppQueryKind::DuplicateList(_)
+                | AppQueryKind::ImportList(_)
+                | AppQueryKind::BulkImport(_)
+                | AppQueryKind::CreateTask(_)
+                | AppQueryKind::CreateTasks(_)
+                | AppQueryKind::UpdateTask(_)
+                | AppQueryKind::DeleteTask(_)
+                | AppQueryKind::SetAllTaskStates(_)
+                | AppQueryKind::AddTaskComment(_)
+                | AppQueryKind::CreateAuthorizedView(_)
+                | AppQueryKind::AddShare(_)
+                | AppQueryKind::AddShares(_)
+                | AppQueryKind::DeleteShare(_)
+                | AppQueryKind::AddOwner(_)
+                | AppQueryKind::RemoveOwner(_)
+                | AppQueryKind::DeleteTeam(_)
+                | AppQueryKind::PurgeDeletedLists
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct AppQuery {
     kind: AppQueryKind,
-    sender: oneshot::Sender<Result<AppResponse>>,
+    // Run `kind` as this user instead of the request's own `uid`, see `AppContext::impersonate`
+    on_behalf_of: Option<UserUid>,
+    cancel: Option<CancellationToken>,
+    // `None` for fire-and-forget queries, whose result nobody waits for
+    sender: Option<oneshot::Sender<Result<AppResponse>>>,
+    // Set by `timed`
+    timing: Option<oneshot::Sender<RequestTiming>>,
 }
 
 impl AppQuery {
     pub fn new(kind: AppQueryKind, sender: oneshot::Sender<Result<AppResponse>>) -> Self {
-        Self { kind, sender }
+        Self { kind, on_behalf_of: None, cancel: None, sender: Some(sender), timing: None }
+    }
+
+    /// A query whose result is discarded. Failures are logged instead.
+    pub fn without_response(kind: AppQueryKind) -> Self {
+        Self { kind, on_behalf_of: None, cancel: None, sender: None, timing: None }
+    }
+
+    /// Also report where the time went while running the query, which arrives before its result
+    pub fn timed(self) -> (Self, oneshot::Receiver<RequestTiming>) {
+        let (send, recv) = oneshot::channel();
+        (Self { timing: Some(send), ..self }, recv)
+    }
+
+    pub fn on_behalf_of(self, on_behalf_of: Option<UserUid>) -> Self {
+        Self { on_behalf_of, ..self }
+    }
+
+    /// Abort the query, returning `Error::Cancelled`, if `cancel` is cancelled before it completes
+    pub fn cancellable(self, cancel: CancellationToken) -> Self {
+        Self { cancel: Some(cancel), ..self }
+    }
+
+    /// Answer the query with `f`'s result, standing in for the application.
+    /// A `timed` query gets an all-zero `RequestTiming`.
+    #[cfg(test)]
+    pub fn answer_with(self, f: impl FnOnce(AppQueryKind) -> Result<AppResponse>) {
+        let r = f(self.kind);
+        if let Some(timing) = self.timing {
+            let _ = timing.send(RequestTiming::default());
+        }
+        if let Some(sender) = self.sender {
+            let _ = sender.send(r);
+        }
+    }
+}
+
+/// Shared flag a client sets to tell the server it no longer wants the answer to a query
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
     }
 }
 
@@ -160,29 +826,192 @@ pub enum Error {
     AuthDenied(Diagnostics),
     #[error("The list {0} does not contain a task with id {1}")]
     InvalidTaskId(EntityUid, i64),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Only the owner of {0} may duplicate it, unless it is a template")]
+    NotATemplate(EntityUid),
+    #[error("The list {0} is locked, so it can't be changed")]
+    ListLocked(EntityUid),
+    #[error("The list {0} already has a task named {1:?}")]
+    Conflict(EntityUid, String),
+    #[error("{0} already has a list named {1:?}")]
+    ListNameTaken(EntityUid, String),
+    #[error("Making {0} a subteam of {1} would create a cycle of teams")]
+    WouldCreateCycle(EntityUid, EntityUid),
+    #[error("The team {0} can't be deleted, it is still used by {1}")]
+    TeamInUse(EntityUid, &'static str),
+    #[error("The query was cancelled")]
+    Cancelled,
+    #[error("The application is too busy to accept the query")]
+    Busy,
+    #[error("The application is in maintenance mode, so it can't be changed")]
+    Maintenance,
+    // Boxed, since the unsent query would otherwise make every `Result` in the crate as large as an `AppQuery`
     #[error("Internal Error")]
-    TokioSend(#[from] tokio::sync::mpsc::error::SendError<AppQuery>),
+    TokioSend(Box<tokio::sync::mpsc::error::SendError<AppQuery>>),
     #[error("Internal Error")]
     TokioRecv(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("Internal Error")]
     Type,
     #[error("Internal Error")]
     IO(#[from] std::io::Error),
+    #[error("The authorization query has complexity {0}, which exceeds the limit of {1}")]
+    QueryTooComplex(usize, usize),
+    #[error("The policies test `{0} in {1}`, which can't be translated to SQL")]
+    UntranslatableMembership(EntityTypeName, EntityTypeName),
+    #[error("Failed to translate residual policies: {0}")]
+    ResidualTranslation(String),
+    #[error("Failed to record audit event: {0}")]
+    Audit(String),
     #[error("Error Parsing PolicySet: {0}")]
     Policy(#[from] ParseErrors),
     #[error("SQL error")]
     SQLError(#[from] rusqlite::Error),
 }
 
+impl From<tokio::sync::mpsc::error::SendError<AppQuery>> for Error {
+    fn from(e: tokio::sync::mpsc::error::SendError<AppQuery>) -> Self {
+        Self::TokioSend(Box::new(e))
+    }
+}
+
+// Errors are reported to clients by their message
+impl Serialize for Error {
+    fn serialize<S>(&self, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(&self.to_string())
+    }
+}
+
 impl Error {
     pub fn no_such_entity(euid: impl Into<EntityUid>) -> Self {
         Self::NoSuchEntity(euid.into())
     }
+
+    /// Whether the query failed only because another connection was using the database,
+    /// so running it again may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::SQLError(rusqlite::Error::SqliteFailure(e, _))
+                if matches!(e.code, rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        )
+    }
 }
 
+// How long to wait before the first retry of a query, see `AppConfig::request_retries`.
+// Each further retry waits this much longer.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+// How many `GetListsPage` result sets to keep before evicting the oldest
+const MAX_RESULT_SETS: usize = 128;
+
+// How many levels of teams `principal_graph` follows above the principal
+const MAX_GRAPH_DEPTH: usize = 8;
+
+// How many replaced policy sets to keep for `RestorePolicies`
+const MAX_POLICY_HISTORY: usize = 16;
+
+// How often to purge the lists deleted longer ago than `AppConfig::deleted_list_retention`
+const DELETED_LIST_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How many `(principal, action)` filters to cache before starting over
+const MAX_PREPARED_FILTERS: usize = 1024;
+
+type RequestKey = (EntityUid, EntityUid, EntityUid);
+
+/// Recent responses to `is_authorized`, keyed by principal, action and resource.
+/// Every mutation clears the cache before it runs, so a cached denial never outlives
+/// a share or membership change that would allow the request.
+#[derive(Default)]
+struct DecisionCache {
+    ttl: Option<Duration>,
+    deny_ttl: Option<Duration>,
+    // The `PRAGMA data_version` the responses were computed at, see `PreparedFilter`
+    data_version: i64,
+    entries: HashMap<RequestKey, (Instant, Response)>,
+    stats: DecisionCacheStats,
+}
+
+impl DecisionCache {
+    fn new(ttl: Option<Duration>, deny_ttl: Option<Duration>) -> Self {
+        Self { ttl, deny_ttl, ..Self::default() }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.ttl.is_some() || self.deny_ttl.is_some()
+    }
+
+    fn ttl_for(&self, decision: Decision) -> Option<Duration> {
+        match decision {
+            Decision::Allow => self.ttl,
+            Decision::Deny => self.deny_ttl.or(self.ttl),
+        }
+    }
+
+    fn is_fresh(&self, at: Instant, response: &Response) -> bool {
+        self.ttl_for(response.decision()).is_some_and(|ttl| at.elapsed() < ttl)
+    }
+
+    fn get(&mut self, key: &RequestKey, data_version: i64) -> Option<Response> {
+        if !self.is_enabled() {
+            return None;
+        }
+        if data_version != self.data_version {
+            self.clear();
+            self.data_version = data_version;
+        }
+        match self.entries.get(key) {
+            Some((at, response)) if self.is_fresh(*at, response) => {
+                self.stats.hits += 1;
+                if response.decision() == Decision::Deny {
+                    self.stats.deny_hits += 1;
+                }
+                Some(response.clone())
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: RequestKey, response: &Response) {
+        if self.ttl_for(response.decision()).is_some() {
+            let entries = std::mem::take(&mut self.entries);
+            self.entries = entries.into_iter().filter(|(_, (at, r))| self.is_fresh(*at, r)).collect();
+            self.entries.insert(key, (Instant::now(), response.clone()));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn stats(&self) -> DecisionCacheStats {
+        DecisionCacheStats { entries: self.entries.len(), ..self.stats.clone() }
+    }
+}
+
+/// The translated residual for a `(principal, action)`, as computed by `get_all_authorized_lists`.
+/// It bakes in the policies and the principal's entity, so it is only reused while neither changed.
+struct PreparedFilter {
+    select: SelectStatement,
+    policy_version: u64,
+    // `PRAGMA data_version` when prepared, which changes when another connection writes the database
+    data_version: i64,
+}
+
+// Policies may carry instructions for the application, e.g. `@obligation("log-access")`,
+// which apply whenever the policy allows a request
+const OBLIGATION_ANNOTATION: &str = "obligation";
+
 lazy_static! {
+    /// The default `AppConfig::application`
     pub static ref APPLICATION_TINY_TODO: EntityUid = r#"Application::"TinyTodo""#.parse().unwrap();
-    static ref ACTION_EDIT_SHARE: EntityUid = r#"Action::"EditShare""#.parse().unwrap();
+    static ref ACTION_EDIT_SHARE: EntityUid = r#"Action::"EditShares""#.parse().unwrap();
     static ref ACTION_UPDATE_TASK: EntityUid = r#"Action::"UpdateTask""#.parse().unwrap();
     static ref ACTION_CREATE_TASK: EntityUid = r#"Action::"CreateTask""#.parse().unwrap();
     static ref ACTION_DELETE_TASK: EntityUid = r#"Action::"DeleteTask""#.parse().unwrap();
@@ -191,6 +1020,107 @@ lazy_static! {
     static ref ACTION_CREATE_LIST: EntityUid = r#"Action::"CreateList""#.parse().unwrap();
     static ref ACTION_UPDATE_LIST: EntityUid = r#"Action::"UpdateList""#.parse().unwrap();
     static ref ACTION_DELETE_LIST: EntityUid = r#"Action::"DeleteList""#.parse().unwrap();
+    static ref ACTION_IMPERSONATE: EntityUid = r#"Action::"Impersonate""#.parse().unwrap();
+    static ref ACTION_GET_TEAMS: EntityUid = r#"Action::"GetTeams""#.parse().unwrap();
+    static ref ACTION_ADD_OWNER: EntityUid = r#"Action::"AddOwner""#.parse().unwrap();
+    static ref ACTION_REMOVE_OWNER: EntityUid = r#"Action::"RemoveOwner""#.parse().unwrap();
+    static ref ACTION_DEBUG_QUERIES: EntityUid = r#"Action::"DebugQueries""#.parse().unwrap();
+    static ref ACTION_VIEW_TASK: EntityUid = r#"Action::"ViewTask""#.parse().unwrap();
+    static ref ACTION_GET_ACTIVITY: EntityUid = r#"Action::"GetActivity""#.parse().unwrap();
+    static ref ACTION_CREATE_FOLDER: EntityUid = r#"Action::"CreateFolder""#.parse().unwrap();
+    static ref ACTION_ADD_TO_FOLDER: EntityUid = r#"Action::"AddToFolder""#.parse().unwrap();
+}
+
+// Every action the server authorizes, which must all be declared in the schema
+fn all_actions() -> [&'static EntityUid; 18] {
+    [
+        &ACTION_EDIT_SHARE,
+        &ACTION_UPDATE_TASK,
+        &ACTION_CREATE_TASK,
+        &ACTION_DELETE_TASK,
+        &ACTION_GET_LISTS,
+        &ACTION_GET_LIST,
+        &ACTION_CREATE_LIST,
+        &ACTION_UPDATE_LIST,
+        &ACTION_DELETE_LIST,
+        &ACTION_IMPERSONATE,
+        &ACTION_GET_TEAMS,
+        &ACTION_ADD_OWNER,
+        &ACTION_REMOVE_OWNER,
+        &ACTION_DEBUG_QUERIES,
+        &ACTION_VIEW_TASK,
+        &ACTION_GET_ACTIVITY,
+        &ACTION_CREATE_FOLDER,
+        &ACTION_ADD_TO_FOLDER,
+    ]
+}
+
+/// The context of an action's requests, as the schema declares it. Building a context from one of these
+/// structs rather than from name and expression pairs means a misspelt or mistyped attribute doesn't compile.
+pub trait ActionContext {
+    /// Each attribute's name and schema type: a primitive like `Long`, or the entity type's name
+    const ATTRIBUTES: &'static [(&'static str, &'static str)];
+
+    /// The action whose requests carry this context
+    fn action() -> &'static EntityUid;
+
+    fn into_context(self) -> Context;
+}
+
+/// `CreateList`: how many lists the principal already owns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreateListContext {
+    pub current_list_count: i64,
+}
+
+impl ActionContext for CreateListContext {
+    const ATTRIBUTES: &'static [(&'static str, &'static str)] = &[("current_list_count", "Long")];
+
+    fn action() -> &'static EntityUid {
+        &ACTION_CREATE_LIST
+    }
+
+    fn into_context(self) -> Context {
+        Context::from_pairs([("current_list_count".to_string(), RestrictedExpression::new_long(self.current_list_count))])
+    }
+}
+
+/// `CreateTask`: how many tasks the list already has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreateTaskContext {
+    pub current_task_count: i64,
+}
+
+impl ActionContext for CreateTaskContext {
+    const ATTRIBUTES: &'static [(&'static str, &'static str)] = &[("current_task_count", "Long")];
+
+    fn action() -> &'static EntityUid {
+        &ACTION_CREATE_TASK
+    }
+
+    fn into_context(self) -> Context {
+        Context::from_pairs([("current_task_count".to_string(), RestrictedExpression::new_long(self.current_task_count))])
+    }
+}
+
+/// `ViewTask`: the team a task is restricted to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewTaskContext {
+    pub visible_to: TeamUid,
+}
+
+impl ActionContext for ViewTaskContext {
+    const ATTRIBUTES: &'static [(&'static str, &'static str)] = &[("visible_to", "Team")];
+
+    fn action() -> &'static EntityUid {
+        &ACTION_VIEW_TASK
+    }
+
+    fn into_context(self) -> Context {
+        let team: RestrictedExpression = EntityUid::from(self.visible_to).to_string().parse()
+            .expect("Entity uids are valid restricted expressions");
+        Context::from_pairs([("visible_to".to_string(), team)])
+    }
 }
 
 pub struct AppContext {
@@ -198,6 +1128,31 @@ pub struct AppContext {
     authorizer: Authorizer,
     policies: PolicySet,
     schema: Schema,
+    // The principal and resource types each action in the schema applies to
+    action_shapes: HashMap<EntityUid, ActionShape>,
+    config: AppConfig,
+    latencies: BTreeMap<&'static str, LatencyHistogram>,
+    // Authorized lists captured by `GetListsPage`, oldest first
+    result_sets: BTreeMap<ResultSetToken, (UserUid, Vec<EntityUid>)>,
+    next_result_set: u64,
+    // The real caller while a query runs on behalf of another user
+    impersonator: Option<UserUid>,
+    // Set by `SetMaintenance`, e.g. to drain writes before a schema migration
+    maintenance: bool,
+    // Bumped whenever the policy set is replaced, counting from 0 when the server starts
+    policy_version: u64,
+    // The policy sets in use before the current one, newest first, with their versions
+    policy_history: VecDeque<(u64, PolicySet)>,
+    // Cleared before any query which writes to the entity store
+    prepared_filters: RefCell<HashMap<(EntityUid, EntityUid), PreparedFilter>>,
+    // Likewise cleared before any write, and when the policies change
+    decisions: RefCell<DecisionCache>,
+    // For the query being handled, reset before each one
+    timing: RefCell<RequestTiming>,
+    // Audit events held back until the transaction they happened in ends, see `audit`
+    pending_audit: RefCell<Vec<AuditEvent>>,
+    // Non-fatal findings about the policies at startup, such as a policy that can never apply
+    validation_warnings: Vec<ValidationIssue>,
     recv: Receiver<AppQuery>,
 }
 
@@ -219,6 +1174,12 @@ pub enum ContextError {
     Validation(String),
     #[error("Error Deserializing Json: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Error Initializing Database: {0}")]
+    Database(#[from] Error),
+    #[error("Actions used by the server are missing from the schema: {0}")]
+    MissingActions(String),
+    #[error("The schema declares a different context for: {0}")]
+    ContextMismatch(String),
 }
 
 impl AppContext {
@@ -227,16 +1188,48 @@ impl AppContext {
         entities_path: impl Into<PathBuf>,
         schema_path: impl Into<PathBuf>,
         policies_path: impl Into<PathBuf>,
+        mut config: AppConfig,
     ) -> std::result::Result<Sender<AppQuery>, ContextError> {
         info!("Starting server");
+        for action in config.fail_open_actions.iter() {
+            warn!("Action {action} is configured to FAIL OPEN: denied requests for it will be allowed");
+        }
 
         let schema_path = schema_path.into();
         let policies_path = policies_path.into();
-        let schema_file = std::fs::File::open(&schema_path)?;
-        let schema = Schema::from_file(schema_file)?;
+        let schema_src = std::fs::read_to_string(&schema_path)?;
+        let action_shapes = check_actions_declared(&schema_src)?;
+        let schema: Schema = schema_src.parse()?;
 
         // let entities_file = std::fs::File::open(entities_path.into())?;
-        let entities = EntityStore::from_file(entities_path.into());
+        let entities_path = entities_path.into();
+        let mut entities = if config.share_connection {
+            info!("Serving tenant {} on a shared connection", config.application);
+            SharedConnection::for_file(&entities_path, config.sqlite, &config.attribute_defaults)?
+                .store_for(config.application.clone())
+        } else if config.read_only {
+            info!("Opening the database read-only");
+            let entities = EntityStore::open_read_only(entities_path)?
+                .with_application(config.application.clone())
+                .with_tuning(config.sqlite)?
+                .with_attribute_defaults(config.attribute_defaults.clone());
+            entities.create_defaults_views()?;
+            entities
+        } else {
+            let entities = EntityStore::from_file(entities_path)
+                .with_application(config.application.clone())
+                .with_tuning(config.sqlite)?
+                .with_attribute_defaults(config.attribute_defaults.clone());
+            entities.initialize_schema()?;
+            entities.bootstrap_defaults(None)?;
+            entities
+        };
+        if config.membership_cache {
+            entities = entities.with_membership_cache();
+        }
+        if config.eager_task_loading {
+            entities = entities.with_eager_task_loading();
+        }
 
         let policy_src = std::fs::read_to_string(&policies_path)?;
         let policies = policy_src.parse()?;
@@ -244,19 +1237,31 @@ impl AppContext {
         let output = validator.validate(&policies, ValidationMode::default());
         if output.validation_passed() {
             info!("Validation passed!");
-            let authorizer = Authorizer::new();
+            let validation_warnings = validation_warnings(&output);
+            for warning in &validation_warnings {
+                warn!("Policy {}: {}", warning.policy_id, warning.message);
+            }
+            let authorizer = config.authorizer.take().unwrap_or_default();
             let (send, recv) = tokio::sync::mpsc::channel(100);
             let tx = send.clone();
+            let read_only = config.read_only;
+            // A read-only connection can't checkpoint, the primary does
+            if let Some(interval) = config.wal_checkpoint_interval.filter(|_| !read_only) {
+                spawn_wal_checkpoints(interval, send.clone());
+            }
+            if config.deleted_list_retention.is_some() && !read_only {
+                spawn_deleted_list_purges(send.clone());
+            }
             tokio::spawn(async move {
                 info!("Serving application server!");
                 policy_store::spawn_watcher(policies_path, schema_path, tx).await;
-                let c = Self {
-                    entities,
-                    authorizer,
-                    policies,
-                    schema,
-                    recv,
-                };
+                let mut c = Self::new(entities, authorizer, policies, schema, action_shapes, config, recv);
+                c.validation_warnings = validation_warnings;
+                c.entities.check_out().await;
+                if let Err(e) = c.refresh_authorized_views() {
+                    error!("Failed to refresh authorized views: {e}");
+                }
+                c.entities.check_in(true);
                 c.serve().await
             });
 
@@ -270,195 +1275,1871 @@ impl AppContext {
         }
     }
 
+    fn new(
+        entities: EntityStore,
+        authorizer: Authorizer,
+        policies: PolicySet,
+        schema: Schema,
+        action_shapes: HashMap<EntityUid, ActionShape>,
+        config: AppConfig,
+        recv: Receiver<AppQuery>,
+    ) -> Self {
+        Self {
+            entities,
+            authorizer,
+            policies,
+            schema,
+            action_shapes,
+            latencies: BTreeMap::new(),
+            result_sets: BTreeMap::new(),
+            next_result_set: 0,
+            impersonator: None,
+            maintenance: config.read_only,
+            policy_version: 0,
+            policy_history: VecDeque::new(),
+            prepared_filters: RefCell::new(HashMap::new()),
+            decisions: RefCell::new(DecisionCache::new(config.decision_cache_ttl, config.deny_cache_ttl)),
+            timing: RefCell::new(RequestTiming::default()),
+            pending_audit: RefCell::new(Vec::new()),
+            validation_warnings: Vec::new(),
+            config,
+            recv,
+        }
+    }
+
     #[tracing::instrument]
     async fn serve(mut self) -> Result<()> {
         loop {
-            if let Some(msg) = self.recv.recv().await {
-                let r = match msg.kind {
-                    AppQueryKind::GetList(r) => self.get_list(r),
-                    AppQueryKind::CreateList(r) => self.create_list(r),
-                    AppQueryKind::UpdateList(r) => self.update_list(r),
-                    AppQueryKind::DeleteList(r) => self.delete_list(r),
-                    AppQueryKind::CreateTask(r) => self.create_task(r),
-                    AppQueryKind::UpdateTask(r) => self.update_task(r),
-                    AppQueryKind::DeleteTask(r) => self.delete_task(r),
-                    AppQueryKind::GetLists(r) => self.get_lists(r),
-                    AppQueryKind::AddShare(r) => self.add_share(r),
-                    AppQueryKind::DeleteShare(r) => self.delete_share(r),
-                    AppQueryKind::UpdatePolicySet(set) => self.update_policy_set(set),
+            if let Some(mut msg) = self.recv.recv().await {
+                let kind_name = msg.kind.name();
+                let wrote = msg.kind.is_mutation() || matches!(msg.kind, AppQueryKind::Batch(_));
+                let start = Instant::now();
+                self.timing.take();
+                self.entities.check_out().await;
+                let r = if msg.cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                    Err(Error::Cancelled)
+                } else {
+                    self.entities.set_cancellation(msg.cancel.clone());
+                    let r = match self.impersonate(&mut msg.kind, msg.on_behalf_of) {
+                        Ok(()) => self.handle_retrying(msg.kind).await,
+                        Err(e) => Err(e),
+                    };
+                    self.entities.set_cancellation(None);
+                    match r {
+                        Err(Error::SQLError(rusqlite::Error::SqliteFailure(e, _)))
+                            if e.code == rusqlite::ErrorCode::OperationInterrupted =>
+                        {
+                            Err(Error::Cancelled)
+                        }
+                        r => r,
+                    }
                 };
-                if let Err(e) = msg.sender.send(r) {
-                    trace!("Failed send response: {:?}", e);
+                self.entities.check_in(wrote);
+                self.impersonator = None;
+                self.latencies.entry(kind_name).or_default().record(start.elapsed());
+                if let Some(sender) = msg.timing {
+                    let timing = RequestTiming { total_micros: start.elapsed().as_micros(), ..self.timing.take() };
+                    // The client may already be gone, in which case nobody wants the timing either
+                    let _ = sender.send(timing);
+                }
+                match msg.sender {
+                    Some(sender) => {
+                        if let Err(e) = sender.send(r) {
+                            trace!("Failed send response: {:?}", e);
+                        }
+                    }
+                    None => {
+                        if let Err(e) = r {
+                            warn!("Fire-and-forget {kind_name} failed: {e}");
+                        }
+                    }
                 }
             }
         }
     }
 
-    #[tracing::instrument(skip(policy_set))]
-    fn update_policy_set(&mut self, policy_set: PolicySet) -> Result<AppResponse> {
-        self.policies = policy_set;
-        info!("Reloaded policy set");
-        Ok(AppResponse::Unit(()))
+    // Re-run `kind` while it fails with a retryable error, up to `config.request_retries` times.
+    // The backoff stalls every other query too, which is intended: they would find the database just as
+    // busy, and only run out their own retries sooner. Keep the retries and `RETRY_BACKOFF` small.
+    async fn handle_retrying(&mut self, kind: AppQueryKind) -> Result<AppResponse> {
+        let retries = self.config.request_retries;
+        // Batches manage their own transaction, and a non-atomic one can't be undone
+        if retries == 0 || matches!(kind, AppQueryKind::Batch(_)) {
+            return self.handle(kind);
+        }
+        let mut attempt = 0;
+        loop {
+            match self.handle_atomically(kind.clone()) {
+                Err(e) if e.is_retryable() && attempt < retries => {
+                    attempt += 1;
+                    info!("Retrying {} after: {e} (retry {attempt} of {retries})", kind.name());
+                    tokio::time::sleep(RETRY_BACKOFF * attempt).await;
+                }
+                r => return r,
+            }
+        }
     }
 
-    fn add_share(&mut self, r: AddShare) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_EDIT_SHARE, &r.list)?;
-        // let list = self.entities.get_list(&r.list)?;
-        // let team_uid = list.get_team(r.role).clone();
-        // let target_entity = self.entities.get_user_or_team_mut(&r.share_with)?;
-        // target_entity.insert_parent(team_uid);
-        Ok(AppResponse::Unit(()))
+    // Run a mutation in its own transaction, so a failed attempt leaves nothing behind.
+    // A bulk import already runs in its own transaction, and can't join another.
+    fn handle_atomically(&mut self, kind: AppQueryKind) -> Result<AppResponse> {
+        if !kind.is_mutation() || matches!(kind, AppQueryKind::BulkImport(_)) {
+            return self.handle(kind);
+        }
+        self.transaction(|c| c.handle(kind))
     }
 
-    fn delete_share(&mut self, r: DeleteShare) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_EDIT_SHARE, &r.list)?;
-        // let list = self.entities.get_list(&r.list)?;
-        // let team_uid = list.get_team(r.role).clone();
-        // let target_entity = self.entities.get_user_or_team_mut(&r.unshare_with)?;
-        // target_entity.delete_parent(&team_uid);
-        Ok(AppResponse::Unit(()))
+    // Run `f` in a transaction, committing if it succeeds and rolling back otherwise.
+    // Audit events from inside it are recorded once it's over, so a denial is audited even though it rolled back.
+    fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.entities.begin_transaction()?;
+        let r = f(self);
+        let r = match self.entities.end_transaction(r.is_ok()) {
+            Ok(()) => r,
+            Err(e) => {
+                // A `COMMIT` which fails because the database is busy leaves the transaction open
+                if let Err(rollback) = self.entities.end_transaction(false) {
+                    trace!("Rollback after failed commit: {rollback}");
+                }
+                Err(e)
+            }
+        };
+        for event in self.pending_audit.take() {
+            self.record_audit(&event);
+        }
+        r
+    }
 
+    fn handle(&mut self, kind: AppQueryKind) -> Result<AppResponse> {
+        self.validate_request(&kind)?;
+        if kind.is_mutation() {
+            if self.maintenance {
+                return Err(Error::Maintenance);
+            }
+            // The mutation may change memberships baked into the filters and decisions
+            self.prepared_filters.get_mut().clear();
+            self.decisions.get_mut().clear();
+        }
+        match kind {
+            AppQueryKind::GetList(r) => self.get_list(r),
+            AppQueryKind::CreateList(r) => self.create_list(r),
+            AppQueryKind::UpdateList(r) => self.update_list(r),
+            AppQueryKind::CreateFolder(r) => self.create_folder(r),
+            AppQueryKind::MoveListToFolder(r) => self.move_list_to_folder(r),
+            AppQueryKind::ExplainListExclusion(r) => Ok(AppResponse::Reasons(self.explain_list_exclusion(&r.uid, &r.list))),
+            AppQueryKind::DeleteList(r) => self.delete_list(r),
+            AppQueryKind::LockList(r) => self.set_list_locked(&r.uid, &r.list, true),
+            AppQueryKind::UnlockList(r) => self.set_list_locked(&r.uid, &r.list, false),
+            AppQueryKind::SetListTemplate(r) => self.set_list_template(r),
+            AppQueryKind::SetListMetadata(r) => self.set_list_metadata(r),
+            AppQueryKind::DuplicateList(r) => self.duplicate_list(r),
+            AppQueryKind::ExportList(r) => self.export_list(r),
+            AppQueryKind::ImportList(r) => self.import_list(r),
+            AppQueryKind::CreateTask(r) => self.create_task(r),
+            AppQueryKind::CreateTasks(r) => self.create_tasks(r),
+            AppQueryKind::UpdateTask(r) => self.update_task(r),
+            AppQueryKind::DeleteTask(r) => self.delete_task(r),
+            AppQueryKind::SetAllTaskStates(r) => self.set_all_task_states(r),
+            AppQueryKind::AddTaskComment(r) => self.add_task_comment(r),
+            AppQueryKind::GetTaskComments(r) => self.get_task_comments(r),
+            AppQueryKind::GetLists(r) => self.get_lists(r),
+            AppQueryKind::GetListsDetailed(r) => self.get_lists_detailed(r),
+            AppQueryKind::GetMyOwnedLists(r) => self.get_my_owned_lists(r),
+            AppQueryKind::GetListsPage(r) => self.get_lists_page(r),
+            AppQueryKind::GetTemplates(r) => self.get_templates(r),
+            AppQueryKind::GetRecentLists(r) => self.get_recent_lists(r),
+            AppQueryKind::GetMyTasks(r) => self.get_my_tasks(r),
+            AppQueryKind::GetMyTeams(r) => self.get_my_teams(r),
+            AppQueryKind::GetListsSql(r) => self.get_lists_sql(r),
+            AppQueryKind::GetUserActivity(r) => self.get_user_activity(r),
+            AppQueryKind::GetApplicationCapabilities(r) => self.get_application_capabilities(r),
+            AppQueryKind::Authorize(r) => Ok(AppResponse::Obligations(
+                self.authorize_in(&r.uid, &r.action, &r.resource, Context::empty())?,
+            )),
+            AppQueryKind::GetResidual(r) => self.get_residual(r),
+            AppQueryKind::CreateAuthorizedView(r) => self.create_authorized_view(&r.principal, &r.view_name),
+            AppQueryKind::AddShare(r) => self.add_share(r),
+            AppQueryKind::AddShares(r) => self.add_shares(r),
+            AppQueryKind::DeleteShare(r) => self.delete_share(r),
+            AppQueryKind::PreviewShare(r) => self.preview_share(r),
+            AppQueryKind::AddOwner(r) => self.add_owner(r),
+            AppQueryKind::RemoveOwner(r) => self.remove_owner(r),
+            AppQueryKind::UpdatePolicySet(set) => self.update_policy_set(set.into()),
+            AppQueryKind::UpdatePolicySetSrc(src) => match src.parse() {
+                Ok(set) => {
+                    self.update_policy_set(set)?;
+                    Ok(AppResponse::PolicyParseErrors(vec![]))
+                }
+                Err(e) => Ok(AppResponse::PolicyParseErrors(PolicyParseError::from_parse_errors(&src, &e))),
+            },
+            AppQueryKind::Batch(r) => self.batch(r),
+            AppQueryKind::PreviewPolicy(r) => self.preview_policy(r),
+            AppQueryKind::EvaluateCorpus(r) => {
+                let policies = r.policies.map(PolicySet::from);
+                Ok(AppResponse::Decisions(self.evaluate_corpus(policies.as_ref().unwrap_or(&self.policies), &r.requests)))
+            }
+            AppQueryKind::DiffAuthorization(r) => {
+                let new = PolicySet::from(r.policies);
+                let CorpusRequest { principal, action, resource } = r.request;
+                Ok(AppResponse::AuthDiff(self.authorization_diff(&self.policies, &new, principal, action, resource)))
+            }
+            AppQueryKind::GetPrincipalGraph(r) => Ok(AppResponse::Graph(self.principal_graph(&r.principal)?)),
+            AppQueryKind::GetListsForPrincipals(r) => {
+                let action = r.action.unwrap_or_else(|| ACTION_GET_LIST.clone());
+                Ok(AppResponse::ListsByPrincipal(self.authorized_lists_for_many(&r.principals, &action)?))
+            }
+            AppQueryKind::ExplainRequest(r) => {
+                Ok(AppResponse::Explanation(self.explain_request(r.principal, r.action, r.resource)))
+            }
+            AppQueryKind::BulkImport(r) => self.bulk_import(r),
+            AppQueryKind::SnapshotPolicies => Ok(AppResponse::Text(self.snapshot_policies().to_string())),
+            AppQueryKind::GetPolicyHistory => Ok(AppResponse::PolicyHistory(
+                self.policy_history.iter()
+                    .map(|(version, policies)| PolicySnapshot { version: *version, policies: policies.clone().into() })
+                    .collect(),
+            )),
+            AppQueryKind::RestorePolicies(r) => {
+                let snapshot = match r.version {
+                    Some(version) => self.policy_history.iter().find(|(v, _)| *v == version),
+                    None => self.policy_history.front(),
+                };
+                let Some((version, policies)) = snapshot.cloned() else {
+                    return Err(Error::InvalidInput("No such policy set in the history".to_string()));
+                };
+                info!("Restoring policy set version {version}");
+                self.restore_policies(policies)
+            }
+            AppQueryKind::DeleteTeam(r) => {
+                self.entities.delete_team(&r.team, r.detach_members)?;
+                info!("Deleted team {}", EntityUid::from(r.team));
+                Ok(AppResponse::Unit(()))
+            }
+            AppQueryKind::GetStats => self.get_stats(),
+            AppQueryKind::GetDecisionCacheStats => Ok(AppResponse::DecisionCacheStats(self.decisions.borrow().stats())),
+            AppQueryKind::GetStoreStats => Ok(AppResponse::StoreStats(self.entities.stats()?)),
+            AppQueryKind::Validate => self.validate(),
+            AppQueryKind::GetValidationWarnings => Ok(AppResponse::ValidationWarnings(self.validation_warnings.clone())),
+            AppQueryKind::SetMaintenance(enabled) => {
+                self.set_maintenance(enabled);
+                Ok(AppResponse::Unit(()))
+            }
+            AppQueryKind::GetApplication => Ok(AppResponse::Application(Application::new(self.config.application.clone()))),
+            AppQueryKind::GetPolicyVersion => Ok(AppResponse::PolicyVersion(self.policy_version)),
+            AppQueryKind::CheckpointWal => {
+                self.entities.checkpoint_wal()?;
+                Ok(AppResponse::Unit(()))
+            }
+            AppQueryKind::PurgeDeletedLists => {
+                let purged = self.entities.purge_deleted(self.config.deleted_list_retention.unwrap_or_default())?;
+                info!("Purged {purged} deleted lists");
+                Ok(AppResponse::Unit(()))
+            }
+        }
     }
 
-    fn update_task(&mut self, r: UpdateTask) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_UPDATE_TASK, &r.list)?;
-        if let Some(new_state) = r.state {
-            self.entities.update_task(&r.list, r.task, new_state)?;
+    /// Make `kind` act as `target` instead of its own principal, if that principal may impersonate `target`.
+    /// Every authorization decision made while handling `kind` is audited with the real caller.
+    fn impersonate(&mut self, kind: &mut AppQueryKind, target: Option<UserUid>) -> Result<()> {
+        let Some(target) = target else {
+            return Ok(());
+        };
+        if let AppQueryKind::Batch(batch) = kind {
+            return batch.queries.iter_mut().try_for_each(|q| self.impersonate(q, Some(target.clone())));
         }
-        // TODO: allow update name
-        Ok(AppResponse::Unit(()))
+        let kind_name = kind.name();
+        let principal = kind.principal_mut().ok_or_else(|| {
+            Error::InvalidInput(format!("{kind_name} can't be run on behalf of another user"))
+        })?;
+        self.is_authorized(&*principal, &*ACTION_IMPERSONATE, &target)?;
+        info!("{} is running {kind_name} on behalf of {}", self.redact(&*principal), self.redact(&target));
+        self.impersonator = Some(std::mem::replace(principal, target));
+        Ok(())
     }
 
-    fn create_task(&mut self, r: CreateTask) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_CREATE_TASK, &r.list)?;
+    // Only queries made by a user, which authorize themselves, may be batched. Operator commands,
+    // nested batches among them, have no HTTP route of their own and mustn't gain one through `/api/batch`.
+    // Refusing them also keeps in-memory changes such as policy swaps, which a rollback couldn't undo,
+    // out of atomic batches.
+    fn batch(&mut self, mut r: Batch) -> Result<AppResponse> {
+        for q in r.queries.iter_mut() {
+            if q.principal_mut().is_none() {
+                return Err(Error::InvalidInput(format!("{} can't be run in a batch", q.name())));
+            }
+        }
+        if !r.atomic {
+            let results = r.queries.into_iter().map(|q| self.handle(q)).collect();
+            return Ok(AppResponse::Batch(results));
+        }
+        self.transaction(|c| {
+            let results = r.queries.into_iter().map(|q| c.handle(q).map(Ok)).collect::<Result<_>>()?;
+            Ok(AppResponse::Batch(results))
+        })
+    }
 
-        let task_id = self.entities.create_task(&r.list, r.name)?;
-        Ok(AppResponse::TaskId(task_id))
+    // Queries naming their own principal, action and resource are checked against the schema before
+    // dispatch, so a mistyped uid fails with a clear message rather than a confusing denial.
+    // The other queries' uids are typed, so deserializing them already checked their types.
+    fn validate_request(&self, kind: &AppQueryKind) -> Result<()> {
+        let check = |p: &EntityUid, a: &EntityUid, r: &EntityUid| self.validate_shape(p.type_name(), a, r.type_name());
+        match kind {
+            AppQueryKind::Authorize(r) => check(r.uid.as_ref(), &r.action, &r.resource),
+            AppQueryKind::ExplainRequest(r) => check(&r.principal, &r.action, &r.resource),
+            AppQueryKind::DiffAuthorization(r) => check(&r.request.principal, &r.request.action, &r.request.resource),
+            AppQueryKind::EvaluateCorpus(r) => r.requests.iter().try_for_each(|r| check(&r.principal, &r.action, &r.resource)),
+            AppQueryKind::GetListsForPrincipals(r) => {
+                let action = r.action.as_ref().unwrap_or(&ACTION_GET_LIST);
+                r.principals.iter().try_for_each(|p| self.validate_shape(p.type_name(), action, &TYPE_LIST))
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn delete_task(&mut self, r: DeleteTask) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_DELETE_TASK, &r.list)?;
-        self.entities.delete_task(&r.list, r.task)?;
-        Ok(AppResponse::Unit(()))
+    fn validate_shape(&self, principal: &EntityTypeName, action: &EntityUid, resource: &EntityTypeName) -> Result<()> {
+        let shape = self.action_shapes.get(action)
+            .ok_or_else(|| Error::InvalidInput(format!("{action} is not an action in the schema")))?;
+        if !shape.principal_types.contains(principal) {
+            return Err(Error::InvalidInput(format!(
+                "{action} applies to principals of type {}, not {principal}",
+                shape.principal_types.iter().join(" or ")
+            )));
+        }
+        if !shape.resource_types.contains(resource) {
+            return Err(Error::InvalidInput(format!(
+                "{action} applies to resources of type {}, not {resource}",
+                shape.resource_types.iter().join(" or ")
+            )));
+        }
+        Ok(())
     }
 
-    fn get_lists(&self, r: GetLists) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &*APPLICATION_TINY_TODO)?;
+    /// Reject queries that write to the entity store until maintenance is disabled again.
+    /// Reads are still served.
+    pub fn set_maintenance(&mut self, enabled: bool) {
+        if enabled != self.maintenance {
+            warn!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+        }
+        self.maintenance = enabled;
+    }
 
-        let mut query_expr = self.get_all_authorized_lists(&r.uid, &*ACTION_GET_LIST)?;
-        let select = query_expr
-            .column((Alias::new("resource"), Alias::new("uid")))
-            .from_as(Alias::new("lists"), Alias::new("resource"))
-            .to_string(SqliteQueryBuilder);
+    fn redact(&self, uid: impl AsRef<EntityUid>) -> String {
+        self.config.log_redaction.redact(uid.as_ref())
+    }
 
-        info!("Running select query {}", select);
-        let result = self.entities.get_lists(select)?;
+    // The generated SQL can contain the principal's id, so it is only logged when ids aren't redacted
+    fn log_select(&self, select: &str) {
+        if self.config.log_redaction == LogRedaction::None {
+            info!("Running select query {}", select);
+        }
+    }
 
-        Ok(AppResponse::Lists(result.into()))
+    /// The policy set in use, e.g. to put back later with `restore_policies`
+    pub fn snapshot_policies(&self) -> PolicySet {
+        self.policies.clone()
     }
 
-    fn create_list(&mut self, r: CreateList) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_CREATE_LIST, &*APPLICATION_TINY_TODO)?;
-        let readers = self.entities.create_team()?;
-        let editors = self.entities.create_team()?;
+    /// Go back to `snapshot`. The policies it replaces are kept in the history like any others,
+    /// so a restore can itself be undone.
+    pub fn restore_policies(&mut self, snapshot: PolicySet) -> Result<AppResponse> {
+        self.update_policy_set(snapshot)
+    }
 
-        let result = self.entities.create_list(r.uid, &r.name, readers, editors)?;
-        Ok(AppResponse::euid(result))
+    #[tracing::instrument(skip(policy_set))]
+    fn update_policy_set(&mut self, policy_set: PolicySet) -> Result<AppResponse> {
+        let replaced = std::mem::replace(&mut self.policies, policy_set);
+        if self.policy_history.len() >= MAX_POLICY_HISTORY {
+            self.policy_history.pop_back();
+        }
+        self.policy_history.push_front((self.policy_version, replaced));
+        self.policy_version += 1;
+        self.decisions.get_mut().clear();
+        info!("Reloaded policy set");
+        self.refresh_authorized_views()?;
+        Ok(AppResponse::Unit(()))
     }
 
-    fn get_list(&self, r: GetList) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_GET_LIST, &r.list)?;
-        let list = self.entities.get_list(&r.list)?;
-        Ok(AppResponse::GetList(Box::new(list)))
+    fn preview_policy(&self, r: PreviewPolicy) -> Result<AppResponse> {
+        let candidate = r.policies.into();
+        let mut impact = PolicyImpact::default();
+        let lists = self.entities.get_all_lists()?;
+        self.entities.with_prefetched_lists(&lists, |_| {
+            for list in lists.iter().cloned() {
+                match self.diff_policy_impact(&candidate, &r.principal, &list) {
+                    (false, true) => impact.granted.push(list),
+                    (true, false) => impact.revoked.push(list),
+                    _ => (),
+                }
+            }
+        })?;
+        info!("Candidate policies would grant {} and revoke {} lists", impact.granted.len(), impact.revoked.len());
+        Ok(AppResponse::PolicyImpact(impact))
     }
 
-    fn update_list(&mut self, r: UpdateList) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_UPDATE_LIST, &r.list)?;
-        self.entities.update_list(&r.list, &r.name)?;
-        Ok(AppResponse::Unit(()))
+    /// Decide every request in `requests` under `policies`.
+    /// Entities are cached per principal, since a corpus usually has many requests for each one,
+    /// and the lists among the resources are all loaded up front.
+    pub fn evaluate_corpus(&self, policies: &PolicySet, requests: &[CorpusRequest]) -> Vec<Decision> {
+        let decide = || {
+            let mut caches: HashMap<&EntityUid, MemoizedEntities> = HashMap::new();
+            requests
+                .iter()
+                .map(|r| {
+                    let es = caches.entry(&r.principal).or_insert_with(|| MemoizedEntities::new(&self.entities));
+                    let q = Request::new(
+                        Some(r.principal.clone().into()),
+                        Some(r.action.clone().into()),
+                        Some(r.resource.clone().into()),
+                        Context::empty(),
+                    );
+                    self.authorizer.is_authorized_full_parsed(&q, policies, es).decision()
+                })
+                .collect()
+        };
+        let lists = requests.iter()
+            .filter_map(|r| ListUid::try_from(r.resource.clone()).ok())
+            .unique()
+            .collect::<Vec<_>>();
+        self.entities.with_prefetched_lists(&lists, |_| decide()).unwrap_or_else(|e| {
+            warn!("Couldn't prefetch the corpus' lists, loading them one at a time: {e}");
+            decide()
+        })
     }
 
-    fn delete_list(&mut self, r: DeleteList) -> Result<AppResponse> {
-        self.is_authorized(&r.uid, &*ACTION_DELETE_LIST, &r.list)?;
-        self.entities.delete_list(&r.list)?;
-        Ok(AppResponse::Unit(()))
+    /// Decide a request as if `entity_overrides` replaced the stored entities with the same uids
+    /// (or were added, for new uids). Nothing is audited, and fail-open actions are ignored.
+    pub fn authorize_with_overrides(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+        entity_overrides: Vec<ParsedEntity>,
+    ) -> Decision {
+        let q = Request::new(
+            Some(principal.as_ref().clone().into()),
+            Some(action.as_ref().clone().into()),
+            Some(resource.as_ref().clone().into()),
+            Context::empty(),
+        );
+        let es = MemoizedEntities::with_overrides(&self.entities, entity_overrides);
+        self.authorizer.is_authorized_full_parsed(&q, &self.policies, &es).decision()
     }
 
-    pub fn get_all_authorized_lists(&self, principal: impl AsRef<EntityUid>, action: impl AsRef<EntityUid>) -> Result<SelectStatement> {
-        let q = Request::builder()
-            .principal(Some(principal.as_ref().clone().into()))
-            .action(Some(action.as_ref().clone().into()))
-            .resource_type("List".parse().unwrap())
-            .build();
+    /// Decide the same request under `old` and `new`, and report which policies account for the difference
+    pub fn authorization_diff(
+        &self,
+        old: &PolicySet,
+        new: &PolicySet,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+    ) -> AuthDiff {
+        let q = Request::new(
+            Some(principal.as_ref().clone().into()),
+            Some(action.as_ref().clone().into()),
+            Some(resource.as_ref().clone().into()),
+            Context::empty(),
+        );
+        // Both decisions see the same entities
         let es = CachedEntities::cache_request(&self.entities, &q);
-        let response = self.authorizer.is_authorized_parsed(&q, &self.policies, &es);
-        match response {
-            cedar_policy::PartialResponse::Concrete(response) => {
-                Ok(Query::select().and_where((response.decision() == Decision::Allow).into()).to_owned())
-            },
-            cedar_policy::PartialResponse::Residual(res) => {
-                Ok(translate_response(&res, &self.schema,
-                    &InByTable(|t1, t2| {
-                    if *t1 == *TYPE_USER && *t2 == *TYPE_TEAM {
-                        Ok((Alias::new("team_memberships"), Alias::new("user_uid"), Alias::new("team_uid")))
-                    } else {
-                        panic!("No tables available for membership test of types {:?} and {:?}", t1, t2)
-                    }
-                })).expect("Failed to translate residual policies"))
-            },
+        let old = self.authorizer.is_authorized_full_parsed(&q, old, &es);
+        let new = self.authorizer.is_authorized_full_parsed(&q, new, &es);
+        let reasons = |response: &Response| response.diagnostics().reason().map(ToString::to_string).collect::<BTreeSet<_>>();
+        let (old_reasons, new_reasons) = (reasons(&old), reasons(&new));
+        AuthDiff {
+            old: old.decision(),
+            new: new.decision(),
+            changed: old.decision() != new.decision(),
+            removed_reasons: old_reasons.difference(&new_reasons).cloned().collect(),
+            added_reasons: new_reasons.difference(&old_reasons).cloned().collect(),
         }
     }
 
-    #[tracing::instrument(skip_all)]
-    pub fn is_authorized(
+    /// Decide a request as `is_authorized` would, without auditing it, and report which entities were loaded
+    pub fn explain_request(
         &self,
         principal: impl AsRef<EntityUid>,
         action: impl AsRef<EntityUid>,
         resource: impl AsRef<EntityUid>,
-    ) -> Result<()> {
+    ) -> Explanation {
         let q = Request::new(
             Some(principal.as_ref().clone().into()),
             Some(action.as_ref().clone().into()),
             Some(resource.as_ref().clone().into()),
             Context::empty(),
         );
-        let es = CachedEntities::cache_request(&self.entities, &q);
-        info!(
-            "is_authorized request: principal: {}, action: {}, resource: {}",
-            principal.as_ref(),
-            action.as_ref(),
-            resource.as_ref()
+        // Every fetch made while caching the request goes through `loaded`, which remembers it
+        let loaded = MemoizedEntities::new(&self.entities);
+        let es = CachedEntities::cache_request(&loaded, &q);
+        let response = self.authorizer.is_authorized_full_parsed(&q, &self.policies, &es);
+        let mut entities = loaded
+            .into_loaded()
+            .into_iter()
+            .map(|entity| LoadedEntity {
+                uid: entity.uid().into(),
+                ancestors: entity.ancestors().cloned().map(EntityUid::from).sorted_by_key(ToString::to_string).collect(),
+            })
+            .collect::<Vec<_>>();
+        entities.sort_by_key(|entity| entity.uid.to_string());
+        Explanation {
+            decision: response.decision(),
+            reasons: response.diagnostics().reason().map(ToString::to_string).collect(),
+            errors: response.diagnostics().errors().collect(),
+            entities,
+        }
+    }
+
+    /// Why `list` isn't among `principal`'s lists: the forbid policies which deny them `GetList` on it,
+    /// or `no permit matched`. Empty if the list isn't excluded. Like `explain_request`, nothing is audited.
+    pub fn explain_list_exclusion(&self, principal: &UserUid, list: &ListUid) -> Vec<String> {
+        let q = Request::new(
+            Some(principal.as_ref().clone().into()),
+            Some(ACTION_GET_LIST.clone().into()),
+            Some(list.as_ref().clone().into()),
+            Context::empty(),
         );
+        let es = CachedEntities::cache_request(&self.entities, &q);
         let response = self.authorizer.is_authorized_full_parsed(&q, &self.policies, &es);
-        info!("Auth response: {:?}", response);
-        match response.decision() {
-            Decision::Allow => Ok(()),
-            Decision::Deny => Err(Error::AuthDenied(response.diagnostics().clone())),
+        if response.decision() == Decision::Allow {
+            return Vec::new();
+        }
+        // A denial's reasons are the forbid policies which were satisfied
+        let forbids = response.diagnostics().reason().map(ToString::to_string).collect::<Vec<_>>();
+        if forbids.is_empty() {
+            vec!["no permit matched".to_string()]
+        } else {
+            forbids
         }
     }
-}
 
-// #[test]
-// fn test_is_authorized_partial() {
-//     let schema_path = "./tinytodo.cedarschema.json";
-//     let entities_path = "./huge_entities.db";
-//     let policies_path = "./policies.cedar";
+    /// Returns whether `principal` may read `list` under the current policies and under `candidate`
+    pub fn diff_policy_impact(&self, candidate: &PolicySet, principal: &UserUid, list: &ListUid) -> (bool, bool) {
+        (
+            self.is_allowed_by(&self.policies, principal, &*ACTION_GET_LIST, list),
+            self.is_allowed_by(candidate, principal, &*ACTION_GET_LIST, list),
+        )
+    }
 
-//     let schema_path: PathBuf = schema_path.into();
-//     let policies_path: PathBuf = policies_path.into();
-//     let schema_file = std::fs::File::open(&schema_path).unwrap();
-//     let schema = Schema::from_file(schema_file).unwrap();
+    // Unlike `is_authorized`, this ignores fail-open actions
+    fn is_allowed_by(
+        &self,
+        policies: &PolicySet,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+    ) -> bool {
+        let q = Request::new(
+            Some(principal.as_ref().clone().into()),
+            Some(action.as_ref().clone().into()),
+            Some(resource.as_ref().clone().into()),
+            Context::empty(),
+        );
+        let es = CachedEntities::cache_request(&self.entities, &q);
+        self.authorizer.is_authorized_full_parsed(&q, policies, &es).decision() == Decision::Allow
+    }
 
-//     let entities = EntityStore::from_file(<PathBuf as From<&str>>::from(entities_path));
+    fn get_stats(&self) -> Result<AppResponse> {
+        let stats = self.latencies.iter()
+            .map(|(kind, histogram)| QueryStats::new(kind, histogram))
+            .collect();
+        Ok(AppResponse::Stats(stats))
+    }
 
-//     let policy_src = std::fs::read_to_string(&policies_path).unwrap();
-//     let policies = policy_src.parse().unwrap();
-//     let validator = Validator::new(schema.clone());
-//     let output = validator.validate(&policies, ValidationMode::default());
-//     assert!(output.validation_passed());
-//     let authorizer = Authorizer::new();
+    /// Validate the current policies against the schema, as is done at startup, without changing anything
+    fn validate(&self) -> Result<AppResponse> {
+        let validator = Validator::new(self.schema.clone());
+        let output = validator.validate(&self.policies, ValidationMode::default());
+        let errors = output
+            .validation_errors()
+            .map(|err| ValidationIssue {
+                policy_id: err.location().policy_id().to_string(),
+                message: err.to_string(),
+            })
+            .collect();
+        Ok(AppResponse::Validation(ValidationReport {
+            passed: output.validation_passed(),
+            errors,
+        }))
+    }
 
-//     let principal: UserUid = <UserUid as TryFrom<EntityUid>>::try_from("User::\"a598f25b-727e-4d88-9df3-facba457ccea\"".parse().unwrap()).unwrap();
+    /// Create (or replace) a SQL view selecting the lists `principal` is allowed to see,
+    /// so external reporting tools can query it directly. The view is rebuilt whenever the policies change.
+    /// It's named `<application>_<view_name>`, so tenants sharing a database can't replace each other's views.
+    pub fn create_authorized_view(&self, principal: &UserUid, view_name: &str) -> Result<AppResponse> {
+        let view_name = format!("{}_{view_name}", self.config.application.id().as_ref());
+        if !is_sql_identifier(&view_name) {
+            return Err(Error::InvalidInput(format!("`{view_name}` is not a valid view name")));
+        }
+        let select = self.authorized_view_select(principal)?;
+        self.entities.replace_authorized_view(&view_name, principal, &select)?;
+        info!("Created authorized view {} for {}", view_name, self.redact(principal));
+        Ok(AppResponse::Unit(()))
+    }
+
+    // The views bake in the translated policies, so they must be regenerated for a new policy set.
+    // A view that can't be regenerated is dropped rather than left granting stale access.
+    fn refresh_authorized_views(&self) -> Result<()> {
+        for (view_name, principal) in self.entities.get_authorized_views()? {
+            match self.authorized_view_select(&principal) {
+                Ok(select) => self.entities.replace_authorized_view(&view_name, &principal, &select)?,
+                Err(e) => {
+                    error!("Dropping authorized view {view_name}, it could not be regenerated: {e}");
+                    self.entities.drop_authorized_view(&view_name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn authorized_view_select(&self, principal: &UserUid) -> Result<String> {
+        Ok(self.get_all_authorized_lists(principal, &*ACTION_GET_LIST)?
+            .column((Alias::new("resource"), Asterisk))
+            .from_as(Alias::new(LISTS_VIEW), Alias::new("resource"))
+            .to_string(SqliteQueryBuilder))
+    }
+
+    fn add_share(&mut self, r: AddShare) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_EDIT_SHARE, &r.list)?;
+        self.share_with(&r.list, &r.share_with, r.role.unwrap_or(self.config.default_share_role))?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    // With `partial` set, each target is shared independently and the per-target results are returned.
+    // Otherwise the shares are applied all-or-nothing, and the first failure is returned.
+    fn add_shares(&mut self, r: AddShares) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_EDIT_SHARE, &r.list)?;
+        let role = r.role.unwrap_or(self.config.default_share_role);
+        if r.partial {
+            let results = r.share_with.iter()
+                .map(|target| self.share_with(&r.list, target, role))
+                .collect();
+            Ok(AppResponse::ItemResults(results))
+        } else {
+            self.entities.with_transaction(|_| {
+                r.share_with.iter().try_for_each(|target| self.share_with(&r.list, target, role))
+            })?;
+            Ok(AppResponse::ItemResults(r.share_with.iter().map(|_| Ok(())).collect()))
+        }
+    }
+
+    // Sharing with a user or team which doesn't exist fails with `NoSuchEntity`, rather than leaving a
+    // dangling membership. The check shares the insert's transaction, so the target can't be deleted in between.
+    fn share_with(&self, list: &ListUid, target: &UserOrTeamUid, role: ShareRole) -> Result<()> {
+        let list = self.entities.get_list_without_tasks(list)?;
+        let team_uid = list.get_team(role);
+        self.entities.with_transaction(|store| {
+            store.ensure_exists(target)?;
+            match TeamUid::try_from(EntityUid::from(target.clone())) {
+                // Sharing with a team nests it inside the list's reader/editor team
+                Ok(team) => store.add_subteam(&team, team_uid),
+                // Sharing with a user makes them a direct member. Anything else is refused rather than trusted to be a user.
+                Err(_) => {
+                    let user = UserUid::try_from(EntityUid::from(target.clone()))
+                        .map_err(|e| Error::InvalidInput(e.to_string()))?;
+                    store.add_team_member(&user, team_uid)
+                }
+            }
+        })
+    }
+
+    // Sharing puts `share_with` in the list's team for `role`, so `user` is affected if they are
+    // `share_with` or one of its members. Nothing is changed.
+    fn preview_share(&self, r: PreviewShare) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_EDIT_SHARE, &r.list)?;
+        self.entities.ensure_exists(&r.share_with)?;
+        let list = self.entities.get_list_without_tasks(&r.list)?;
+        let action = match r.role {
+            ShareRole::Reader => &*ACTION_GET_LIST,
+            ShareRole::Editor => &*ACTION_UPDATE_LIST,
+        };
+        let has_access = self.is_allowed_by(&self.policies, &r.user, action, &r.list);
+
+        let target = EntityUid::from(r.share_with);
+        let affected = *target.as_ref() == *r.user.as_ref()
+            || self.entities.get_user_teams(&r.user)?.into_iter().any(|team| EntityUid::from(team) == target);
+        let would_have_access = if affected {
+            let user = self.entities.get_user_with_teams(&r.user, &[list.get_team(r.role).clone()])?;
+            self.authorize_with_overrides(&r.user, action, &r.list, vec![user]) == Decision::Allow
+        } else {
+            has_access
+        };
+        Ok(AppResponse::SharePreview(SharePreview { has_access, would_have_access }))
+    }
+
+    // Undoes `share_with`. Unsharing with someone the list isn't shared with succeeds without changing anything.
+    fn delete_share(&mut self, r: DeleteShare) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_EDIT_SHARE, &r.list)?;
+        let list = self.entities.get_list_without_tasks(&r.list)?;
+        let team_uid = list.get_team(r.role);
+        match TeamUid::try_from(EntityUid::from(r.unshare_with.clone())) {
+            Ok(team) => self.entities.remove_subteam(&team, team_uid)?,
+            Err(_) => {
+                let user = UserUid::try_from(EntityUid::from(r.unshare_with))
+                    .map_err(|e| Error::InvalidInput(e.to_string()))?;
+                self.entities.remove_team_member(&user, team_uid)?;
+            }
+        }
+        Ok(AppResponse::Unit(()))
+    }
+
+    // Co-owners are also made editors, since `GetLists` only finds lists through team membership
+    fn add_owner(&mut self, r: AddOwner) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_ADD_OWNER, &r.list)?;
+        let list = self.entities.get_list_without_tasks(&r.list)?;
+        if *list.get_owner() != r.owner {
+            self.entities.with_transaction(|store| {
+                store.ensure_exists(&r.owner.clone().into())?;
+                store.add_owner(&r.list, &r.owner)?;
+                store.add_team_member(&r.owner, list.get_editors())
+            })?;
+        }
+        Ok(AppResponse::Unit(()))
+    }
+
+    // The removed co-owner keeps the editor access `add_owner` gave them
+    fn remove_owner(&mut self, r: RemoveOwner) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_REMOVE_OWNER, &r.list)?;
+        let list = self.entities.get_list_without_tasks(&r.list)?;
+        if *list.get_owner() == r.owner {
+            return Err(Error::InvalidInput(format!("{} is the primary owner of {}", r.owner.as_ref(), r.list.as_ref())));
+        }
+        self.entities.remove_owner(&r.list, &r.owner)?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    fn update_task(&mut self, r: UpdateTask) -> Result<AppResponse> {
+        if let Some(name) = &r.name {
+            self.validate_name(name)?;
+        }
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_TASK, &r.list)?;
+        self.entities.with_transaction(|store| {
+            if let Some(new_state) = r.state {
+                store.update_task(&r.list, r.task, new_state)?;
+            }
+            if let Some(name) = &r.name {
+                store.rename_task(&r.list, r.task, name)?;
+            }
+            match &r.visibility {
+                Some(TaskVisibility::Everyone) => store.set_task_visibility(&r.list, r.task, None)?,
+                Some(TaskVisibility::Team(team)) => {
+                    store.ensure_exists(&team.clone().into())?;
+                    store.set_task_visibility(&r.list, r.task, Some(team))?;
+                }
+                None => (),
+            }
+            Ok(())
+        })?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    fn create_task(&mut self, r: CreateTask) -> Result<AppResponse> {
+        self.validate_name(&r.name)?;
+        self.authorize_create_task(&r.uid, &r.list)?;
+        if r.validate_only {
+            return Ok(AppResponse::Validated);
+        }
+
+        self.ensure_new_task_name(&self.entities, &r.list, &r.name)?;
+        let task_id = self.entities.create_task(&r.list, r.name)?;
+        Ok(AppResponse::TaskId(task_id))
+    }
+
+    fn create_tasks(&mut self, r: CreateTasks) -> Result<AppResponse> {
+        self.authorize_create_task(&r.uid, &r.list)?;
+        let results = if r.partial {
+            r.names.into_iter()
+                .map(|name| {
+                    self.validate_name(&name)?;
+                    self.ensure_new_task_name(&self.entities, &r.list, &name)?;
+                    self.entities.create_task(&r.list, name)
+                })
+                .collect()
+        } else {
+            r.names.iter().try_for_each(|name| self.validate_name(name))?;
+            let ids = self.entities.with_transaction(|store| {
+                r.names.into_iter()
+                    .map(|name| {
+                        self.ensure_new_task_name(store, &r.list, &name)?;
+                        store.create_task(&r.list, name)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })?;
+            ids.into_iter().map(Ok).collect()
+        };
+        Ok(AppResponse::TaskIds(results))
+    }
+
+    // With `dedup_task_names`, fails with `Conflict` if `list` already has a task called `name`.
+    // Takes the store so that names added earlier in the same transaction, e.g. by an import, are seen.
+    fn ensure_new_task_name(&self, store: &EntityStore, list: &ListUid, name: &str) -> Result<()> {
+        if self.config.dedup_task_names && store.has_task_named(list, name, self.config.case_insensitive_names)? {
+            return Err(Error::Conflict(list.clone().into(), name.to_string()));
+        }
+        Ok(())
+    }
+
+    // With `unique_list_names`, fails with `ListNameTaken` if `owner` has a list other than `except` called `name`
+    fn ensure_new_list_name(&self, store: &EntityStore, owner: &UserUid, name: &str, except: Option<&ListUid>) -> Result<()> {
+        if self.config.unique_list_names && store.has_list_named(owner, name, self.config.case_insensitive_names, except)? {
+            return Err(Error::ListNameTaken(owner.clone().into(), name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn delete_task(&mut self, r: DeleteTask) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_DELETE_TASK, &r.list)?;
+        self.entities.delete_task(&r.list, r.task)?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    fn set_all_task_states(&mut self, r: SetAllTaskStates) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_TASK, &r.list)?;
+        let changed = self.entities.set_all_task_states(&r.list, r.state)?;
+        Ok(AppResponse::ChangedTasks(if r.return_tasks {
+            ChangedTasks::Tasks(changed)
+        } else {
+            ChangedTasks::Count(changed.len())
+        }))
+    }
+
+    fn add_task_comment(&mut self, r: AddTaskComment) -> Result<AppResponse> {
+        if r.body.trim().is_empty() {
+            return Err(Error::InvalidInput("Comment must not be empty".to_string()));
+        }
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_TASK, &r.list)?;
+        let visible_to = self.entities.get_task_visibility(&r.list, r.task)?;
+        self.ensure_task_visible(&r.uid, &r.list, r.task, visible_to)?;
+        let id = self.entities.add_task_comment(&r.list, r.task, &r.uid, &r.body)?;
+        Ok(AppResponse::CommentId(id))
+    }
+
+    fn get_task_comments(&self, r: GetTaskComments) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LIST, &r.list)?;
+        let visible_to = self.entities.get_task_visibility(&r.list, r.task)?;
+        self.ensure_task_visible(&r.uid, &r.list, r.task, visible_to)?;
+        Ok(AppResponse::TaskComments(self.entities.get_task_comments(&r.list, r.task)?))
+    }
+
+    fn get_lists(&self, r: GetLists) -> Result<AppResponse> {
+        let tenant = r.tenant.map(check_tenant).transpose()?.unwrap_or_else(|| self.config.application.clone());
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &tenant)?;
+
+        let mut select = self.authorized_lists_select(&r.uid)?;
+        select.and_where(Expr::col((Alias::new("resource"), Alias::new("tenant"))).eq(tenant.as_ref().id().as_ref()));
+        if let Some(name) = &r.name {
+            select.and_where(self.name_contains((Alias::new("resource"), Alias::new("name")), name));
+        }
+        if let Some(ListSort::Name) = r.sort {
+            select.clear_order_by()
+                .order_by((Alias::new("resource"), Alias::new("name")), Order::Asc)
+                .order_by((Alias::new("resource"), Alias::new("uid")), Order::Asc);
+        }
+        let select = select.to_string(SqliteQueryBuilder);
+
+        self.log_select(&select);
+        let result = self.time_sql(|entities| entities.get_lists(select))?;
+
+        let mut lists = match (r.offset, r.limit) {
+            (None, None) => result.into(),
+            (offset, limit) => Lists::page(result, offset.unwrap_or(0), limit),
+        };
+        if r.labels {
+            let labels = self.entities.get_labels(lists.lists())?;
+            lists = lists.with_labels(labels);
+        }
+        Ok(AppResponse::Lists(lists))
+    }
+
+    // Run one of the queries finding the lists a user may access, counting it in `RequestTiming::sql_micros`
+    fn time_sql<T>(&self, query: impl FnOnce(&EntityStore) -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = query(&self.entities);
+        self.timing.borrow_mut().sql_micros += start.elapsed().as_micros();
+        result
+    }
+
+    fn get_lists_page(&mut self, r: GetListsPage) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &self.config.application)?;
+
+        let result_set = match r.result_set {
+            Some(token) => match self.result_sets.get(&token) {
+                Some((owner, _)) if *owner == r.uid => token,
+                _ => return Err(Error::InvalidInput(format!("Unknown or expired result set {}", token.0))),
+            },
+            None => {
+                let select = self.authorized_lists_select(&r.uid)?.to_string(SqliteQueryBuilder);
+                self.log_select(&select);
+                let lists = self.time_sql(|entities| entities.get_lists(select))?;
+
+                let token = ResultSetToken(self.next_result_set);
+                self.next_result_set += 1;
+                if self.result_sets.len() >= MAX_RESULT_SETS {
+                    self.result_sets.pop_first();
+                }
+                self.result_sets.insert(token, (r.uid.clone(), lists));
+                token
+            }
+        };
+
+        let lists = &self.result_sets[&result_set].1;
+        Ok(AppResponse::ListsPage(ListsPage {
+            result_set,
+            total: lists.len(),
+            lists: lists.iter().skip(r.offset).take(r.limit).cloned().collect::<Vec<_>>().into(),
+        }))
+    }
+
+    fn get_my_owned_lists(&self, r: GetMyOwnedLists) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &self.config.application)?;
+
+        // Owners can normally read their lists, but a forbid policy may still hide some of them
+        let select = self.authorized_lists_select(&r.uid)?
+            .and_where(Expr::col((Alias::new("resource"), Alias::new("owner"))).eq(r.uid.as_ref().id().as_ref()))
+            .to_string(SqliteQueryBuilder);
+
+        self.log_select(&select);
+        Ok(AppResponse::Lists(self.time_sql(|entities| entities.get_lists(select))?.into()))
+    }
+
+    fn get_lists_detailed(&self, r: GetListsDetailed) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &self.config.application)?;
+
+        let resource_col = |c: &str| (Alias::new("resource"), Alias::new(c));
+        let select = self.authorized_lists_select(&r.uid)?
+            .columns(["name", "owner", "readers", "editors"].map(resource_col))
+            .to_string(SqliteQueryBuilder);
+
+        self.log_select(&select);
+        let result = self.time_sql(|entities| entities.get_list_summaries(select))?;
+
+        Ok(AppResponse::ListSummaries(result))
+    }
+
+    fn get_templates(&self, r: GetTemplates) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &self.config.application)?;
+
+        let lists = Alias::new(LISTS_VIEW);
+        let base = Query::select()
+            .column((lists.clone(), Alias::new("uid")))
+            .from(lists.clone())
+            .and_where(Expr::col((lists.clone(), Alias::new("is_template"))).eq(true))
+            .to_owned();
+        let filter = self.authorized_lists_filter(&r.uid, &*ACTION_GET_LIST, (lists, Alias::new("uid")))?;
+        let result = self.time_sql(|entities| entities.run_filtered(base, filter, |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(EntityUid::from(ListUid::from(uid.id())))
+        }))?;
+
+        Ok(AppResponse::Lists(result.into()))
+    }
+
+    fn get_recent_lists(&self, r: GetRecentLists) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &self.config.application)?;
+
+        let views = Alias::new("recent_views");
+        let base = Query::select()
+            .column((views.clone(), Alias::new("list_uid")))
+            .from(views.clone())
+            .and_where(Expr::col((views.clone(), Alias::new("user_uid"))).eq(r.uid.as_ref().id().as_ref()))
+            .order_by((views.clone(), Alias::new("viewed_at")), Order::Desc)
+            .order_by((views.clone(), Alias::new("rowid")), Order::Desc)
+            .limit(r.limit.unwrap_or(MAX_RECENT_VIEWS).min(MAX_RECENT_VIEWS) as u64)
+            .to_owned();
+        // Lists shared with the user once may have been unshared since
+        let filter = self.authorized_lists_filter(&r.uid, &*ACTION_GET_LIST, (views, Alias::new("list_uid")))?;
+        let result = self.time_sql(|entities| entities.run_filtered(base, filter, |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(EntityUid::from(ListUid::from(uid.id())))
+        }))?;
+
+        Ok(AppResponse::Lists(result.into()))
+    }
+
+    // The SQL pages through the tasks in the readable lists, in a stable order. A task restricted to a team
+    // is only selected for the team's members, since `ViewTask` needs that, and is then checked like
+    // any other restricted task. So unlike `GetList`, the feed leaves out restricted tasks of the user's
+    // own lists whose team they aren't in, and a page is short by any task a `forbid` hides.
+    fn get_my_tasks(&self, r: GetMyTasks) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LISTS, &self.config.application)?;
+
+        let column = |name: &str| (Alias::new("tasks"), Alias::new(name));
+        let teams = self.entities.get_user_teams(&r.uid)?;
+        let visible = Condition::any()
+            .add(Expr::col(column("visible_to")).is_null())
+            .add(Expr::col(column("visible_to")).is_in(teams.iter().map(|team| team.as_ref().id().as_ref().to_string())));
+        let filter = self.authorized_lists_filter(&r.uid, &*ACTION_GET_LIST, column("list_uid"))?.add(visible);
+        let (page, total) = self.time_sql(|entities| entities.get_tasks_in(filter, r.offset, r.limit))?;
+        let has_more = r.offset + page.len() < total;
+        let mut tasks = Vec::with_capacity(page.len());
+        for (list, task) in page {
+            if self.can_view_task(&r.uid, &list, &task)? {
+                tasks.push(ListTask { list, task });
+            }
+        }
+        let pagination = Pagination {
+            total,
+            offset: r.offset,
+            limit: Some(r.limit),
+            has_more,
+        };
+        Ok(AppResponse::TaskFeed(TaskFeed { tasks, pagination }))
+    }
+
+    // Remembered for `GetRecentLists`. Views made while impersonating aren't the user's own,
+    // and failing to record a view doesn't fail the `GetList` itself.
+    fn record_view(&self, user: &UserUid, list: &ListUid) {
+        if self.maintenance || self.impersonator.is_some() {
+            return;
+        }
+        if let Err(e) = self.entities.record_view(user, list) {
+            warn!("Failed to record a view of {}: {e}", EntityUid::from(list.clone()));
+        }
+    }
+
+    fn get_my_teams(&self, r: GetMyTeams) -> Result<AppResponse> {
+        let user = r.user.unwrap_or_else(|| r.uid.clone());
+        self.is_authorized(&r.uid, &*ACTION_GET_TEAMS, &user)?;
+        Ok(AppResponse::Teams(self.entities.get_user_teams(&user)?))
+    }
+
+    // Like `is_allowed_by`, the checks aren't audited: they only decide what the client offers
+    fn get_application_capabilities(&self, r: GetApplicationCapabilities) -> Result<AppResponse> {
+        let es = MemoizedEntities::new(&self.entities);
+        let checks = [
+            (&*ACTION_CREATE_LIST, self.create_list_context(&r.uid)?.into_context()),
+            (&*ACTION_GET_LISTS, Context::empty()),
+        ];
+        let allowed = checks.into_iter()
+            .filter_map(|(action, context)| {
+                let q = Request::new(
+                    Some(r.uid.as_ref().clone().into()),
+                    Some(action.clone().into()),
+                    Some(self.config.application.clone().into()),
+                    context,
+                );
+                let decision = self.authorizer.is_authorized_full_parsed(&q, &self.policies, &es).decision();
+                (decision == Decision::Allow).then(|| action.clone())
+            })
+            .collect();
+        Ok(AppResponse::Actions(allowed))
+    }
+
+    fn get_user_activity(&self, r: GetUserActivity) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_ACTIVITY, &r.target)?;
+        let (events, total) = self.entities.get_audit_events(r.target.as_ref(), r.offset, r.limit)?;
+        let pagination = Pagination {
+            total,
+            offset: r.offset,
+            limit: Some(r.limit.min(MAX_AUDIT_PAGE)),
+            has_more: r.offset + events.len() < total,
+        };
+        Ok(AppResponse::UserActivity(UserActivity { events, pagination }))
+    }
+
+    fn get_lists_sql(&self, r: GetListsSql) -> Result<AppResponse> {
+        let user = r.user.unwrap_or_else(|| r.uid.clone());
+        self.is_authorized(&r.uid, &*ACTION_DEBUG_QUERIES, &user)?;
+        Ok(AppResponse::Text(self.authorized_lists_select(&user)?.to_string(SqliteQueryBuilder)))
+    }
+
+    // Selects the uids of every list `principal` may read, as `resource.uid`
+    fn authorized_lists_select(&self, principal: &UserUid) -> Result<SelectStatement> {
+        self.list_uids_select(principal, &*ACTION_GET_LIST)
+    }
+
+    fn list_uids_select(&self, principal: impl AsRef<EntityUid>, action: impl AsRef<EntityUid>) -> Result<SelectStatement> {
+        let mut query_expr = self.get_all_authorized_lists(principal, action)?;
+        query_expr
+            .column((Alias::new("resource"), Alias::new("uid")))
+            .from_as(Alias::new(LISTS_VIEW), Alias::new("resource"));
+        Ok(query_expr)
+    }
+
+    /// A condition on `uid_column` holding the uids of the lists `principal` may perform `action` on,
+    /// to restrict a custom select with `EntityStore::run_filtered`
+    pub fn authorized_lists_filter(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        uid_column: impl IntoColumnRef,
+    ) -> Result<Condition> {
+        let select = self.list_uids_select(principal, action)?;
+        self.log_select(&select.to_string(SqliteQueryBuilder));
+        Ok(Condition::all().add(Expr::col(uid_column).in_subquery(select)))
+    }
+
+    /// The entities reachable from `principal`, for drawing a diagram of their effective access:
+    /// the teams they are in, following subteams up to `MAX_GRAPH_DEPTH` levels, and the lists they may read.
+    /// Returned as `{"nodes": [{"id", "type"}], "edges": [{"from", "to", "kind"}], "truncated"}`, where
+    /// `truncated` says whether teams beyond the depth limit were left out.
+    pub fn principal_graph(&self, principal: &UserUid) -> Result<serde_json::Value> {
+        let root = EntityUid::from(principal.clone());
+        let node = |uid: &EntityUid| serde_json::json!({ "id": uid.to_string(), "type": uid.type_name().to_string() });
+        let edge = |from: &EntityUid, to: &EntityUid, kind: &str| serde_json::json!({ "from": from.to_string(), "to": to.to_string(), "kind": kind });
+        let mut seen = HashSet::from([root.clone()]);
+        let mut nodes = vec![node(&root)];
+        let mut edges = vec![];
+        let mut frontier = vec![root.clone()];
+        for _ in 0..MAX_GRAPH_DEPTH {
+            let mut next = vec![];
+            for member in &frontier {
+                for team in self.entities.get_parent_teams(member)? {
+                    let team = EntityUid::from(team);
+                    edges.push(edge(member, &team, "memberOf"));
+                    if seen.insert(team.clone()) {
+                        nodes.push(node(&team));
+                        next.push(team);
+                    }
+                }
+            }
+            frontier = next;
+        }
+        // Teams reached again through another path were already walked, so they don't count as cut off
+        let mut truncated = false;
+        for member in &frontier {
+            if self.entities.get_parent_teams(member)?.into_iter().any(|team| !seen.contains(&EntityUid::from(team))) {
+                truncated = true;
+                break;
+            }
+        }
+
+        let select = self.authorized_lists_select(principal)?.to_string(SqliteQueryBuilder);
+        self.log_select(&select);
+        for list in self.entities.get_list_uids(select)? {
+            let list = EntityUid::from(list);
+            edges.push(edge(&root, &list, "GetList"));
+            nodes.push(node(&list));
+        }
+        Ok(serde_json::json!({ "nodes": nodes, "edges": edges, "truncated": truncated }))
+    }
+
+    /// The lists each of `principals` may perform `action` on. The residual policies depend on each
+    /// principal's own attributes and teams, so they are translated once per distinct principal, but
+    /// every query runs in a single transaction so the overview is consistent.
+    pub fn authorized_lists_for_many(&self, principals: &[EntityUid], action: &EntityUid) -> Result<HashMap<EntityUid, Vec<ListUid>>> {
+        self.entities.with_transaction(|store| {
+            let mut result = HashMap::with_capacity(principals.len());
+            for principal in principals {
+                if result.contains_key(principal) {
+                    continue;
+                }
+                let select = self.list_uids_select(principal, action)?.to_string(SqliteQueryBuilder);
+                self.log_select(&select);
+                result.insert(principal.clone(), store.get_list_uids(select)?);
+            }
+            Ok(result)
+        })
+    }
+
+    fn create_list(&mut self, r: CreateList) -> Result<AppResponse> {
+        self.validate_name(&r.name)?;
+        let tenant = r.tenant.map(check_tenant).transpose()?.unwrap_or_else(|| self.config.application.clone());
+        self.authorize_create_list(&r.uid, &tenant)?;
+        self.ensure_new_list_name(&self.entities, &r.uid, &r.name, None)?;
+        if r.validate_only {
+            return Ok(AppResponse::Validated);
+        }
+        let readers = self.entities.create_team()?;
+        let editors = self.entities.create_team()?;
+
+        let result = self.entities.create_list_in(&tenant, r.uid, &r.name, readers, editors)?;
+        Ok(AppResponse::euid(result))
+    }
+
+    fn set_list_template(&mut self, r: SetListTemplate) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_LIST, &r.list)?;
+        self.entities.set_template(&r.list, r.is_template)?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    fn set_list_metadata(&mut self, r: SetListMetadata) -> Result<AppResponse> {
+        if !r.metadata.is_object() {
+            return Err(Error::InvalidInput("List metadata must be a JSON object".to_string()));
+        }
+        let size = r.metadata.to_string().len();
+        if size > self.config.max_metadata_size {
+            return Err(Error::InvalidInput(format!(
+                "List metadata is {size} bytes, but the limit is {}",
+                self.config.max_metadata_size
+            )));
+        }
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_LIST, &r.list)?;
+        self.entities.set_metadata(&r.list, &r.metadata)?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    // Creates a fresh list owned by the caller with the same tasks, all unchecked.
+    // The source must be readable, and either owned by the caller or a template.
+    fn duplicate_list(&mut self, r: DuplicateList) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LIST, &r.list)?;
+        let source = self.entities.get_list(&r.list)?;
+        self.authorize_create_list(&r.uid, source.get_tenant())?;
+        if source.get_owner() != &r.uid && !source.is_template() {
+            return Err(Error::NotATemplate(r.list.into()));
+        }
+        let name = r.name.unwrap_or_else(|| source.get_name().to_string());
+        self.validate_name(&name)?;
+        let result = self.entities.with_transaction(|store| {
+            self.ensure_new_list_name(store, &r.uid, &name, None)?;
+            let readers = store.create_team()?;
+            let editors = store.create_team()?;
+            let list = store.create_list_in(source.get_tenant(), r.uid.clone(), &name, readers, editors)?;
+            for task in self.get_tasks_for(&source, &r.uid)? {
+                self.ensure_new_task_name(store, &list, task.get_name())?;
+                store.create_task(&list, task.get_name().to_string())?;
+            }
+            Ok(list)
+        })?;
+        Ok(AppResponse::euid(result))
+    }
+
+    fn get_list(&self, r: GetList) -> Result<AppResponse> {
+        if let Some(fields) = &r.fields {
+            let fields = fields.iter()
+                .map(|name| ListField::parse(name).ok_or_else(|| Error::InvalidInput(format!(
+                    "Unknown list field `{name}`, expected one of: {}",
+                    ListField::ALL.map(ListField::name).join(", ")
+                ))))
+                .collect::<Result<Vec<_>>>()?;
+            if fields.is_empty() {
+                return Err(Error::InvalidInput("`fields` must name at least one field".to_string()));
+            }
+            self.is_authorized(&r.uid, &*ACTION_GET_LIST, &r.list)?;
+            let projection = self.entities.get_list_fields(&r.list, &fields)?;
+            self.record_view(&r.uid, &r.list);
+            return Ok(AppResponse::ListFields(projection));
+        }
+        self.is_authorized(&r.uid, &*ACTION_GET_LIST, &r.list)?;
+        let list = if r.include_tasks {
+            let list = self.entities.get_list(&r.list)?;
+            let tasks = self.get_tasks_for(&list, &r.uid)?;
+            list.with_tasks(tasks)
+        } else {
+            self.entities.get_list_without_tasks(&r.list)?
+        }
+        .with_version();
+        self.record_view(&r.uid, &r.list);
+        if r.if_none_match.as_deref() == Some(list.get_version()) {
+            return Ok(AppResponse::NotModified);
+        }
+        Ok(AppResponse::GetList(Box::new(list)))
+    }
+
+    fn export_list(&self, r: ExportList) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_GET_LIST, &r.list)?;
+        let list = self.entities.get_list(&r.list)?;
+        let tasks = self.get_tasks_for(&list, &r.uid)?;
+        let list = list.with_tasks(tasks);
+        let text = match r.format {
+            ExportFormat::Markdown => list.to_markdown(),
+            ExportFormat::Json => list.to_export_json().to_string(),
+        };
+        Ok(AppResponse::Text(text))
+    }
+
+    fn import_list(&mut self, r: ImportList) -> Result<AppResponse> {
+        self.validate_name(&r.name)?;
+        self.authorize_create_list(&r.uid, &self.config.application)?;
+        let (tasks, skipped) = self.parse_checklist(&r.name, &r.markdown);
+        let list = self.entities.with_transaction(|store| {
+            self.ensure_new_list_name(store, &r.uid, &r.name, None)?;
+            let readers = store.create_team()?;
+            let editors = store.create_team()?;
+            let list = store.create_list(r.uid, &r.name, readers, editors)?;
+            for (name, state) in tasks {
+                self.ensure_new_task_name(store, &list, &name)?;
+                let id = store.create_task(&list, name)?;
+                if state != TaskState::Unchecked {
+                    store.update_task(&list, id, state)?;
+                }
+            }
+            Ok(list)
+        })?;
+        Ok(AppResponse::ImportedList(ImportedList { list, skipped }))
+    }
+
+    fn bulk_import(&mut self, r: BulkImport) -> Result<AppResponse> {
+        let mut lists = Vec::with_capacity(r.lists.len());
+        let mut skipped = Vec::with_capacity(r.lists.len());
+        for list in r.lists {
+            self.validate_name(&list.name)?;
+            self.ensure_new_list_name(&self.entities, &list.uid, &list.name, None)?;
+            if self.config.unique_list_names && lists.iter().any(|l: &BulkList| l.owner == list.uid && self.same_name(&l.name, &list.name)) {
+                return Err(Error::ListNameTaken(list.uid.into(), list.name));
+            }
+            let (tasks, list_skipped) = self.parse_checklist(&list.name, &list.markdown);
+            skipped.push(list_skipped);
+            lists.push(BulkList { owner: list.uid, name: list.name, tasks });
+        }
+        let count = lists.len();
+        let uids = self.entities.bulk_import(lists, |store, list, name| self.ensure_new_task_name(store, list, name))?;
+        info!("Bulk imported {count} lists");
+        let imported = uids.into_iter()
+            .zip(skipped)
+            .map(|(list, skipped)| ImportedList { list, skipped })
+            .collect();
+        Ok(AppResponse::ImportedLists(imported))
+    }
+
+    // The tasks of an imported checklist, leaving out the lines which don't parse or whose names aren't valid.
+    // Also returns how many lines were left out.
+    fn parse_checklist(&self, list_name: &str, markdown: &str) -> (Vec<(String, TaskState)>, usize) {
+        let (parsed, mut skipped) = parse_markdown_checklist(markdown);
+        let parsed_count = parsed.len();
+        let tasks = parsed.into_iter()
+            .filter(|(name, _)| self.validate_name(name).is_ok())
+            .collect::<Vec<_>>();
+        skipped += parsed_count - tasks.len();
+        if skipped > 0 {
+            warn!("Skipped {skipped} lines while importing list {list_name}");
+        }
+        (tasks, skipped)
+    }
+
+    fn update_list(&mut self, r: UpdateList) -> Result<AppResponse> {
+        self.validate_name(&r.name)?;
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_LIST, &r.list)?;
+        self.entities.with_transaction(|store| {
+            let list = store.get_list_without_tasks(&r.list)?;
+            self.ensure_new_list_name(store, list.get_owner(), &r.name, Some(&r.list))?;
+            store.update_list(&r.list, &r.name)?;
+            if let Some(priority_threshold) = r.priority_threshold {
+                store.set_priority_threshold(&r.list, priority_threshold)?;
+            }
+            Ok(())
+        })?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    fn create_folder(&mut self, r: CreateFolder) -> Result<AppResponse> {
+        self.validate_name(&r.name)?;
+        self.is_authorized(&r.uid, &*ACTION_CREATE_FOLDER, &self.config.application)?;
+        Ok(AppResponse::euid(self.entities.create_folder(&r.uid, &r.name)?))
+    }
+
+    fn move_list_to_folder(&mut self, r: MoveListToFolder) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_UPDATE_LIST, &r.list)?;
+        if let Some(folder) = &r.folder {
+            self.is_authorized(&r.uid, &*ACTION_ADD_TO_FOLDER, folder)?;
+        }
+        self.entities.move_list_to_folder(&r.list, r.folder.as_ref())?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    fn delete_list(&mut self, r: DeleteList) -> Result<AppResponse> {
+        self.is_authorized(&r.uid, &*ACTION_DELETE_LIST, &r.list)?;
+        self.entities.delete_list(&r.list)?;
+        // Without a retention period nothing is kept, not even lists deleted back when there was one
+        if self.config.deleted_list_retention.is_none() {
+            self.entities.purge_deleted(Duration::ZERO)?;
+        }
+        Ok(AppResponse::Unit(()))
+    }
+
+    // Anyone who may update the list may lock or unlock it. Locking is enforced by the store, not by policy.
+    fn set_list_locked(&mut self, principal: &UserUid, list: &ListUid, locked: bool) -> Result<AppResponse> {
+        self.is_authorized(principal, &*ACTION_UPDATE_LIST, list)?;
+        self.entities.set_locked(list, locked)?;
+        Ok(AppResponse::Unit(()))
+    }
+
+    // Whether `column` contains `needle`. With `case_insensitive_names`, both sides are lowercased,
+    // which like `COLLATE NOCASE` only folds ASCII letters.
+    fn name_contains(&self, column: impl IntoColumnRef, needle: &str) -> SimpleExpr {
+        let (haystack, needle): (SimpleExpr, SimpleExpr) = if self.config.case_insensitive_names {
+            (Func::lower(Expr::col(column)).into(), Func::lower(Expr::val(needle)).into())
+        } else {
+            (Expr::col(column).into(), Expr::val(needle).into())
+        };
+        Expr::expr(Func::cust(Alias::new("instr")).args([haystack, needle])).gt(0)
+    }
+
+    // Whether two names are the same as far as `dedup_task_names` and `unique_list_names` are concerned
+    fn same_name(&self, a: &str, b: &str) -> bool {
+        if self.config.case_insensitive_names {
+            a.trim().eq_ignore_ascii_case(b.trim())
+        } else {
+            a.trim() == b.trim()
+        }
+    }
+
+    // List and task names must be non-empty, printable, and at most `max_name_length` bytes
+    fn validate_name(&self, name: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            Err(Error::InvalidInput("Name must not be empty".to_string()))
+        } else if name.len() > self.config.max_name_length {
+            Err(Error::InvalidInput(format!(
+                "Name is {} bytes long, but the limit is {}",
+                name.len(),
+                self.config.max_name_length
+            )))
+        } else if name.chars().any(char::is_control) {
+            Err(Error::InvalidInput("Name must not contain control characters".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Like `prepare_authorized_lists`, but reuses the result for the same principal and action
+    /// until the policies or the database change.
+    /// The lists are ordered by uid, so results are the same from one call to the next;
+    /// use `SelectStatement::clear_order_by` to sort them differently.
+    pub fn get_all_authorized_lists(&self, principal: impl AsRef<EntityUid>, action: impl AsRef<EntityUid>) -> Result<SelectStatement> {
+        let key = (principal.as_ref().clone(), action.as_ref().clone());
+        let data_version = self.entities.data_version()?;
+        if let Some(filter) = self.prepared_filters.borrow().get(&key) {
+            if filter.policy_version == self.policy_version && filter.data_version == data_version {
+                return Ok(filter.select.clone());
+            }
+        }
+        let mut select = self.prepare_authorized_lists(&key.0, &key.1)?
+            .order_by((Alias::new("resource"), Alias::new("uid")), Order::Asc)
+            .to_owned();
+        // Other tenants' lists may well be allowed by the policies, but aren't this store's to show
+        if let Some(tenant) = self.entities.tenant_scope() {
+            select.and_where(Expr::col((Alias::new("resource"), Alias::new("tenant"))).eq(tenant.as_ref().id().as_ref()));
+        }
+        let mut filters = self.prepared_filters.borrow_mut();
+        if filters.len() >= MAX_PREPARED_FILTERS {
+            filters.clear();
+        }
+        filters.insert(key, PreparedFilter { select: select.clone(), policy_version: self.policy_version, data_version });
+        Ok(select)
+    }
+
+    fn prepare_authorized_lists(&self, principal: &EntityUid, action: &EntityUid) -> Result<SelectStatement> {
+        let q = Request::builder()
+            .principal(Some(principal.clone().into()))
+            .action(Some(action.clone().into()))
+            .resource_type("List".parse().unwrap())
+            .build();
+        let start = Instant::now();
+        let es = CachedEntities::cache_request(&self.entities, &q);
+        let response = self.authorizer.is_authorized_parsed(&q, &self.policies, &es);
+        self.timing.borrow_mut().authorization_micros += start.elapsed().as_micros();
+        match response {
+            cedar_policy::PartialResponse::Concrete(response) => {
+                Ok(Query::select().and_where((response.decision() == Decision::Allow).into()).to_owned())
+            },
+            cedar_policy::PartialResponse::Residual(res) => {
+                // The closure can only fail with the translator's own error, so an `in` test between other
+                // types is remembered here and refused once translation is done, rather than panicking
+                let untranslatable = Cell::new(None);
+                let query = translate_response(&res, &self.schema,
+                    &InByTable(|t1, t2| {
+                    if *t1 == *TYPE_USER && *t2 == *TYPE_TEAM {
+                        // Shared teams are nested in a list's readers or editors, so direct memberships aren't enough
+                        Ok((Alias::new("team_membership_closure"), Alias::new("user_uid"), Alias::new("team_uid")))
+                    } else if *t1 == *TYPE_LIST && *t2 == *TYPE_FOLDER {
+                        Ok((Alias::new("lists"), Alias::new("uid"), Alias::new("folder_uid")))
+                    } else {
+                        untranslatable.set(Some((t1.clone(), t2.clone())));
+                        // Never run, the query is thrown away below
+                        Ok((Alias::new("team_membership_closure"), Alias::new("user_uid"), Alias::new("team_uid")))
+                    }
+                }));
+                if let Some((t1, t2)) = untranslatable.take() {
+                    return Err(Error::UntranslatableMembership(t1, t2));
+                }
+                let query = query.map_err(|e| Error::ResidualTranslation(format!("{e:?}")))?;
+                let complexity = query_complexity(&query);
+                if complexity > self.config.max_query_complexity {
+                    warn!("Refusing to run authorization query with complexity {complexity}");
+                    return Err(Error::QueryTooComplex(complexity, self.config.max_query_complexity));
+                }
+                Ok(query)
+            },
+        }
+    }
+
+    // The principal is known, so the residual only refers to the resource (and its attributes).
+    // The residual reveals the policies that apply to the principal, so it needs `GetLists`,
+    // which is also what's audited, since no decision about a particular resource is made here.
+    fn get_residual(&self, r: GetResidual) -> Result<AppResponse> {
+        let resource_type: EntityTypeName = r.resource_type.parse()
+            .map_err(|_| Error::InvalidInput(format!("`{}` is not an entity type", r.resource_type)))?;
+        self.validate_shape(&TYPE_USER, &r.action, &resource_type)?;
+        self.is_authorized(&r.principal, &*ACTION_GET_LISTS, &self.config.application)?;
+        let q = Request::builder()
+            .principal(Some(r.principal.as_ref().clone().into()))
+            .action(Some(r.action.into()))
+            .resource_type(resource_type)
+            .build();
+        let es = CachedEntities::cache_request(&self.entities, &q);
+        match self.authorizer.is_authorized_parsed(&q, &self.policies, &es) {
+            cedar_policy::PartialResponse::Concrete(response) => Ok(AppResponse::Residual(Residual::Decision(response.decision()))),
+            cedar_policy::PartialResponse::Residual(res) => {
+                Ok(AppResponse::Residual(Residual::Policies(res.residuals().clone().into())))
+            }
+        }
+    }
+
+    // The context carries the user's current number of lists, so policies can cap it,
+    // e.g. with `when { context.current_list_count < 100 }`
+    fn authorize_create_list(&self, user: &UserUid, tenant: &EntityUid) -> Result<()> {
+        let context = self.create_list_context(user)?;
+        self.is_authorized_with(user, tenant, context)
+    }
+
+    fn create_list_context(&self, user: &UserUid) -> Result<CreateListContext> {
+        Ok(CreateListContext { current_list_count: self.entities.count_owned_lists(user)? })
+    }
+
+    /// The tasks of `list` which `principal` may see. A task restricted to a team is only
+    /// included if `ViewTask` is allowed, with the team as `context.visible_to`.
+    pub fn get_tasks_for(&self, list: &List, principal: &UserUid) -> Result<Vec<Task>> {
+        let mut visible = Vec::with_capacity(list.get_tasks().len());
+        for task in list.get_tasks() {
+            if self.can_view_task(principal, list.uid(), task)? {
+                visible.push(task.clone());
+            }
+        }
+        Ok(visible)
+    }
+
+    // Whether to include `task` of `list` when listing tasks for `principal`
+    fn can_view_task(&self, principal: &UserUid, list: &ListUid, task: &Task) -> Result<bool> {
+        match task.get_visible_to().map(|team| self.filter_view_task(principal, list, team)) {
+            Some(Err(Error::AuthDenied(_))) => Ok(false),
+            Some(Err(e)) => Err(e),
+            _ => Ok(true),
+        }
+    }
+
+    // A task `principal` can't see is reported as missing, the same as `GetList` leaving it out
+    fn ensure_task_visible(&self, principal: &UserUid, list: &ListUid, task: i64, visible_to: Option<TeamUid>) -> Result<()> {
+        match visible_to.map(|team| self.authorize_view_task(principal, list, &team)) {
+            Some(Err(Error::AuthDenied(_))) => Err(Error::InvalidTaskId(list.clone().into(), task)),
+            Some(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn authorize_view_task(&self, principal: &UserUid, list: &ListUid, team: &TeamUid) -> Result<()> {
+        self.is_authorized_with(principal, list, ViewTaskContext { visible_to: team.clone() })
+    }
+
+    // Like `authorize_view_task`, for leaving a task out of a list. Only audited with `config.audit_task_filtering`.
+    fn filter_view_task(&self, principal: &UserUid, list: &ListUid, team: &TeamUid) -> Result<()> {
+        let context = ViewTaskContext { visible_to: team.clone() }.into_context();
+        self.authorize(principal, &*ACTION_VIEW_TASK, list, Some(context), self.config.audit_task_filtering).map(|_| ())
+    }
+
+    // Like `authorize_create_list`, with the number of tasks already in `list` as `context.current_task_count`
+    fn authorize_create_task(&self, user: &UserUid, list: &ListUid) -> Result<()> {
+        let context = CreateTaskContext { current_task_count: self.entities.count_tasks(list)? };
+        self.is_authorized_with(user, list, context)
+    }
+
+    pub fn is_authorized(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+    ) -> Result<()> {
+        self.authorize(principal, action, resource, None, true).map(|_| ())
+    }
+
+    /// Like `is_authorized_in`, for the action `context` belongs to
+    pub fn is_authorized_with<C: ActionContext>(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+        context: C,
+    ) -> Result<()> {
+        self.is_authorized_in(principal, C::action(), resource, context.into_context())
+    }
+
+    pub fn is_authorized_in(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+        context: Context,
+    ) -> Result<()> {
+        self.authorize_in(principal, action, resource, context).map(|_| ())
+    }
+
+    /// Like `is_authorized_in`, but if the request is allowed, return the obligations
+    /// attached to the policies that allowed it
+    #[tracing::instrument(skip_all)]
+    pub fn authorize_in(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+        context: Context,
+    ) -> Result<Vec<Obligation>> {
+        self.authorize(principal, action, resource, Some(context), true)
+    }
+
+    // Inside a transaction, the event is held back until it ends, since a rollback would lose it
+    fn audit(&self, event: AuditEvent) {
+        if self.entities.in_transaction() {
+            self.pending_audit.borrow_mut().push(event);
+        } else {
+            self.record_audit(&event);
+        }
+    }
+
+    // A broken audit sink shouldn't take the application down with it
+    fn record_audit(&self, event: &AuditEvent) {
+        if let Err(e) = self.config.audit_sink.record(&self.entities, event) {
+            error!("Couldn't record audit event for {}: {e}", self.redact(&event.principal));
+        }
+    }
+
+    // Only requests without a context are cached, since their decision depends on the entities alone
+    fn authorize(
+        &self,
+        principal: impl AsRef<EntityUid>,
+        action: impl AsRef<EntityUid>,
+        resource: impl AsRef<EntityUid>,
+        context: Option<Context>,
+        audited: bool,
+    ) -> Result<Vec<Obligation>> {
+        info!(
+            "is_authorized request: principal: {}, action: {}, resource: {}",
+            self.redact(&principal),
+            action.as_ref(),
+            self.redact(&resource)
+        );
+        for hook in &self.config.authorization_hooks {
+            hook.before_authorize(principal.as_ref(), action.as_ref(), resource.as_ref())?;
+        }
+        let key = (context.is_none() && self.decisions.borrow().is_enabled())
+            .then(|| (principal.as_ref().clone(), action.as_ref().clone(), resource.as_ref().clone()));
+        let cached = match &key {
+            Some(key) => self.decisions.borrow_mut().get(key, self.entities.data_version()?),
+            None => None,
+        };
+        let response = match cached {
+            Some(response) => response,
+            None => {
+                let q = Request::new(
+                    Some(principal.as_ref().clone().into()),
+                    Some(action.as_ref().clone().into()),
+                    Some(resource.as_ref().clone().into()),
+                    context.unwrap_or_else(Context::empty),
+                );
+                let start = Instant::now();
+                let es = CachedEntities::cache_request(&self.entities, &q);
+                let response = self.authorizer.is_authorized_full_parsed(&q, &self.policies, &es);
+                self.timing.borrow_mut().authorization_micros += start.elapsed().as_micros();
+                if let Some(key) = key {
+                    self.decisions.borrow_mut().insert(key, &response);
+                }
+                response
+            }
+        };
+        info!("Auth response: {:?}", response);
+        let fail_open = response.decision() == Decision::Deny && self.config.fail_open_actions.contains(action.as_ref());
+        for hook in &self.config.authorization_hooks {
+            hook.after_authorize(principal.as_ref(), action.as_ref(), resource.as_ref(), response.decision(), fail_open);
+        }
+        let event = AuditEvent::new(&principal, &action, &resource, response.decision())
+            .impersonated_by(self.impersonator.as_ref());
+        match response.decision() {
+            Decision::Allow => {
+                if audited {
+                    self.audit(event);
+                }
+                let mut obligations = response.diagnostics().reason()
+                    .filter_map(|id| {
+                        let obligation = self.policies.annotation(id, OBLIGATION_ANNOTATION)?;
+                        Some(Obligation { policy_id: id.to_string(), obligation: obligation.to_string() })
+                    })
+                    .collect::<Vec<_>>();
+                obligations.sort_by(|a, b| a.policy_id.cmp(&b.policy_id));
+                if !obligations.is_empty() {
+                    info!("Request allowed with obligations: {:?}", obligations);
+                }
+                Ok(obligations)
+            }
+            Decision::Deny if fail_open => {
+                warn!(
+                    "FAIL OPEN: allowing denied request: principal: {}, action: {}, resource: {}",
+                    self.redact(&principal),
+                    action.as_ref(),
+                    self.redact(&resource)
+                );
+                if audited {
+                    self.audit(event.fail_open());
+                }
+                Ok(vec![])
+            }
+            Decision::Deny => {
+                if audited {
+                    self.audit(event);
+                }
+                // Only checked once denied, so allowed requests don't pay for the lookup
+                if self.config.report_missing_resources && !self.entities.exists(resource.as_ref())? {
+                    return Err(Error::NoSuchEntity(resource.as_ref().clone()));
+                }
+                Err(Error::AuthDenied(response.diagnostics().clone()))
+            }
+        }
+    }
+}
+
+// Lists belong to an application, which acts as the tenant in a database shared by several
+fn check_tenant(tenant: EntityUid) -> Result<EntityUid> {
+    if *tenant.as_ref().type_name() == *TYPE_APP {
+        Ok(tenant)
+    } else {
+        Err(Error::InvalidInput(format!("{tenant} is not an Application")))
+    }
+}
+
+/// The entity types an action applies to, from its `appliesTo` in the schema
+struct ActionShape {
+    principal_types: Vec<EntityTypeName>,
+    resource_types: Vec<EntityTypeName>,
+}
+
+// Requests for an action the schema doesn't declare are always denied, so catch any mismatch at startup.
+// Returns the actions declared in the schema, along with the types they apply to.
+fn check_actions_declared(schema_src: &str) -> std::result::Result<HashMap<EntityUid, ActionShape>, ContextError> {
+    let schema: serde_json::Value = serde_json::from_str(schema_src)?;
+    let mut declared = HashMap::new();
+    for (namespace, fragment) in schema.as_object().into_iter().flatten() {
+        let prefix = if namespace.is_empty() { String::new() } else { format!("{namespace}::") };
+        // Types in `appliesTo` are relative to the namespace
+        let types = |applies_to: &serde_json::Value| {
+            applies_to.as_array().into_iter().flatten()
+                .filter_map(|t| format!("{prefix}{}", t.as_str()?).parse().ok())
+                .collect::<Vec<EntityTypeName>>()
+        };
+        for (action, decl) in fragment["actions"].as_object().into_iter().flatten() {
+            if let Ok(euid) = format!(r#"{prefix}Action::"{action}""#).parse::<EntityUid>() {
+                let shape = ActionShape {
+                    principal_types: types(&decl["appliesTo"]["principalTypes"]),
+                    resource_types: types(&decl["appliesTo"]["resourceTypes"]),
+                };
+                declared.insert(euid, shape);
+            }
+        }
+    }
+    let missing = all_actions()
+        .into_iter()
+        .filter(|action| !declared.contains_key(*action))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(ContextError::MissingActions(missing.join(", ")));
+    }
+    check_typed_contexts(&schema)?;
+    Ok(declared)
+}
+
+// Queue a `CheckpointWal` every `interval`, until the server stops
+fn spawn_wal_checkpoints(interval: Duration, tx: Sender<AppQuery>) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick is immediate, and there's nothing to checkpoint yet
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if tx.send(AppQuery::without_response(AppQueryKind::CheckpointWal)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// The warnings in `output` as reported by `GetValidationWarnings`
+fn validation_warnings(output: &ValidationResult<'_>) -> Vec<ValidationIssue> {
+    output
+        .validation_warnings()
+        .map(|warning| ValidationIssue {
+            policy_id: warning.location().policy_id().to_string(),
+            message: warning.to_string(),
+        })
+        .collect()
+}
+
+// Queue a `PurgeDeletedLists` every `DELETED_LIST_PURGE_INTERVAL`, starting straight away
+// with the lists whose retention ran out while the server was down
+fn spawn_deleted_list_purges(tx: Sender<AppQuery>) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(DELETED_LIST_PURGE_INTERVAL);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if tx.send(AppQuery::without_response(AppQueryKind::PurgeDeletedLists)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Every action the schema gives a context, in any namespace, must have an `ActionContext` declaring the same attributes
+fn check_typed_contexts(schema: &serde_json::Value) -> std::result::Result<(), ContextError> {
+    let typed = [
+        (CreateListContext::action(), CreateListContext::ATTRIBUTES),
+        (CreateTaskContext::action(), CreateTaskContext::ATTRIBUTES),
+        (ViewTaskContext::action(), ViewTaskContext::ATTRIBUTES),
+    ];
+    let mut mismatched = vec![];
+    for (namespace, fragment) in schema.as_object().into_iter().flatten() {
+        let prefix = if namespace.is_empty() { String::new() } else { format!("{namespace}::") };
+        for (action, decl) in fragment["actions"].as_object().into_iter().flatten() {
+            let declared = decl["appliesTo"]["context"]["attributes"].as_object().into_iter().flatten()
+                .filter_map(|(name, ty)| Some((name.as_str(), ty["name"].as_str().or(ty["type"].as_str())?)))
+                .collect::<BTreeSet<_>>();
+            let euid = format!(r#"{prefix}Action::"{action}""#).parse::<EntityUid>().ok();
+            let expected = typed.iter()
+                .find(|(uid, _)| Some(*uid) == euid.as_ref())
+                .map(|(_, attributes)| attributes.iter().copied().collect())
+                .unwrap_or_default();
+            if declared != expected {
+                mismatched.push(format!("{prefix}{action}"));
+            }
+        }
+    }
+    if mismatched.is_empty() {
+        Ok(())
+    } else {
+        Err(ContextError::ContextMismatch(mismatched.join(", ")))
+    }
+}
+
+// A rough count of the conditions, joins and subqueries in `query`. It's counted with the values bound
+// as parameters, so that a value like a list name can't add to the count.
+fn query_complexity(query: &SelectStatement) -> usize {
+    let (sql, _) = query.build(SqliteQueryBuilder);
+    [" AND ", " OR ", " JOIN ", "SELECT "]
+        .iter()
+        .map(|keyword| sql.matches(keyword).count())
+        .sum()
+}
+
+fn is_sql_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// #[test]
+// fn test_is_authorized_partial() {
+//     let schema_path = "./tinytodo.cedarschema.json";
+//     let entities_path = "./huge_entities.db";
+//     let policies_path = "./policies.cedar";
+
+//     let schema_path: PathBuf = schema_path.into();
+//     let policies_path: PathBuf = policies_path.into();
+//     let schema_file = std::fs::File::open(&schema_path).unwrap();
+//     let schema = Schema::from_file(schema_file).unwrap();
+
+//     let entities = EntityStore::from_file(<PathBuf as From<&str>>::from(entities_path));
+
+//     let policy_src = std::fs::read_to_string(&policies_path).unwrap();
+//     let policies = policy_src.parse().unwrap();
+//     let validator = Validator::new(schema.clone());
+//     let output = validator.validate(&policies, ValidationMode::default());
+//     assert!(output.validation_passed());
+//     let authorizer = Authorizer::new();
+
+//     let principal: UserUid = <UserUid as TryFrom<EntityUid>>::try_from("User::\"a598f25b-727e-4d88-9df3-facba457ccea\"".parse().unwrap()).unwrap();
 //     let action = &*ACTION_GET_LIST;
 
 //     let query_expr: ConditionExpression = {
@@ -497,3 +3178,617 @@ impl AppContext {
     //     .cond_where(Condition::any().add(query_expr))
     //     .to_string(SqliteQueryBuilder))
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{audit::AuditSink, hooks::AuthorizationHook};
+    use cedar_policy::EntityId;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_schema_declares_actions() {
+        let schema = std::fs::read_to_string("./tinytodo.cedarschema.json").unwrap();
+        let shapes = check_actions_declared(&schema).unwrap();
+        let create_task = &shapes[&*ACTION_CREATE_TASK];
+        assert_eq!(create_task.principal_types, std::slice::from_ref(&*TYPE_USER));
+        assert_eq!(create_task.resource_types, std::slice::from_ref(&*TYPE_LIST));
+
+        let empty = r#"{"": {"entityTypes": {}, "actions": {}}}"#;
+        assert!(matches!(check_actions_declared(empty), Err(ContextError::MissingActions(_))));
+    }
+
+    #[test]
+    fn test_action_contexts_match_schema() {
+        let mut schema: serde_json::Value = serde_json::from_str(&std::fs::read_to_string("./tinytodo.cedarschema.json").unwrap()).unwrap();
+        check_typed_contexts(&schema).unwrap();
+        schema[""]["actions"]["CreateList"]["appliesTo"]["context"]["attributes"]["current_list_count"]["type"] = "String".into();
+        assert!(matches!(check_typed_contexts(&schema), Err(ContextError::ContextMismatch(actions)) if actions == "CreateList"));
+
+        // Actions in another namespace aren't the ones `ActionContext` is implemented for
+        let mut schema: serde_json::Value = serde_json::from_str(&std::fs::read_to_string("./tinytodo.cedarschema.json").unwrap()).unwrap();
+        schema["Other"] = schema[""].clone();
+        assert!(matches!(check_typed_contexts(&schema), Err(ContextError::ContextMismatch(actions)) if actions.starts_with("Other::")));
+    }
+
+    fn user(name: &str) -> UserUid {
+        name.parse::<EntityId>().unwrap().into()
+    }
+
+    // A context over a fresh in-memory database with the server's schema and policies.
+    // `admin` is in `Team::"admin"`, while `alice` and `bob` are ordinary users.
+    fn test_context(config: AppConfig) -> AppContext {
+        let entities = EntityStore::new(rusqlite::Connection::open_in_memory().unwrap())
+            .with_application(config.application.clone());
+        entities.initialize_schema().unwrap();
+        entities.bootstrap_defaults(Some((&user("admin"), "Admin"))).unwrap();
+        for name in ["alice", "bob"] {
+            entities.insert_user(&user(name), name).unwrap();
+        }
+        let schema_src = std::fs::read_to_string("./tinytodo.cedarschema.json").unwrap();
+        let action_shapes = check_actions_declared(&schema_src).unwrap();
+        let policies = std::fs::read_to_string("./policies.cedar").unwrap().parse().unwrap();
+        let (_, recv) = tokio::sync::mpsc::channel(1);
+        AppContext::new(entities, Authorizer::new(), policies, schema_src.parse().unwrap(), action_shapes, config, recv)
+    }
+
+    fn get_list(uid: &UserUid, list: &ListUid) -> AppQueryKind {
+        let r: GetList = serde_json::from_value(serde_json::json!({ "uid": uid, "list": list })).unwrap();
+        r.into()
+    }
+
+    #[test]
+    fn test_batch_refuses_operator_commands() {
+        let mut c = test_context(AppConfig::default());
+        let batch = Batch { queries: vec![AppQueryKind::UpdatePolicySet(PolicySet::new().into())], atomic: false };
+        assert!(matches!(c.handle(AppQueryKind::Batch(batch)), Err(Error::InvalidInput(_))));
+        assert_eq!(c.policy_version, 0);
+
+        let nested = Batch { queries: vec![AppQueryKind::Batch(Batch { queries: vec![], atomic: false })], atomic: true };
+        assert!(matches!(c.handle(AppQueryKind::Batch(nested)), Err(Error::InvalidInput(_))));
+
+        let get_lists = GetLists { uid: user("alice"), tenant: None, name: None, offset: None, limit: None, labels: false, sort: None };
+        let batch = Batch { queries: vec![get_lists.into()], atomic: false };
+        assert!(c.handle(AppQueryKind::Batch(batch)).is_ok());
+    }
+
+    #[test]
+    fn test_lists_for_principals_not_batched() {
+        let mut c = test_context(AppConfig::default());
+        let r = GetListsForPrincipals { principals: vec![user("admin").into()], action: None };
+        let batch = Batch { queries: vec![r.into()], atomic: false };
+        assert!(matches!(c.handle(AppQueryKind::Batch(batch)), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_maintenance_not_batched() {
+        let mut c = test_context(AppConfig::default());
+        let batch = Batch { queries: vec![AppQueryKind::SetMaintenance(true)], atomic: false };
+        assert!(matches!(c.handle(AppQueryKind::Batch(batch)), Err(Error::InvalidInput(_))));
+        assert!(!c.maintenance);
+    }
+
+    #[test]
+    fn test_preview_policy_not_batched() {
+        let mut c = test_context(AppConfig::default());
+        let r = PreviewPolicy { principal: user("alice"), policies: PolicySet::new().into() };
+        let batch = Batch { queries: vec![r.into()], atomic: false };
+        assert!(matches!(c.handle(AppQueryKind::Batch(batch)), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_authorized_views_are_operator_only() {
+        let mut c = test_context(AppConfig::default());
+        let r = CreateAuthorizedView { principal: user("alice"), view_name: "alice_lists".to_string() };
+        let batch = Batch { queries: vec![r.into()], atomic: false };
+        assert!(matches!(c.handle(AppQueryKind::Batch(batch)), Err(Error::InvalidInput(_))));
+
+        c.create_authorized_view(&user("alice"), "alice_lists").unwrap();
+        let views = c.entities.get_authorized_views().unwrap();
+        assert_eq!(views, vec![("TinyTodo_alice_lists".to_string(), user("alice"))]);
+    }
+
+    #[test]
+    fn test_validate_only_creates_nothing() {
+        let mut c = test_context(AppConfig::default());
+        let create = CreateList { uid: user("alice"), name: "Groceries".to_string(), tenant: None, validate_only: true };
+        assert!(matches!(c.handle(create.into()), Ok(AppResponse::Validated)));
+        assert_eq!(c.entities.count_owned_lists(&user("alice")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_residual_is_audited() {
+        let mut c = test_context(AppConfig::default());
+        let r = GetResidual { principal: user("alice"), action: ACTION_GET_LIST.clone(), resource_type: "List".to_string() };
+        c.handle(r.into()).unwrap();
+        let (events, total) = c.entities.get_audit_events(user("alice").as_ref(), 0, 10).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(events[0].action, *ACTION_GET_LISTS);
+        assert_eq!(events[0].resource, c.config.application);
+    }
+
+    #[test]
+    fn test_atomic_batch_rolls_back_and_audits() {
+        let mut c = test_context(AppConfig::default());
+        let create = CreateList { uid: user("alice"), name: "Groceries".to_string(), tenant: None, validate_only: false };
+        let missing: GetList = serde_json::from_value(serde_json::json!({
+            "uid": "User::\"alice\"", "list": "List::\"missing\""
+        })).unwrap();
+        let batch = Batch { queries: vec![create.into(), missing.into()], atomic: true };
+        assert!(c.handle(AppQueryKind::Batch(batch)).is_err());
+        assert_eq!(c.entities.count_owned_lists(&user("alice")).unwrap(), 0);
+        // The denial was rolled back along with the batch, but is still audited
+        let (events, _) = c.entities.get_audit_events(user("alice").as_ref(), 0, 10).unwrap();
+        assert_eq!(events[0].decision, Decision::Deny);
+    }
+
+    #[derive(Debug)]
+    struct BrokenAuditSink;
+
+    impl AuditSink for BrokenAuditSink {
+        fn record(&self, _: &EntityStore, _: &AuditEvent) -> Result<()> {
+            Err(Error::Audit("disk full".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_audit_failures_are_logged() {
+        let mut c = test_context(AppConfig { audit_sink: Box::new(BrokenAuditSink), ..AppConfig::default() });
+        let r = GetMyTeams { uid: user("alice"), user: None };
+        assert!(!matches!(c.handle(r.into()), Err(Error::Audit(_))));
+    }
+
+    #[test]
+    fn test_task_filtering_not_audited() {
+        let c = test_context(AppConfig::default());
+        let team = c.entities.create_team().unwrap();
+        let list = c.entities.create_list(user("bob"), "Chores", team.clone(), team.clone()).unwrap();
+        let hidden = c.entities.create_task(&list, "Hidden".to_string()).unwrap();
+        c.entities.set_task_visibility(&list, hidden, Some(&team)).unwrap();
+        c.get_tasks_for(&c.entities.get_list(&list).unwrap(), &user("alice")).unwrap();
+        assert_eq!(c.entities.get_audit_events(user("alice").as_ref(), 0, 10).unwrap().1, 0);
+
+        let c = AppContext { config: AppConfig { audit_task_filtering: true, ..AppConfig::default() }, ..c };
+        c.get_tasks_for(&c.entities.get_list(&list).unwrap(), &user("alice")).unwrap();
+        assert_eq!(c.entities.get_audit_events(user("alice").as_ref(), 0, 10).unwrap().1, 1);
+    }
+
+    #[test]
+    fn test_imports_dedup_task_names() {
+        let config = AppConfig { dedup_task_names: true, case_insensitive_names: true, ..AppConfig::default() };
+        let mut c = test_context(config);
+        let import = |name: &str| ImportList { uid: user("alice"), name: name.to_string(), markdown: "- [ ] Milk\n- [x] milk \n".to_string() };
+        let r = c.handle(import("Groceries").into());
+        assert!(matches!(r, Err(Error::Conflict(_, name)) if name == "milk"));
+        let r = c.handle(BulkImport { lists: vec![import("Fine"), import("Groceries")] }.into());
+        assert!(matches!(r, Err(Error::Conflict(_, _))));
+        assert_eq!(c.entities.count_owned_lists(&user("alice")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unique_list_names() {
+        let config = AppConfig { unique_list_names: true, case_insensitive_names: true, ..AppConfig::default() };
+        let mut c = test_context(config);
+        let create = |name: &str| CreateList { uid: user("alice"), name: name.to_string(), tenant: None, validate_only: false };
+        let groceries: ListUid = match c.handle(create("Groceries").into()) {
+            Ok(AppResponse::Euid(euid)) => euid.try_into().unwrap(),
+            r => panic!("unexpected response: {r:?}"),
+        };
+        assert!(matches!(c.handle(create(" groceries").into()), Err(Error::ListNameTaken(_, _))));
+        let bob = CreateList { uid: user("bob"), ..create("Groceries") };
+        assert!(c.handle(bob.into()).is_ok());
+
+        c.handle(create("Chores").into()).unwrap();
+        let rename = |name: &str| UpdateList { uid: user("alice"), list: groceries.clone(), name: name.to_string(), priority_threshold: None };
+        assert!(matches!(c.handle(rename("CHORES").into()), Err(Error::ListNameTaken(_, _))));
+        assert!(c.handle(rename("GROCERIES").into()).is_ok());
+    }
+
+    #[test]
+    fn test_my_tasks_pages_and_hides() {
+        let mut c = test_context(AppConfig::default());
+        let readers = c.entities.create_team().unwrap();
+        let editors = c.entities.create_team().unwrap();
+        let hidden_from_alice = c.entities.create_team().unwrap();
+        c.entities.add_team_member(&user("alice"), &readers).unwrap();
+        let list = c.entities.create_list(user("bob"), "Chores", readers.clone(), editors).unwrap();
+        for name in ["Sweep", "Dust", "Secret", "Readers only", "Mop"] {
+            c.entities.create_task(&list, name.to_string()).unwrap();
+        }
+        c.entities.set_task_visibility(&list, 3, Some(&hidden_from_alice)).unwrap();
+        c.entities.set_task_visibility(&list, 4, Some(&readers)).unwrap();
+
+        let mut page = |offset| match c.handle(GetMyTasks { uid: user("alice"), offset, limit: 2 }.into()) {
+            Ok(AppResponse::TaskFeed(feed)) => feed,
+            r => panic!("unexpected response: {r:?}"),
+        };
+        let first = page(0);
+        let names = first.tasks.iter().map(|t| t.task.get_name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Sweep", "Dust"]);
+        assert_eq!(first.pagination.total, 4);
+        assert!(first.pagination.has_more);
+        let second = page(2);
+        let names = second.tasks.iter().map(|t| t.task.get_name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Readers only", "Mop"]);
+        assert!(!second.pagination.has_more);
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let sqlite_error = |code| Error::SQLError(rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(code), None));
+        assert!(sqlite_error(rusqlite::ffi::SQLITE_BUSY).is_retryable());
+        assert!(sqlite_error(rusqlite::ffi::SQLITE_LOCKED).is_retryable());
+        assert!(!sqlite_error(rusqlite::ffi::SQLITE_CONSTRAINT).is_retryable());
+        assert!(!Error::Cancelled.is_retryable());
+    }
+
+    #[test]
+    fn test_add_share_grants_get_list() {
+        let mut c = test_context(AppConfig::default());
+        let list = c.entities.create_list(user("bob"), "Chores", c.entities.create_team().unwrap(), c.entities.create_team().unwrap()).unwrap();
+        assert!(matches!(c.handle(get_list(&user("alice"), &list)), Err(Error::AuthDenied(_))));
+
+        let share = |target: UserUid| AddShare { uid: user("bob"), list: list.clone(), share_with: target.into(), role: Some(ShareRole::Reader) };
+        c.handle(share(user("alice")).into()).unwrap();
+        assert!(c.handle(get_list(&user("alice"), &list)).is_ok());
+        assert!(matches!(c.handle(share(user("nobody")).into()), Err(Error::NoSuchEntity(_))));
+    }
+
+    #[test]
+    fn test_share_with_team_lists_for_members() {
+        let mut c = test_context(AppConfig::default());
+        let list = c.entities.create_list(user("bob"), "Chores", c.entities.create_team().unwrap(), c.entities.create_team().unwrap()).unwrap();
+        let team = c.entities.create_team().unwrap();
+        c.entities.add_team_member(&user("alice"), &team).unwrap();
+        let share = AddShare { uid: user("bob"), list: list.clone(), share_with: team.into(), role: Some(ShareRole::Reader) };
+        c.handle(share.into()).unwrap();
+
+        // alice is only in the list's readers through the shared team
+        let get_lists = GetLists { uid: user("alice"), tenant: None, name: None, offset: None, limit: None, labels: false, sort: None };
+        match c.handle(get_lists.into()) {
+            Ok(AppResponse::Lists(lists)) => assert_eq!(lists.lists(), &[EntityUid::from(list)]),
+            r => panic!("unexpected response: {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_share_with_missing_user() {
+        let mut c = test_context(AppConfig::default());
+        let list = c.entities.create_list(user("bob"), "Chores", c.entities.create_team().unwrap(), c.entities.create_team().unwrap()).unwrap();
+        let share = AddShare { uid: user("bob"), list, share_with: user("nobody").into(), role: None };
+        assert!(matches!(c.handle(share.into()), Err(Error::NoSuchEntity(_))));
+        assert!(c.entities.get_user_teams(&user("nobody")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_delete_share_revokes_get_list() {
+        let mut c = test_context(AppConfig::default());
+        let list = c.entities.create_list(user("bob"), "Chores", c.entities.create_team().unwrap(), c.entities.create_team().unwrap()).unwrap();
+        let team = c.entities.create_team().unwrap();
+        c.entities.add_team_member(&user("alice"), &team).unwrap();
+
+        for target in [UserOrTeamUid::from(user("alice")), team.into()] {
+            let share = AddShare { uid: user("bob"), list: list.clone(), share_with: target.clone(), role: Some(ShareRole::Reader) };
+            c.handle(share.into()).unwrap();
+            assert!(c.handle(get_list(&user("alice"), &list)).is_ok());
+            let unshare = DeleteShare { uid: user("bob"), list: list.clone(), unshare_with: target, role: ShareRole::Reader };
+            c.handle(unshare.into()).unwrap();
+            assert!(matches!(c.handle(get_list(&user("alice"), &list)), Err(Error::AuthDenied(_))));
+        }
+    }
+
+    #[derive(Debug)]
+    struct RateLimited;
+
+    impl AuthorizationHook for RateLimited {
+        fn before_authorize(&self, _: &EntityUid, _: &EntityUid, _: &EntityUid) -> Result<()> {
+            Err(Error::Busy)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Observer(Arc<Mutex<Vec<(Decision, bool)>>>);
+
+    impl AuthorizationHook for Observer {
+        fn after_authorize(&self, _: &EntityUid, _: &EntityUid, _: &EntityUid, decision: Decision, fail_open: bool) {
+            self.0.lock().unwrap().push((decision, fail_open));
+        }
+    }
+
+    #[test]
+    fn test_before_authorize_short_circuits() {
+        let c = test_context(AppConfig { authorization_hooks: vec![Box::new(RateLimited)], ..AppConfig::default() });
+        let r = c.is_authorized(&user("alice"), &*ACTION_GET_LISTS, &c.config.application);
+        assert!(matches!(r, Err(Error::Busy)));
+        assert_eq!(c.entities.get_audit_events(user("alice").as_ref(), 0, 10).unwrap().1, 0);
+    }
+
+    #[test]
+    fn test_after_authorize_sees_fail_open() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let config = AppConfig {
+            authorization_hooks: vec![Box::new(Observer(seen.clone()))],
+            fail_open_actions: [ACTION_GET_LIST.clone()].into(),
+            ..AppConfig::default()
+        };
+        let c = test_context(config);
+        let list = c.entities.create_list(user("bob"), "Chores", c.entities.create_team().unwrap(), c.entities.create_team().unwrap()).unwrap();
+        c.is_authorized(&user("alice"), &*ACTION_GET_LIST, &list).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![(Decision::Deny, true)]);
+    }
+
+    #[test]
+    fn test_principal_graph_truncation() {
+        let c = test_context(AppConfig::default());
+        let shortcut = c.entities.create_team().unwrap();
+        c.entities.add_team_member(&user("alice"), &shortcut).unwrap();
+        let mut chain = vec![c.entities.create_team().unwrap()];
+        c.entities.add_team_member(&user("alice"), &chain[0]).unwrap();
+        while chain.len() < MAX_GRAPH_DEPTH {
+            let parent = c.entities.create_team().unwrap();
+            c.entities.add_subteam(chain.last().unwrap(), &parent).unwrap();
+            chain.push(parent);
+        }
+        // The deepest team's only parent was already reached directly
+        c.entities.add_subteam(chain.last().unwrap(), &shortcut).unwrap();
+        let graph = c.principal_graph(&user("alice")).unwrap();
+        assert_eq!(graph["truncated"], false);
+        assert_eq!(graph["nodes"].as_array().unwrap().len(), MAX_GRAPH_DEPTH + 2);
+
+        let beyond = c.entities.create_team().unwrap();
+        c.entities.add_subteam(chain.last().unwrap(), &beyond).unwrap();
+        assert_eq!(c.principal_graph(&user("alice")).unwrap()["truncated"], true);
+    }
+
+    #[test]
+    fn test_get_lists_defaults_to_application() {
+        let mut c = test_context(AppConfig::default());
+        let other: EntityUid = r#"Application::"Other""#.parse().unwrap();
+        let team = || c.entities.create_team().unwrap();
+        let mine = c.entities.create_list(user("alice"), "Groceries", team(), team()).unwrap();
+        c.entities.create_list_in(&other, user("alice"), "Elsewhere", team(), team()).unwrap();
+
+        let get_lists = GetLists { uid: user("alice"), tenant: None, name: None, offset: None, limit: None, labels: false, sort: None };
+        match c.handle(get_lists.into()) {
+            Ok(AppResponse::Lists(lists)) => assert_eq!(lists.lists(), &[EntityUid::from(mine)]),
+            r => panic!("unexpected response: {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_query_complexity_ignores_values() {
+        let query = Query::select()
+            .column(Alias::new("uid"))
+            .from(Alias::new("lists"))
+            .and_where(Expr::col(Alias::new("name")).eq("SELECT a JOIN b AND c OR d"))
+            .to_owned();
+        assert_eq!(query_complexity(&query), 1);
+        let query = query.clone().and_where(Expr::col(Alias::new("owner")).eq("alice")).to_owned();
+        assert_eq!(query_complexity(&query), 2);
+    }
+
+    #[test]
+    fn test_explain_request() {
+        let c = test_context(AppConfig::default());
+        let readers = c.entities.create_team().unwrap();
+        let list = c.entities.create_list(user("bob"), "Chores", readers.clone(), c.entities.create_team().unwrap()).unwrap();
+        c.entities.add_team_member(&user("alice"), &readers).unwrap();
+        let loaded = |explanation: &Explanation, uid: EntityUid| explanation.entities.iter().find(|entity| entity.uid == uid).cloned();
+
+        // The membership that let alice in is in the graph the authorizer saw
+        let explanation = c.explain_request(user("alice"), &*ACTION_GET_LIST, list.clone());
+        assert_eq!(explanation.decision, Decision::Allow);
+        assert!(!explanation.reasons.is_empty());
+        let alice = loaded(&explanation, user("alice").into()).unwrap();
+        assert!(alice.ancestors.contains(&readers.clone().into()));
+        assert!(loaded(&explanation, readers.clone().into()).is_some());
+        assert!(loaded(&explanation, list.clone().into()).is_some());
+
+        let explanation = c.explain_request(user("admin"), &*ACTION_GET_LIST, list.clone());
+        assert_eq!(explanation.decision, Decision::Deny);
+        assert!(!loaded(&explanation, user("admin").into()).unwrap().ancestors.contains(&readers.into()));
+
+        // A resource that doesn't exist is left out
+        let missing: ListUid = "missing".parse::<EntityId>().unwrap().into();
+        let explanation = c.explain_request(user("alice"), &*ACTION_GET_LIST, missing.clone());
+        assert_eq!(explanation.decision, Decision::Deny);
+        assert!(loaded(&explanation, missing.into()).is_none());
+    }
+
+    #[test]
+    fn test_prepared_filters() {
+        let mut c = test_context(AppConfig::default());
+        let key = (EntityUid::from(user("alice")), ACTION_GET_LIST.clone());
+        let first = c.get_all_authorized_lists(&key.0, &key.1).unwrap();
+        assert_eq!(c.prepared_filters.borrow().len(), 1);
+        let second = c.get_all_authorized_lists(&key.0, &key.1).unwrap();
+        assert_eq!(first.to_string(SqliteQueryBuilder), second.to_string(SqliteQueryBuilder));
+
+        // A new policy set makes the filter stale, and it is prepared again under the new version
+        c.update_policy_set(c.policies.clone()).unwrap();
+        assert_eq!(c.prepared_filters.borrow()[&key].policy_version, 0);
+        c.get_all_authorized_lists(&key.0, &key.1).unwrap();
+        assert_eq!(c.prepared_filters.borrow()[&key].policy_version, 1);
+
+        // Any mutation may change memberships, so it drops every filter, whether or not it succeeds
+        let create = CreateList { uid: user("alice"), name: "Groceries".to_string(), tenant: None, validate_only: false };
+        let _ = c.handle(create.into());
+        assert!(c.prepared_filters.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_decision_cache() {
+        let mut c = test_context(AppConfig { decision_cache_ttl: Some(Duration::from_secs(60)), ..AppConfig::default() });
+        let team = c.entities.create_team().unwrap();
+        let list = c.entities.create_list(user("alice"), "Groceries", team.clone(), team).unwrap();
+        for name in ["alice", "bob"] {
+            let _ = c.is_authorized(user(name), &*ACTION_GET_LIST, list.clone());
+            let _ = c.is_authorized(user(name), &*ACTION_GET_LIST, list.clone());
+        }
+        let stats = c.decisions.borrow().stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 2, 2));
+
+        // Requests with a context aren't cached
+        let context = CreateTaskContext { current_task_count: 0 };
+        let _ = c.is_authorized_with(user("alice"), list.clone(), context);
+        assert_eq!(c.decisions.borrow().stats().misses, 2);
+
+        // Any mutation drops the cached decisions, since it may change the entities they were made from
+        let create = CreateList { uid: user("bob"), name: "Chores".to_string(), tenant: None, validate_only: false };
+        let _ = c.handle(create.into());
+        assert_eq!(c.decisions.borrow().stats().entries, 0);
+    }
+
+    #[test]
+    fn test_deny_cache() {
+        let response = |decision| Response::new(decision, HashSet::new(), HashSet::new());
+        let key = |name: &str| (EntityUid::from(user(name)), ACTION_GET_LIST.clone(), APPLICATION_TINY_TODO.clone());
+        // Only denials are cached
+        let mut cache = DecisionCache::new(None, Some(Duration::from_secs(60)));
+        cache.insert(key("alice"), &response(Decision::Allow));
+        cache.insert(key("bob"), &response(Decision::Deny));
+        assert!(cache.get(&key("alice"), 0).is_none());
+        assert_eq!(cache.get(&key("bob"), 0).map(|r| r.decision()), Some(Decision::Deny));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.deny_hits, stats.misses, stats.entries), (1, 1, 1, 1));
+
+        // A write from another connection may have shared the list, so the denial is dropped
+        assert!(cache.get(&key("bob"), 1).is_none());
+        assert_eq!(cache.stats().entries, 0);
+
+        // Denials are kept for their own TTL, which may be shorter than that of allows
+        let mut cache = DecisionCache::new(Some(Duration::from_secs(60)), Some(Duration::ZERO));
+        cache.insert(key("alice"), &response(Decision::Allow));
+        cache.insert(key("bob"), &response(Decision::Deny));
+        assert!(cache.get(&key("alice"), 0).is_some());
+        assert!(cache.get(&key("bob"), 0).is_none());
+    }
+
+    #[test]
+    fn test_get_lists_order() {
+        let mut c = test_context(AppConfig::default());
+        let team = || c.entities.create_team().unwrap();
+        let mut lists = ["Groceries", "Chores", "Books"]
+            .map(|name| c.entities.create_list(user("alice"), name, team(), team()).unwrap())
+            .to_vec();
+        let mut get_lists = |sort| {
+            let r = GetLists { uid: user("alice"), tenant: None, name: None, offset: None, limit: None, labels: false, sort };
+            match c.handle(r.into()) {
+                Ok(AppResponse::Lists(lists)) => lists.lists().to_vec(),
+                r => panic!("unexpected response: {r:?}"),
+            }
+        };
+
+        let by_name: Vec<EntityUid> = lists.iter().rev().cloned().map(EntityUid::from).collect();
+        assert_eq!(get_lists(Some(ListSort::Name)), by_name);
+        lists.sort_by_key(|list| list.as_ref().id().to_string());
+        let by_uid: Vec<EntityUid> = lists.into_iter().map(EntityUid::from).collect();
+        assert_eq!(get_lists(None), by_uid);
+        assert_eq!(get_lists(None), by_uid);
+        assert_eq!(get_lists(Some(ListSort::Uid)), by_uid);
+    }
+
+    #[tokio::test]
+    async fn test_timed_queries() {
+        let mut c = test_context(AppConfig::default());
+        let (chan, recv) = tokio::sync::mpsc::channel(1);
+        c.recv = recv;
+        tokio::spawn(c.serve());
+        let get_lists = || GetLists { uid: user("alice"), tenant: None, name: None, offset: None, limit: None, labels: false, sort: None };
+
+        let (_, timing) = crate::api::send_timed_query(&chan, get_lists().into(), None, true).await;
+        let timing = timing.unwrap();
+        assert!(timing.total_micros >= timing.authorization_micros + timing.sql_micros);
+
+        let (r, timing) = crate::api::send_timed_query(&chan, get_lists().into(), None, false).await;
+        assert!(timing.is_none());
+        let (r2, _) = crate::api::send_timed_query(&chan, get_lists().into(), None, true).await;
+        assert_eq!(format!("{r:?}"), format!("{r2:?}"));
+    }
+
+    #[test]
+    fn test_restore_policies() {
+        let mut c = test_context(AppConfig::default());
+        let original = c.snapshot_policies().to_string();
+        c.handle(AppQueryKind::UpdatePolicySet(PolicySet::new().into())).unwrap();
+        match c.handle(AppQueryKind::GetPolicyHistory) {
+            Ok(AppResponse::PolicyHistory(history)) => assert_eq!(history.iter().map(|s| s.version).collect::<Vec<_>>(), [0]),
+            r => panic!("unexpected response: {r:?}"),
+        }
+
+        // Restoring puts back the policies in use before, and can itself be undone
+        c.handle(RestorePolicies { version: None }.into()).unwrap();
+        assert_eq!(c.snapshot_policies().to_string(), original);
+        assert_eq!(c.policy_version, 2);
+        assert_eq!(c.policy_history.iter().map(|(v, _)| *v).collect::<Vec<_>>(), [1, 0]);
+        c.handle(RestorePolicies { version: Some(1) }.into()).unwrap();
+        assert_eq!(c.snapshot_policies().to_string(), PolicySet::new().to_string());
+
+        assert!(matches!(c.handle(RestorePolicies { version: Some(99) }.into()), Err(Error::InvalidInput(_))));
+        for _ in 0..MAX_POLICY_HISTORY {
+            c.update_policy_set(PolicySet::new()).unwrap();
+        }
+        assert_eq!(c.policy_history.len(), MAX_POLICY_HISTORY);
+    }
+
+    #[test]
+    fn test_deleted_list_retention() {
+        for retention in [None, Some(Duration::from_secs(60 * 60))] {
+            let mut c = test_context(AppConfig { deleted_list_retention: retention, ..AppConfig::default() });
+            let team = c.entities.create_team().unwrap();
+            let list = c.entities.create_list(user("alice"), "Groceries", team.clone(), team).unwrap();
+            c.handle(DeleteList { uid: user("alice"), list: list.clone() }.into()).unwrap();
+            assert!(c.handle(get_list(&user("alice"), &list)).is_err());
+            c.handle(AppQueryKind::PurgeDeletedLists).unwrap();
+            // Within the retention period, the rows are still there for an operator to recover
+            let kept = c.entities.stats().unwrap().lists;
+            assert_eq!(kept, if retention.is_some() { 1 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_add_shares_memberships() {
+        let mut c = test_context(AppConfig::default());
+        let readers = c.entities.create_team().unwrap();
+        let list = c.entities.create_list(user("bob"), "Chores", readers.clone(), c.entities.create_team().unwrap()).unwrap();
+        let team = c.entities.create_team().unwrap();
+        c.entities.add_team_member(&user("admin"), &team).unwrap();
+        let shares = |list: &ListUid, targets: Vec<UserOrTeamUid>, partial| {
+            AddShares { uid: user("bob"), list: list.clone(), share_with: targets, role: Some(ShareRole::Reader), partial }
+        };
+
+        // Each target that exists ends up in the readers team, the user directly and the team as a subteam
+        let r = c.handle(shares(&list, vec![user("alice").into(), team.clone().into(), user("nobody").into()], true).into());
+        match r {
+            Ok(AppResponse::ItemResults(results)) => {
+                assert!(results[0].is_ok() && results[1].is_ok());
+                assert!(matches!(results[2], Err(Error::NoSuchEntity(_))));
+            }
+            r => panic!("unexpected response: {r:?}"),
+        }
+        assert!(c.entities.get_user_teams(&user("alice")).unwrap().contains(&readers));
+        assert!(c.entities.get_user_teams(&user("admin")).unwrap().contains(&readers));
+
+        // Without `partial`, one missing target undoes the whole share
+        let readers = c.entities.create_team().unwrap();
+        let list = c.entities.create_list(user("bob"), "Groceries", readers.clone(), c.entities.create_team().unwrap()).unwrap();
+        let r = c.handle(shares(&list, vec![user("alice").into(), user("nobody").into()], false).into());
+        assert!(matches!(r, Err(Error::NoSuchEntity(_))));
+        assert!(!c.entities.get_user_teams(&user("alice")).unwrap().contains(&readers));
+    }
+
+    #[test]
+    fn test_validation_warnings() {
+        let mut c = test_context(AppConfig::default());
+        let impossible: PolicySet = r#"permit(principal, action == Action::"GetList", resource) when { false };"#.parse().unwrap();
+        let validator = Validator::new(c.schema.clone());
+        c.validation_warnings = validation_warnings(&validator.validate(&impossible, ValidationMode::default()));
+        match c.handle(AppQueryKind::GetValidationWarnings) {
+            Ok(AppResponse::ValidationWarnings(warnings)) => {
+                assert_eq!(warnings.len(), 1);
+                assert_eq!(warnings[0].policy_id, "policy0");
+            }
+            r => panic!("unexpected response: {r:?}"),
+        }
+    }
+}
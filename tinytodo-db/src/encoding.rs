@@ -0,0 +1,86 @@
+/*
+ * Copyright 2022-2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Debug;
+
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("Couldn't encode the response as JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Couldn't encode the response as MessagePack: {0}")]
+    MsgPack(#[from] rmp_serde::encode::Error),
+}
+
+/// The wire format of response bodies, so that a transport can pick one without the handlers knowing.
+/// Bodies reach the encoder as a `serde_json::Value`, which keeps the trait object safe: the encoder
+/// can then be chosen per request, e.g. from the `Accept` header.
+pub trait ResponseEncoder: Debug + Send + Sync {
+    /// The `Content-Type` of the encoded bodies
+    fn content_type(&self) -> &'static str;
+
+    fn encode_value(&self, body: &serde_json::Value) -> Result<Vec<u8>, EncodeError>;
+}
+
+impl dyn ResponseEncoder + '_ {
+    pub fn encode(&self, body: impl Serialize) -> Result<Vec<u8>, EncodeError> {
+        self.encode_value(&serde_json::to_value(body)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonEncoder;
+
+impl ResponseEncoder for JsonEncoder {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn encode_value(&self, body: &serde_json::Value) -> Result<Vec<u8>, EncodeError> {
+        Ok(serde_json::to_vec(body)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackEncoder;
+
+impl ResponseEncoder for MsgPackEncoder {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn encode_value(&self, body: &serde_json::Value) -> Result<Vec<u8>, EncodeError> {
+        Ok(rmp_serde::to_vec(body)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encoders_agree() {
+        let body = serde_json::json!({ "lists": ["List::\"0\""], "total": 1, "has_more": false });
+        let json: &dyn ResponseEncoder = &JsonEncoder;
+        let msgpack: &dyn ResponseEncoder = &MsgPackEncoder;
+        let from_json: serde_json::Value = serde_json::from_slice(&json.encode(&body).unwrap()).unwrap();
+        let from_msgpack: serde_json::Value = rmp_serde::from_slice(&msgpack.encode(&body).unwrap()).unwrap();
+        assert_eq!(from_json, body);
+        assert_eq!(from_msgpack, body);
+    }
+}
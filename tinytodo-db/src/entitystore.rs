@@ -14,58 +14,201 @@
  * limitations under the License.
  */
 
-use std::{collections::{HashMap, HashSet}, borrow::Cow, path::Path};
+use std::{collections::{HashMap, HashSet}, borrow::Cow, cell::RefCell, ops::Deref, path::{Path, PathBuf}, str::FromStr, time::Duration};
+use std::sync::{atomic::{AtomicI64, Ordering}, Arc};
 use lazy_static::lazy_static;
 use cedar_db_example::sqlite::{EntitySQLInfo, AncestorSQLInfo, EntitySQLId};
-use rusqlite::{Connection, params, OptionalExtension};
+use rusqlite::{Connection, DatabaseName, OpenFlags, Row, params, params_from_iter, OptionalExtension, ffi};
+use rusqlite::types::{FromSql, Type, ValueRef};
+use sea_query::{Alias, Asterisk, Condition, Expr, Func, Order, Query, SelectStatement, SqliteQueryBuilder};
 use thiserror::Error;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{trace, warn};
 use uuid::Uuid;
 
-use cedar_policy::{EvaluationError, EntityDatabase, ParsedEntity, EntityId};
+use cedar_policy::{Decision, EvaluationError, EntityDatabase, ParsedEntity, EntityId, EntityTypeName};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    context::{Error, APPLICATION_TINY_TODO},
-    objects::{List, Application, Task, TaskState},
-    util::{EntityUid, ListUid, TeamUid, UserUid, TYPE_USER, TYPE_TEAM, TYPE_LIST, TYPE_APP},
+    audit::{decision_str, now, AuditEvent},
+    config::{AttributeDefault, SqliteTuning},
+    context::{CancellationToken, Error, APPLICATION_TINY_TODO},
+    objects::{List, ListField, ListSummary, Application, Folder, Task, TaskComment, TaskState},
+    stats::StoreStats,
+    util::{EntityUid, FolderUid, ListUid, TeamUid, UserOrTeamUid, UserUid, TYPE_USER, TYPE_TEAM, TYPE_LIST, TYPE_APP, TYPE_FOLDER},
 };
 
 pub struct EntityStore {
-    conn: Connection
+    conn: StoreConnection,
+    // The root application, the parent of every user and team
+    application: EntityUid,
+    // Set for stores made by `SharedConnection::store_for`: only lists of `application` are visible
+    tenant_scoped: bool,
+    // Lists loaded up front by `with_prefetched_lists`, consulted before hitting the database
+    prefetched_lists: RefCell<HashMap<ListUid, List>>,
+    // Set by `with_membership_cache`
+    memberships: Option<RefCell<MembershipCache>>,
+    // Applied by the `<table>_with_defaults` views
+    attribute_defaults: Vec<AttributeDefault>,
+    // Set by `with_eager_task_loading`
+    eager_task_loading: bool,
 }
 
+/// One SQLite connection shared by the stores of several tenants, so that hosting many small tenants
+/// doesn't take a connection (and a file handle) each. A store holds the connection from `EntityStore::check_out`
+/// to `EntityStore::check_in`, i.e. for a whole request, so tenants' transactions never interleave.
+/// Using the store at any other time panics. The application checks out around each query it serves,
+/// so background tasks should queue an `AppQuery`, as `spawn_wal_checkpoints` does, rather than use the store directly.
+#[derive(Debug, Clone)]
+pub struct SharedConnection {
+    conn: Arc<Mutex<Connection>>,
+    // Writes through this connection don't change `PRAGMA data_version` for the other stores using it,
+    // so each store's writes are counted here instead
+    writes: Arc<AtomicI64>,
+}
+
+impl SharedConnection {
+    /// The connection shared by every store on `file`, opened and tuned with `tuning` on first use.
+    /// Its views apply the `attribute_defaults` given then too, since every tenant reads through the same views.
+    pub fn for_file(file: &Path, tuning: SqliteTuning, attribute_defaults: &[AttributeDefault]) -> Result<Self, Error> {
+        let mut shared = SHARED_CONNECTIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(conn) = shared.get(file) {
+            return Ok(conn.clone());
+        }
+        let store = EntityStore::from_file(file).with_tuning(tuning)?.with_attribute_defaults(attribute_defaults.to_vec());
+        let conn = Self::new(store)?;
+        shared.insert(file.to_path_buf(), conn.clone());
+        Ok(conn)
+    }
+
+    /// Initialize the schema and default teams of `store`'s database, then share its connection
+    pub fn new(store: EntityStore) -> Result<Self, Error> {
+        store.initialize_schema()?;
+        store.bootstrap_defaults(None)?;
+        let StoreConnection::Owned(conn) = store.conn else {
+            return Err(Error::InvalidInput("The connection is already shared".into()));
+        };
+        Ok(Self { conn: Arc::new(Mutex::new(conn)), writes: Arc::new(AtomicI64::new(0)) })
+    }
+
+    /// A store seeing only the lists of `tenant`, which is also its root application.
+    /// Users and teams are shared by every tenant.
+    pub fn store_for(&self, tenant: EntityUid) -> EntityStore {
+        EntityStore {
+            tenant_scoped: true,
+            ..EntityStore::with_connection(StoreConnection::Shared(self.clone(), None)).with_application(tenant)
+        }
+    }
+}
+
+enum StoreConnection {
+    Owned(Connection),
+    // The guard is held while the store is checked out
+    Shared(SharedConnection, Option<OwnedMutexGuard<Connection>>),
+}
+
+// Using a store on a shared connection without checking it out is a bug in the caller, not a runtime
+// condition to recover from, so it panics, blaming the caller
+impl Deref for StoreConnection {
+    type Target = Connection;
+
+    #[track_caller]
+    fn deref(&self) -> &Connection {
+        match self {
+            Self::Owned(conn) => conn,
+            Self::Shared(_, Some(guard)) => guard,
+            Self::Shared(_, None) => panic!(
+                "A store on a shared connection was used without being checked out. \
+                 Call `EntityStore::check_out` first, or send an `AppQuery` so the application does."
+            ),
+        }
+    }
+}
+
+// Each user's teams, as of `data_version`. Emptied by this connection's own changes to memberships,
+// which don't change the data version.
+#[derive(Default)]
+struct MembershipCache {
+    data_version: i64,
+    closures: HashMap<UserUid, HashSet<cedar_policy::EntityUid>>,
+}
+
+pub const LISTS_VIEW: &str = "lists_with_defaults";
+
 lazy_static! {
-    static ref USERS_TABLE_INFO: EntitySQLInfo<'static> = EntitySQLInfo::simple("users", vec!["name"], None);
-    static ref USERS_TEAM_MEMBERSHIPS: AncestorSQLInfo<'static> = AncestorSQLInfo::new("team_memberships", "user_uid", "team_uid");
+    static ref SHARED_CONNECTIONS: std::sync::Mutex<HashMap<PathBuf, SharedConnection>> = std::sync::Mutex::new(HashMap::new());
+
+    static ref USERS_TABLE_INFO: EntitySQLInfo<'static> = EntitySQLInfo::simple("users_with_defaults", vec!["name"], None);
 
     static ref TEAM_TABLE_INFO: EntitySQLInfo<'static> = EntitySQLInfo::simple("teams", vec![], None);
     static ref TEAM_MEMBERSHIPS: AncestorSQLInfo<'static> = AncestorSQLInfo::new("subteams", "child_team", "parent_team");
 
-    static ref LIST_TABLE_INFO: EntitySQLInfo<'static> = EntitySQLInfo::new("lists", "uid",
-        vec!["text", "name", "owner", "readers", "editors"],
+    static ref LIST_TABLE_INFO: EntitySQLInfo<'static> = EntitySQLInfo::new(LISTS_VIEW, "uid",
+        vec!["text", "name", "owner", "readers", "editors", "is_template", "priority_threshold"],
         vec![(0, "text"), (1, "name")],
         None);
 }
 
+// Indexes besides the primary keys, which `bulk_import` drops while loading
+const SECONDARY_INDEXES: [(&str, &str); 2] = [
+    ("lists_by_owner", "CREATE INDEX IF NOT EXISTS lists_by_owner ON lists (owner)"),
+    ("task_comments_by_task", "CREATE INDEX IF NOT EXISTS task_comments_by_task ON task_comments (task_rowid)"),
+];
+
+/// A list to create with `EntityStore::bulk_import`
+#[derive(Debug, Clone)]
+pub struct BulkList {
+    pub owner: UserUid,
+    pub name: String,
+    pub tasks: Vec<(String, TaskState)>,
+}
+
+/// How many recently viewed lists are remembered for each user
+pub const MAX_RECENT_VIEWS: usize = 20;
+
+/// The most audit events returned at once by `get_audit_events`
+pub const MAX_AUDIT_PAGE: usize = 100;
+
+const ADMIN_TEAM: &str = "admin";
+const DEFAULT_TEAMS: [&str; 3] = ["temp", ADMIN_TEAM, "interns"];
+const DEFAULT_SUBTEAMS: [(&str, &str); 1] = [("interns", "temp")];
+// How many fresh uids to try before giving up on a primary key collision
+const MAX_UID_ATTEMPTS: usize = 3;
+
 impl EntityDatabase for EntityStore {
 
     fn get<'e>(&'e self, uid: &cedar_policy::EntityUid) -> Result<Option<Cow<'e, ParsedEntity>>, EvaluationError> {
         // println!("Executing fetch for {:?}", uid);
         match uid.type_name() {
-            t if *t == *TYPE_USER => {
-                let mut ancestors = USERS_TEAM_MEMBERSHIPS.get_ancestors(&self.conn, uid.id(), &TYPE_TEAM).map_err(EvaluationError::mk_err)?;
-                ancestors.extend([uid.clone(), APPLICATION_TINY_TODO.clone().into()]);
-                Ok(USERS_TABLE_INFO.make_entity(&self.conn, uid, |_| Ok(ancestors)).map_err(EvaluationError::mk_err)?.map(Cow::Owned))
-            },
+            t if *t == *TYPE_USER => Ok(self.user_entity(uid, &[]).map_err(EvaluationError::mk_err)?.map(Cow::Owned)),
             t if *t == *TYPE_TEAM => {
                 let mut ancestors = TEAM_MEMBERSHIPS.get_ancestors(&self.conn, uid.id(), &TYPE_TEAM).map_err(EvaluationError::mk_err)?;
-                ancestors.insert(APPLICATION_TINY_TODO.clone().into());
+                ancestors.insert(self.application.clone().into());
                 Ok(TEAM_TABLE_INFO.make_entity(&self.conn, uid, |_| Ok(ancestors)).map_err(EvaluationError::mk_err)?.map(Cow::Owned))
             },
             t if *t == *TYPE_LIST => {
-                Ok(self.get_list(&EntityUid(uid.clone()).try_into().unwrap()).ok().map(|l| Cow::Owned(l.into())))
+                let list: ListUid = EntityUid(uid.clone()).try_into().unwrap();
+                let found = match self.prefetched_lists.borrow().get(&list) {
+                    Some(l) => Ok(l.clone()),
+                    // Don't bother counting the tasks, the entity has no attribute for the count
+                    None => self.get_list_row(&list, false),
+                };
+                if !self.eager_task_loading {
+                    return Ok(found.ok().map(|l| Cow::Owned(l.into())));
+                }
+                // Prefetched lists come without their tasks too, so load them either way
+                let found = found.and_then(|l| Ok(l.with_tasks(self.get_tasks(&list)?)));
+                Ok(found.ok().map(|l| Cow::Owned(l.into_entity_with_tasks())))
+            },
+            t if *t == *TYPE_FOLDER => {
+                let folder: FolderUid = EntityUid(uid.clone()).try_into().unwrap();
+                match self.get_folder(&folder) {
+                    Ok(folder) => Ok(Some(Cow::Owned(folder.into()))),
+                    Err(Error::NoSuchEntity(_)) => Ok(None),
+                    Err(e) => Err(EvaluationError::mk_err(e)),
+                }
             },
-            t if *t == *TYPE_APP => Ok(Some(Cow::Owned(Application::default().into()))),
+            t if *t == *TYPE_APP => Ok(Some(Cow::Owned(Application::new(uid.clone().into()).into()))),
             t if t.basename() == "Action" => Ok(Some(Cow::Owned(ParsedEntity::new(uid.clone(), HashMap::new(), HashSet::new())))),
             _ => Ok(None)
         }
@@ -76,108 +219,1368 @@ impl EntityDatabase for EntityStore {
     }
 }
 
+/// Remembers every entity fetched from `store`, for evaluating many requests against unchanging data
+pub struct MemoizedEntities<'a> {
+    store: &'a EntityStore,
+    cache: RefCell<HashMap<cedar_policy::EntityUid, Option<ParsedEntity>>>,
+}
+
+impl<'a> MemoizedEntities<'a> {
+    pub fn new(store: &'a EntityStore) -> Self {
+        Self { store, cache: RefCell::new(HashMap::new()) }
+    }
+
+    /// Like `new`, but `overrides` are used instead of the stored entities with the same uids
+    pub fn with_overrides(store: &'a EntityStore, overrides: Vec<ParsedEntity>) -> Self {
+        let cache = overrides.into_iter().map(|entity| (entity.uid(), Some(entity))).collect();
+        Self { store, cache: RefCell::new(cache) }
+    }
+
+    /// The entities fetched so far which exist
+    pub fn into_loaded(self) -> Vec<ParsedEntity> {
+        self.cache.into_inner().into_values().flatten().collect()
+    }
+}
+
+impl EntityDatabase for MemoizedEntities<'_> {
+    fn get<'e>(&'e self, uid: &cedar_policy::EntityUid) -> Result<Option<Cow<'e, ParsedEntity>>, EvaluationError> {
+        if let Some(entity) = self.cache.borrow().get(uid) {
+            return Ok(entity.clone().map(Cow::Owned));
+        }
+        let entity = self.store.get(uid)?.map(Cow::into_owned);
+        self.cache.borrow_mut().insert(uid.clone(), entity.clone());
+        Ok(entity.map(Cow::Owned))
+    }
+
+    fn partial_mode(&self) -> cedar_policy::Mode {
+        self.store.partial_mode()
+    }
+}
+
 impl EntityStore {
     pub fn from_file(file: impl AsRef<Path>) -> Self {
         Self::new(Connection::open(file).expect("Failed to open database"))
     }
 
     pub fn new(conn: Connection) -> Self {
-        Self { conn }
+        Self::with_connection(StoreConnection::Owned(conn))
+    }
+
+    fn with_connection(conn: StoreConnection) -> Self {
+        Self {
+            conn,
+            application: APPLICATION_TINY_TODO.clone(),
+            tenant_scoped: false,
+            prefetched_lists: RefCell::new(HashMap::new()),
+            memberships: None,
+            attribute_defaults: AttributeDefault::standard(),
+            eager_task_loading: false,
+        }
+    }
+
+    /// Remember each user's `membership_closure` until team memberships or subteams change,
+    /// rather than walking the team hierarchy whenever the user entity is loaded
+    pub fn with_membership_cache(self) -> Self {
+        Self { memberships: Some(RefCell::new(MembershipCache::default())), ..self }
+    }
+
+    /// Load a list's tasks along with the list entity, filling in its `tasks` attribute.
+    /// Otherwise it's left out, and authorizing a list never goes through its tasks.
+    pub fn with_eager_task_loading(self) -> Self {
+        Self { eager_task_loading: true, ..self }
+    }
+
+    /// Use `application` as the root of the entity hierarchy, instead of `Application::"TinyTodo"`
+    pub fn with_application(self, application: EntityUid) -> Self {
+        Self { application, ..self }
+    }
+
+    /// Replace NULLs according to `attribute_defaults` rather than `AttributeDefault::standard()`.
+    /// Takes effect when the views are next made, by `initialize_schema` or `create_defaults_views`.
+    pub fn with_attribute_defaults(self, attribute_defaults: Vec<AttributeDefault>) -> Self {
+        Self { attribute_defaults, ..self }
+    }
+
+    /// Open `file`, which may be a `file:` URI, for reads only, e.g. a read replica.
+    /// SQLite itself refuses any write, so the schema must already be initialized,
+    /// apart from the views made by `create_defaults_views`.
+    pub fn open_read_only(file: impl AsRef<Path>) -> Result<Self, Error> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        Ok(Self::new(Connection::open_with_flags(file, flags)?))
+    }
+
+    /// Apply `tuning` to the connection. Meant to be called straight after opening it.
+    pub fn with_tuning(self, tuning: SqliteTuning) -> Result<Self, Error> {
+        if let Some(cache_size) = tuning.cache_size {
+            self.conn.pragma_update(None, "cache_size", cache_size)?;
+        }
+        if let Some(mmap_size) = tuning.mmap_size {
+            let requested = i64::try_from(mmap_size)
+                .map_err(|_| Error::InvalidInput(format!("mmap_size {mmap_size} is too large")))?;
+            self.conn.pragma_update(None, "mmap_size", requested)?;
+            // SQLite silently caps the size at a compile-time limit. In-memory databases report nothing.
+            let applied: Option<i64> = self.conn.pragma_query_value(None, "mmap_size", |row| row.get(0)).optional()?;
+            if let Some(applied) = applied.filter(|applied| *applied != requested) {
+                warn!("Requested mmap_size {requested}, but SQLite is limited to {applied}");
+            }
+        }
+        Ok(self)
+    }
+
+    /// Take the shared connection for the store's exclusive use, waiting for any other tenant using it.
+    /// A store on a shared connection panics if used without this. Does nothing for a store with a connection of its own.
+    pub async fn check_out(&mut self) {
+        if let StoreConnection::Shared(shared, guard @ None) = &mut self.conn {
+            *guard = Some(shared.conn.clone().lock_owned().await);
+        }
+    }
+
+    /// Hand the shared connection back for the other tenants. `wrote` says whether the store changed
+    /// the database meanwhile, so the other stores drop anything they cached from it.
+    pub fn check_in(&mut self, wrote: bool) {
+        if let StoreConnection::Shared(shared, guard) = &mut self.conn {
+            if wrote {
+                shared.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            *guard = None;
+        }
+    }
+
+    // ` AND <column> = '<tenant>'` for a tenant-scoped store, restricting a query on lists to the tenant's own
+    fn tenant_condition(&self, column: &str) -> String {
+        if self.tenant_scoped {
+            format!(" AND {column} = '{}'", self.application.id().as_ref().replace('\'', "''"))
+        } else {
+            String::new()
+        }
+    }
+
+    // Like `tenant_condition` on the `tenant` column, also leaving out lists deleted but not yet purged
+    fn live_lists_condition(&self) -> String {
+        format!(" AND deleted_at IS NULL{}", self.tenant_condition("tenant"))
+    }
+
+    /// The tenant whose lists this store is restricted to, if it was made by `SharedConnection::store_for`
+    pub fn tenant_scope(&self) -> Option<&EntityUid> {
+        self.tenant_scoped.then_some(&self.application)
+    }
+
+    // The entity for user `uid`, as if they were also a member of `extra_teams`
+    fn user_entity(&self, uid: &cedar_policy::EntityUid, extra_teams: &[TeamUid]) -> Result<Option<ParsedEntity>, Error> {
+        let user: UserUid = EntityUid(uid.clone()).try_into().unwrap();
+        let mut ancestors = self.membership_closure(&user)?;
+        ancestors.extend([uid.clone(), self.application.clone().into()]);
+        for team in extra_teams {
+            let team = EntityUid::from(team.clone());
+            ancestors.extend(TEAM_MEMBERSHIPS.get_ancestors(&self.conn, team.id(), &TYPE_TEAM)?);
+            ancestors.insert(team.into());
+        }
+        Ok(USERS_TABLE_INFO.make_entity(&self.conn, uid, |_| Ok(ancestors))?)
+    }
+
+    /// Every team `user` is a member of, directly or through subteams.
+    /// Served from the cache set up by `with_membership_cache`, if any.
+    pub fn membership_closure(&self, user: &UserUid) -> Result<HashSet<cedar_policy::EntityUid>, Error> {
+        let Some(cache) = &self.memberships else {
+            return self.load_membership_closure(user);
+        };
+        let data_version = self.data_version()?;
+        if cache.borrow().data_version != data_version {
+            *cache.borrow_mut() = MembershipCache { data_version, closures: HashMap::new() };
+        }
+        if let Some(closure) = cache.borrow().closures.get(user) {
+            return Ok(closure.clone());
+        }
+        let closure = self.load_membership_closure(user)?;
+        cache.borrow_mut().closures.insert(user.clone(), closure.clone());
+        Ok(closure)
+    }
+
+    fn load_membership_closure(&self, user: &UserUid) -> Result<HashSet<cedar_policy::EntityUid>, Error> {
+        Ok(self.get_user_teams(user)?.into_iter().map(|team| EntityUid::from(team).into()).collect())
+    }
+
+    // Called after any change to `team_memberships` or `subteams` made on this connection
+    fn forget_memberships(&self) {
+        if let Some(cache) = &self.memberships {
+            cache.borrow_mut().closures.clear();
+        }
+    }
+
+    /// The entity for `user` if they were also a member of `extra_teams`, for evaluating hypothetical requests
+    pub fn get_user_with_teams(&self, user: &UserUid, extra_teams: &[TeamUid]) -> Result<ParsedEntity, Error> {
+        self.user_entity(user.as_ref(), extra_teams)?
+            .ok_or_else(|| Error::no_such_entity(user.clone()))
+    }
+
+    /// Changes whenever another connection commits to the database, but not for this connection's own writes
+    pub fn data_version(&self) -> Result<i64, Error> {
+        let data_version: i64 = self.conn.pragma_query_value(None, "data_version", |row| row.get(0))?;
+        match &self.conn {
+            StoreConnection::Owned(_) => Ok(data_version),
+            StoreConnection::Shared(shared, _) => Ok(data_version + shared.writes.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Abort any SQL statement run from now on once `cancel` is cancelled, or stop doing so if `cancel` is `None`.
+    /// An aborted statement fails with `ErrorCode::OperationInterrupted`.
+    pub fn set_cancellation(&self, cancel: Option<CancellationToken>) {
+        match cancel {
+            // The handler runs every 1000 virtual machine instructions
+            Some(cancel) => self.conn.progress_handler(1000, Some(move || cancel.is_cancelled())),
+            None => self.conn.progress_handler(0, None::<fn() -> bool>),
+        }
+    }
+
+    /// Copy the write-ahead log into the database and truncate it, if the database is in WAL mode.
+    /// Refused inside a transaction, whose changes the log must keep until it ends.
+    pub fn checkpoint_wal(&self) -> Result<(), Error> {
+        if !self.conn.is_autocommit() {
+            return Err(Error::InvalidInput("Can't checkpoint the WAL inside a transaction".to_string()));
+        }
+        let busy: bool = self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+        if busy {
+            warn!("WAL checkpoint didn't complete, another connection is using the database");
+        }
+        Ok(())
+    }
+
+    /// Create any missing tables, so that a brand new database file can be used directly.
+    /// This mirrors the layout produced by `create_huge_db.py`.
+    pub fn initialize_schema(&self) -> Result<(), Error> {
+        self.conn.execute_batch("
+            CREATE TABLE IF NOT EXISTS users (uid text PRIMARY KEY, name text NOT NULL);
+            CREATE TABLE IF NOT EXISTS teams (uid text PRIMARY KEY);
+            CREATE TABLE IF NOT EXISTS team_memberships (user_uid REFERENCES users, team_uid REFERENCES teams);
+            CREATE TABLE IF NOT EXISTS subteams (child_team REFERENCES teams, parent_team REFERENCES teams);
+            CREATE TABLE IF NOT EXISTS lists (uid text PRIMARY KEY, owner REFERENCES users, name text NOT NULL, readers REFERENCES teams, editors REFERENCES teams);
+            CREATE TABLE IF NOT EXISTS owners (list_uid REFERENCES lists, user_uid REFERENCES users, PRIMARY KEY (list_uid, user_uid));
+            CREATE TABLE IF NOT EXISTS folders (uid text PRIMARY KEY, name text NOT NULL, owner REFERENCES users);
+            CREATE TABLE IF NOT EXISTS tasks (name text NOT NULL, state integer NOT NULL, list_uid REFERENCES lists);
+            CREATE TABLE IF NOT EXISTS task_comments (task_rowid integer NOT NULL, author_uid REFERENCES users, body text NOT NULL, created_at integer NOT NULL);
+            CREATE TABLE IF NOT EXISTS authorized_views (name text PRIMARY KEY, principal REFERENCES users);
+            CREATE TABLE IF NOT EXISTS recent_views (user_uid REFERENCES users, list_uid REFERENCES lists, viewed_at integer NOT NULL, PRIMARY KEY (user_uid, list_uid));
+            CREATE TABLE IF NOT EXISTS audit_log (timestamp integer NOT NULL, principal text NOT NULL, action text NOT NULL, resource text NOT NULL, decision text NOT NULL, fail_open bool NOT NULL);
+        ")?;
+        for (_, create) in SECONDARY_INDEXES {
+            self.conn.execute(create, [])?;
+        }
+        // Lists used to have arbitrary parents, which folders replaced since `in` can only be translated to SQL through `folder_uid`
+        self.conn.execute_batch("DROP TABLE IF EXISTS list_parents")?;
+        // Columns added after the original table layout, so existing databases need migrating
+        self.add_column_if_missing("lists", "is_template", "bool NOT NULL DEFAULT 0")?;
+        self.add_column_if_missing("lists", "priority_threshold", "integer NOT NULL DEFAULT 0")?;
+        self.add_column_if_missing("lists", "metadata", "text NOT NULL DEFAULT '{}'")?;
+        self.add_column_if_missing("lists", "tenant", "text NOT NULL DEFAULT 'TinyTodo'")?;
+        self.add_column_if_missing("lists", "locked", "bool NOT NULL DEFAULT 0")?;
+        self.add_column_if_missing("lists", "folder_uid", "text REFERENCES folders")?;
+        // Seconds since the epoch when the list was deleted, see `delete_list`
+        self.add_column_if_missing("lists", "deleted_at", "integer")?;
+        self.add_column_if_missing("audit_log", "impersonator", "text")?;
+        self.add_column_if_missing("authorized_views", "tenant", "text NOT NULL DEFAULT 'TinyTodo'")?;
+        self.add_column_if_missing("tasks", "visible_to", "text REFERENCES teams")?;
+        // `state` used to be a bool. SQLite stored it as 0 or 1, which `TaskState` still reads as
+        // `Unchecked` and `Checked`, so only values written as text or reals need rewriting.
+        self.conn.execute("UPDATE tasks SET state = CASE WHEN state THEN 1 ELSE 0 END WHERE typeof(state) != 'integer'", [])?;
+        // Tasks are numbered 1, 2, 3, ... within their list. Numbers aren't reused after a task is deleted,
+        // so each list remembers the last number it handed out.
+        self.add_column_if_missing("lists", "last_task_number", "integer NOT NULL DEFAULT 0")?;
+        if self.add_column_if_missing("tasks", "task_number", "integer")? {
+            self.conn.execute_batch("
+                UPDATE tasks SET task_number = (SELECT n FROM
+                    (SELECT ROWID AS task, ROW_NUMBER() OVER (PARTITION BY list_uid ORDER BY ROWID) AS n FROM tasks)
+                    WHERE task = tasks.ROWID);
+                UPDATE lists SET last_task_number = (SELECT COALESCE(MAX(task_number), 0) FROM tasks WHERE list_uid = lists.uid);
+            ")?;
+        }
+        self.create_defaults_views()
+    }
+
+    /// (Re)create the `<table>_with_defaults` views entities are read through, and `team_membership_closure`.
+    /// `initialize_schema` does this, but can't on a read-only connection, where the views are made `TEMP`,
+    /// for this connection only.
+    pub fn create_defaults_views(&self) -> Result<(), Error> {
+        let schema = if self.conn.is_readonly(DatabaseName::Main)? { "temp" } else { "main" };
+        self.create_defaults_view(schema, "users")?;
+        self.create_defaults_view(schema, "lists")?;
+        // Every team each user is in, directly or through subteams, as Cedar's `in` sees it.
+        // Authorization queries test `User in Team` against this rather than `team_memberships`.
+        self.conn.execute_batch(&format!("DROP VIEW IF EXISTS {schema}.team_membership_closure;
+            CREATE VIEW {schema}.team_membership_closure AS
+                WITH RECURSIVE closure(user_uid, team_uid) AS (
+                    SELECT user_uid, team_uid FROM team_memberships
+                    UNION
+                    SELECT closure.user_uid, parent_team FROM subteams JOIN closure ON child_team = closure.team_uid
+                )
+                SELECT user_uid, team_uid FROM closure;"))?;
+        Ok(())
+    }
+
+    // (Re)create `<table>_with_defaults` in `schema`, replacing NULLs according to `attribute_defaults`
+    fn create_defaults_view(&self, schema: &str, table: &str) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT name FROM pragma_table_info('{table}')"))?;
+        let columns = stmt.query_map([], |row| row.get::<_, String>(0))?
+            .map(|column| column.map(|column| {
+                match self.attribute_defaults.iter().find(|d| d.table == table && d.column == column) {
+                    Some(default) => format!("COALESCE({column}, {}) AS {column}", default.value),
+                    None => column,
+                }
+            }))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Every list query goes through the view, so this is what hides deleted lists until they're purged
+        let filter = if table == "lists" { " WHERE deleted_at IS NULL" } else { "" };
+        self.conn.execute_batch(&format!("DROP VIEW IF EXISTS {schema}.{table}_with_defaults;
+            CREATE VIEW {schema}.{table}_with_defaults AS SELECT {} FROM {table}{filter};", columns.join(", ")))?;
+        Ok(())
+    }
+
+    // Returns whether the column was added
+    fn add_column_if_missing(&self, table: &str, column: &str, decl: &str) -> Result<bool, Error> {
+        let exists = self.conn.query_row(&format!("SELECT EXISTS (SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?)"), [column], |row| row.get::<_, bool>(0))?;
+        if !exists {
+            self.conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))?;
+        }
+        Ok(!exists)
+    }
+
+    /// Seed the default teams (and optionally an admin user) if they aren't already present.
+    /// The application itself has no row: it is synthesized in `get`, so every user
+    /// and team is a member of it without any bootstrapping.
+    pub fn bootstrap_defaults(&self, admin: Option<(&UserUid, &str)>) -> Result<(), Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        for team in DEFAULT_TEAMS {
+            tx.execute("INSERT OR IGNORE INTO teams VALUES (?)", [team])?;
+        }
+        for (child, parent) in DEFAULT_SUBTEAMS {
+            tx.execute("INSERT INTO subteams SELECT ?1, ?2 WHERE NOT EXISTS
+                (SELECT 1 FROM subteams WHERE child_team = ?1 AND parent_team = ?2)", [child, parent])?;
+        }
+        if let Some((uid, name)) = admin {
+            let id = uid.as_ref().id().as_ref();
+            tx.execute("INSERT OR IGNORE INTO users VALUES (?, ?)", [id, name])?;
+            tx.execute("INSERT INTO team_memberships SELECT ?1, ?2 WHERE NOT EXISTS
+                (SELECT 1 FROM team_memberships WHERE user_uid = ?1 AND team_uid = ?2)", [id, ADMIN_TEAM])?;
+        }
+        tx.commit()?;
+        self.forget_memberships();
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn insert_user(&self, uid: &UserUid, name: &str) -> Result<(), Error> {
+        self.conn.execute("INSERT INTO users VALUES (?, ?)", [uid.as_ref().id().as_ref(), name])?;
+        Ok(())
+    }
+
+    /// Run `f` inside a transaction, committing if it succeeds and rolling back otherwise.
+    pub fn with_transaction<T>(&self, f: impl FnOnce(&Self) -> Result<T, Error>) -> Result<T, Error> {
+        // Inside `begin_transaction`, just join the outer transaction, which rolls back if `f` fails
+        if !self.conn.is_autocommit() {
+            return f(self);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let result = match f(self) {
+            Ok(result) => result,
+            Err(e) => {
+                // Memberships read inside the transaction are gone once it rolls back
+                self.forget_memberships();
+                return Err(e);
+            }
+        };
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Start a transaction spanning several calls, which lasts until `end_transaction`
+    pub fn begin_transaction(&self) -> Result<(), Error> {
+        self.conn.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        !self.conn.is_autocommit()
+    }
+
+    pub fn end_transaction(&self, commit: bool) -> Result<(), Error> {
+        self.conn.execute_batch(if commit { "COMMIT" } else { "ROLLBACK" })?;
+        if !commit {
+            self.forget_memberships();
+        }
+        Ok(())
+    }
+
+    pub fn ensure_exists(&self, euid: &UserOrTeamUid) -> Result<(), Error> {
+        if self.exists(euid.as_ref())? {
+            Ok(())
+        } else {
+            Err(Error::no_such_entity(euid.clone()))
+        }
+    }
+
+    /// Whether `euid` has a row in the database. Entities which aren't stored, like applications
+    /// and actions, always exist.
+    pub fn exists(&self, euid: &EntityUid) -> Result<bool, Error> {
+        let table = match euid.type_name() {
+            t if *t == *TYPE_USER => "users",
+            t if *t == *TYPE_TEAM => "teams",
+            t if *t == *TYPE_LIST => "lists",
+            t if *t == *TYPE_FOLDER => "folders",
+            _ => return Ok(true),
+        };
+        let condition = if table == "lists" { self.live_lists_condition() } else { String::new() };
+        Ok(self.conn.query_row(&format!("SELECT EXISTS (SELECT 1 FROM {table} WHERE uid = ?{condition})"), [euid.id().as_ref()], |row| row.get(0))?)
+    }
+
+    pub fn record_audit(&self, event: &AuditEvent) -> Result<(), Error> {
+        self.conn.execute("INSERT INTO audit_log (timestamp, principal, action, resource, decision, fail_open, impersonator) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                event.timestamp,
+                event.principal.to_string(),
+                event.action.to_string(),
+                event.resource.to_string(),
+                decision_str(event.decision),
+                event.fail_open,
+                event.impersonator.as_ref().map(ToString::to_string)
+            ])?;
+        Ok(())
+    }
+
+    /// The events with `principal` as the principal, newest first, skipping `offset` and returning
+    /// at most `limit`, itself at most `MAX_AUDIT_PAGE`. The total number of such events is returned too.
+    pub fn get_audit_events(&self, principal: &EntityUid, offset: usize, limit: usize) -> Result<(Vec<AuditEvent>, usize), Error> {
+        let limit = limit.min(MAX_AUDIT_PAGE);
+        let principal = principal.to_string();
+        let total = self.conn.query_row("SELECT COUNT(*) FROM audit_log WHERE principal = ?", [&principal], |row| row.get::<_, i64>(0))?;
+        let mut stmt = self.conn.prepare("SELECT timestamp, principal, action, resource, decision, fail_open, impersonator FROM audit_log
+            WHERE principal = ? ORDER BY timestamp DESC, ROWID DESC LIMIT ? OFFSET ?")?;
+        let events = stmt.query_map(params![principal, limit as i64, offset as i64], |row| {
+            Ok(AuditEvent {
+                timestamp: row.get(0)?,
+                principal: parse_column(row, 1)?,
+                action: parse_column(row, 2)?,
+                resource: parse_column(row, 3)?,
+                decision: parse_decision(row, 4)?,
+                fail_open: row.get(5)?,
+                impersonator: match row.get_ref(6)? {
+                    ValueRef::Null => None,
+                    _ => Some(parse_column(row, 6)?),
+                },
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(sql_error)?;
+        Ok((events, total as usize))
+    }
+
+    // `name` must already have been checked to be a plain SQL identifier
+    pub fn replace_authorized_view(&self, name: &str, principal: &UserUid, select: &str) -> Result<(), Error> {
+        self.with_transaction(|store| {
+            store.conn.execute_batch(&format!("DROP VIEW IF EXISTS {name}; CREATE VIEW {name} AS {select};"))?;
+            store.conn.execute("INSERT OR REPLACE INTO authorized_views (name, principal, tenant) VALUES (?, ?, ?)",
+                [name, principal.as_ref().id().as_ref(), store.application.id().as_ref()])?;
+            Ok(())
+        })
+    }
+
+    pub fn drop_authorized_view(&self, name: &str) -> Result<(), Error> {
+        self.with_transaction(|store| {
+            store.conn.execute_batch(&format!("DROP VIEW IF EXISTS {name};"))?;
+            store.conn.execute("DELETE FROM authorized_views WHERE name = ?", [name])?;
+            Ok(())
+        })
     }
 
-    pub fn create_team(&mut self) -> Result<TeamUid, Error> {
-        let fresh_uid = Uuid::new_v4().to_string();
-        self.conn.execute("INSERT INTO teams VALUES (?)", &[&fresh_uid])?;
+    pub fn get_authorized_views(&self) -> Result<Vec<(String, UserUid)>, Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT name, principal FROM authorized_views WHERE 1{}", self.tenant_condition("tenant")))?;
+        let result = stmt.query_map([], |row| {
+            let principal: EntitySQLId = row.get(1)?;
+            Ok((row.get(0)?, principal.id().into()))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+        Ok(result)
+    }
+
+    pub fn create_team(&self) -> Result<TeamUid, Error> {
+        let fresh_uid = self.insert_with_fresh_uid(fresh_uuid, |uid| self.conn.execute("INSERT INTO teams VALUES (?)", &[uid]))?;
         Ok(fresh_uid.parse::<EntityId>().unwrap().into())
     }
 
-    pub fn create_list(&mut self, owner: UserUid, name: &str, readers: TeamUid, editors: TeamUid) -> Result<ListUid, Error> {
-        let fresh_uid = Uuid::new_v4().to_string();
-        self.conn.execute("INSERT INTO lists VALUES (?, ?, ?, ?, ?)",
-        &[
-            &fresh_uid,
-            owner.as_ref().id().as_ref(),
-            name,
-            readers.as_ref().id().as_ref(),
-            editors.as_ref().id().as_ref()
-        ]).unwrap();
+    /// Run `insert` with uids from `fresh_uid` until one doesn't collide with an existing primary key
+    fn insert_with_fresh_uid(&self, mut fresh_uid: impl FnMut() -> String, insert: impl Fn(&str) -> rusqlite::Result<usize>) -> Result<String, Error> {
+        let mut attempt = 1;
+        loop {
+            let uid = fresh_uid();
+            match insert(&uid) {
+                Ok(_) => return Ok(uid),
+                Err(rusqlite::Error::SqliteFailure(e, _)) if e.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY && attempt < MAX_UID_ATTEMPTS => {
+                    warn!("uid {uid} is already taken, retrying with a fresh one");
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Make `child` a subteam of `parent`, refusing to create a cycle in the team hierarchy
+    pub fn add_subteam(&self, child: &TeamUid, parent: &TeamUid) -> Result<(), Error> {
+        let child_id = child.as_ref().id().as_ref();
+        let parent_id = parent.as_ref().id().as_ref();
+        // Walk up from `parent`: if we reach `child`, the new edge would close a loop
+        let would_cycle = self.conn.query_row("
+            WITH RECURSIVE ancestors(uid) AS (
+                SELECT ?1
+                UNION
+                SELECT parent_team FROM subteams JOIN ancestors ON child_team = ancestors.uid
+            )
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE uid = ?2)", [parent_id, child_id], |row| row.get::<_, bool>(0))?;
+        if would_cycle {
+            return Err(Error::WouldCreateCycle(child.clone().into(), parent.clone().into()));
+        }
+        self.conn.execute("INSERT INTO subteams SELECT ?1, ?2 WHERE NOT EXISTS
+            (SELECT 1 FROM subteams WHERE child_team = ?1 AND parent_team = ?2)", [child_id, parent_id])?;
+        self.forget_memberships();
+        Ok(())
+    }
+
+    /// Undo `add_subteam`. Removing an edge which doesn't exist changes nothing.
+    pub fn remove_subteam(&self, child: &TeamUid, parent: &TeamUid) -> Result<(), Error> {
+        let removed = self.conn.execute("DELETE FROM subteams WHERE child_team = ? AND parent_team = ?",
+            [child.as_ref().id().as_ref(), parent.as_ref().id().as_ref()])?;
+        if removed == 0 {
+            trace!("{} was not a subteam of {}", child.as_ref(), parent.as_ref());
+        }
+        self.forget_memberships();
+        Ok(())
+    }
+
+    /// Delete `team` and its subteam edges. Refuses if a list or task still refers to the team, or if
+    /// the team has members, unless `detach_members` is set, in which case the memberships are removed too.
+    pub fn delete_team(&self, team: &TeamUid, detach_members: bool) -> Result<(), Error> {
+        let id = team.as_ref().id().as_ref();
+        self.with_transaction(|store| {
+            let in_use = |query: &str| store.conn.query_row(query, [id], |row| row.get::<_, bool>(0));
+            if in_use("SELECT EXISTS (SELECT 1 FROM lists WHERE readers = ?1 OR editors = ?1)")? {
+                return Err(Error::TeamInUse(team.clone().into(), "a list"));
+            }
+            if in_use("SELECT EXISTS (SELECT 1 FROM tasks WHERE visible_to = ?)")? {
+                return Err(Error::TeamInUse(team.clone().into(), "a task"));
+            }
+            if !detach_members && in_use("SELECT EXISTS (SELECT 1 FROM team_memberships WHERE team_uid = ?)")? {
+                return Err(Error::TeamInUse(team.clone().into(), "its members"));
+            }
+            store.conn.execute("DELETE FROM team_memberships WHERE team_uid = ?", [id])?;
+            store.conn.execute("DELETE FROM subteams WHERE child_team = ?1 OR parent_team = ?1", [id])?;
+            store.forget_memberships();
+            if store.conn.execute("DELETE FROM teams WHERE uid = ?", [id])? == 0 {
+                return Err(Error::no_such_entity(team.clone()));
+            }
+            Ok(())
+        })
+    }
+
+    /// Put `user` directly in `team`. Adding an existing member changes nothing.
+    pub fn add_team_member(&self, user: &UserUid, team: &TeamUid) -> Result<(), Error> {
+        self.conn.execute("INSERT INTO team_memberships SELECT ?1, ?2 WHERE NOT EXISTS
+            (SELECT 1 FROM team_memberships WHERE user_uid = ?1 AND team_uid = ?2)", [user.as_ref().id().as_ref(), team.as_ref().id().as_ref()])?;
+        self.forget_memberships();
+        Ok(())
+    }
+
+    /// Take `user` out of `team`. Removing someone who isn't a direct member changes nothing.
+    pub fn remove_team_member(&self, user: &UserUid, team: &TeamUid) -> Result<(), Error> {
+        let removed = self.conn.execute("DELETE FROM team_memberships WHERE user_uid = ? AND team_uid = ?",
+            [user.as_ref().id().as_ref(), team.as_ref().id().as_ref()])?;
+        if removed == 0 {
+            trace!("{} was not a member of {}", user.as_ref(), team.as_ref());
+        }
+        self.forget_memberships();
+        Ok(())
+    }
+
+    /// Every team `user` is a member of, directly or through subteams
+    pub fn get_user_teams(&self, user: &UserUid) -> Result<Vec<TeamUid>, Error> {
+        let mut stmt = self.conn.prepare("
+            WITH RECURSIVE teams(uid) AS (
+                SELECT team_uid FROM team_memberships WHERE user_uid = ?
+                UNION
+                SELECT parent_team FROM subteams JOIN teams ON child_team = teams.uid
+            )
+            SELECT uid FROM teams")?;
+        let r: Result<Vec<TeamUid>, rusqlite::Error> = stmt.query_map([user.as_ref().id().as_ref()], |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(uid.id().into())
+        })?
+        .collect();
+        Ok(r?)
+    }
+
+    /// The teams `member`, a user or a team, is directly in, without following subteams
+    pub fn get_parent_teams(&self, member: &EntityUid) -> Result<Vec<TeamUid>, Error> {
+        let query = if *member.type_name() == *TYPE_USER {
+            "SELECT team_uid FROM team_memberships WHERE user_uid = ? ORDER BY team_uid"
+        } else {
+            "SELECT parent_team FROM subteams WHERE child_team = ? ORDER BY parent_team"
+        };
+        let mut stmt = self.conn.prepare(query)?;
+        let r: Result<Vec<TeamUid>, rusqlite::Error> = stmt.query_map([member.id().as_ref()], |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(uid.id().into())
+        })?
+        .collect();
+        Ok(r?)
+    }
+
+    pub fn create_list(&self, owner: UserUid, name: &str, readers: TeamUid, editors: TeamUid) -> Result<ListUid, Error> {
+        self.create_list_in(&self.application, owner, name, readers, editors)
+    }
+
+    /// Like `create_list`, for a list belonging to the application `tenant`
+    pub fn create_list_in(&self, tenant: &EntityUid, owner: UserUid, name: &str, readers: TeamUid, editors: TeamUid) -> Result<ListUid, Error> {
+        let fresh_uid = self.insert_with_fresh_uid(fresh_uuid, |uid| {
+            self.conn.execute("INSERT INTO lists (uid, owner, name, readers, editors, tenant) VALUES (?, ?, ?, ?, ?, ?)",
+            &[
+                uid,
+                owner.as_ref().id().as_ref(),
+                name,
+                readers.as_ref().id().as_ref(),
+                editors.as_ref().id().as_ref(),
+                tenant.as_ref().id().as_ref(),
+            ])
+        })?;
         Ok(fresh_uid.parse::<EntityId>().unwrap().into())
     }
 
+    /// Create many lists and their tasks, each list with fresh reader and editor teams, much faster than
+    /// `create_list` and `create_task` would. Everything is loaded in one transaction, with the secondary
+    /// indexes dropped until the end and foreign keys checked only on commit, so if anything fails
+    /// nothing is imported. Returns the new lists' uids, in the order of `lists`.
+    /// `check_task` is called before each task is added, and can fail the import.
+    /// Refused inside `begin_transaction`, whose other statements would run without the indexes
+    /// and with their foreign keys deferred too.
+    pub fn bulk_import(
+        &self,
+        lists: Vec<BulkList>,
+        check_task: impl Fn(&Self, &ListUid, &str) -> Result<(), Error>,
+    ) -> Result<Vec<ListUid>, Error> {
+        if self.in_transaction() {
+            return Err(Error::InvalidInput("Can't bulk import inside a transaction".to_string()));
+        }
+        self.with_transaction(|store| {
+            store.conn.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            for (index, _) in SECONDARY_INDEXES {
+                store.conn.execute_batch(&format!("DROP INDEX IF EXISTS {index}"))?;
+            }
+            let mut uids = Vec::with_capacity(lists.len());
+            let mut insert_task = store.conn.prepare("INSERT INTO tasks (name, state, list_uid, task_number) VALUES (?, ?, ?, ?)")?;
+            for list in lists {
+                let insert_team = || store.insert_with_fresh_uid(fresh_uuid, |uid| {
+                    store.conn.prepare_cached("INSERT INTO teams VALUES (?)")?.execute([uid])
+                });
+                let (readers, editors) = (insert_team()?, insert_team()?);
+                let uid = store.insert_with_fresh_uid(fresh_uuid, |uid| {
+                    store.conn.prepare_cached("INSERT INTO lists (uid, owner, name, readers, editors, tenant, last_task_number) VALUES (?, ?, ?, ?, ?, ?, ?)")?
+                        .execute(params![
+                            uid,
+                            list.owner.as_ref().id().as_ref(),
+                            list.name,
+                            readers,
+                            editors,
+                            store.application.id().as_ref(),
+                            list.tasks.len() as i64,
+                        ])
+                })?;
+                let list_uid: ListUid = uid.parse::<EntityId>().unwrap().into();
+                for (number, (name, state)) in (1..).zip(list.tasks) {
+                    check_task(store, &list_uid, &name)?;
+                    insert_task.execute(params![name, i64::from(state), uid, number])?;
+                }
+                uids.push(list_uid);
+            }
+            for (_, create) in SECONDARY_INDEXES {
+                store.conn.execute(create, [])?;
+            }
+            Ok(uids)
+        })
+    }
+
+    /// The number of lists `user` is the primary owner of
+    pub fn count_owned_lists(&self, user: &UserUid) -> Result<i64, Error> {
+        let condition = self.live_lists_condition();
+        Ok(self.conn.query_row(&format!("SELECT COUNT(*) FROM lists WHERE owner = ?{condition}"), [user.as_ref().id().as_ref()], |row| row.get(0))?)
+    }
+
+    /// The size of each table operators are likely to watch. Users and teams are shared by every tenant,
+    /// but only this store's lists and their tasks are counted.
+    pub fn stats(&self) -> Result<StoreStats, Error> {
+        let count = |sql: &str| self.conn.query_row(sql, [], |row| row.get::<_, i64>(0));
+        let tenant = self.tenant_condition("tenant");
+        Ok(StoreStats {
+            users: count("SELECT COUNT(*) FROM users")?,
+            teams: count("SELECT COUNT(*) FROM teams")?,
+            lists: count(&format!("SELECT COUNT(*) FROM lists WHERE TRUE{tenant}"))?,
+            tasks: count(&format!("SELECT COUNT(*) FROM tasks WHERE list_uid IN (SELECT uid FROM lists WHERE TRUE{tenant})"))?,
+            memberships: count("SELECT (SELECT COUNT(*) FROM team_memberships) + (SELECT COUNT(*) FROM subteams)")?,
+        })
+    }
+
+    pub fn count_tasks(&self, list: &ListUid) -> Result<i64, Error> {
+        Ok(self.conn.query_row("SELECT COUNT(*) FROM tasks WHERE list_uid = ?", [list.as_ref().id().as_ref()], |row| row.get(0))?)
+    }
+
     fn get_tasks(&self, euid: &ListUid) -> Result<Vec<Task>, Error> {
-        let mut stmt = self.conn.prepare("SELECT ROWID, name, state FROM tasks WHERE list_uid = ?")?;
+        let mut stmt = self.conn.prepare("SELECT task_number, name, state, visible_to FROM tasks WHERE list_uid = ? ORDER BY task_number")?;
         let result = stmt.query_map(&[euid.as_ref().id().as_ref()], |row| {
             Ok(Task::new(
                 row.get(0)?,
                 row.get(1)?,
-                row.get::<_, bool>(2)?.into()
-            ))
+                parse_task_state(row, 2)?
+            ).with_visible_to(row.get::<_, Option<EntitySQLId>>(3)?.map(|team| team.id().into())))
         })?
         .collect::<Result<Vec<Task>, _>>()?;
         Ok(result)
     }
 
+    /// A page of the tasks selected by `filter`, a condition on `tasks` such as
+    /// `AppContext::authorized_lists_filter` on `tasks.list_uid`, ordered by list and then task number.
+    /// Also returns how many tasks `filter` selects in all.
+    pub fn get_tasks_in(&self, filter: Condition, offset: usize, limit: usize) -> Result<(Vec<(ListUid, Task)>, usize), Error> {
+        let tasks = Alias::new("tasks");
+        let count = Query::select()
+            .expr(Func::count(Expr::col(Asterisk)))
+            .from(tasks.clone())
+            .cond_where(filter.clone())
+            .to_string(SqliteQueryBuilder);
+        let total: i64 = self.conn.query_row(&count, [], |row| row.get(0))?;
+        let base = Query::select()
+            .columns(["list_uid", "task_number", "name", "state", "visible_to"].map(|c| (tasks.clone(), Alias::new(c))))
+            .from(tasks.clone())
+            .order_by((tasks.clone(), Alias::new("list_uid")), Order::Asc)
+            .order_by((tasks, Alias::new("task_number")), Order::Asc)
+            .limit(limit as u64)
+            .offset(offset as u64)
+            .to_owned();
+        let page = self.run_filtered(base, filter, |row| {
+            let list: EntitySQLId = row.get(0)?;
+            let task = Task::new(row.get(1)?, row.get(2)?, parse_task_state(row, 3)?)
+                .with_visible_to(row.get::<_, Option<EntitySQLId>>(4)?.map(|team| team.id().into()));
+            Ok((list.id().into(), task))
+        })?;
+        Ok((page, total as usize))
+    }
+
     pub fn get_list(&self, euid: &ListUid) -> Result<List, Error> {
         let tasks = self.get_tasks(euid)?;
-        self.conn.query_row("SELECT owner, name, readers, editors FROM lists WHERE uid = ?", [euid.as_ref().id().as_ref()],
-        |row| {
-            let owner: EntitySQLId = row.get(0)?;
-            let readers: EntitySQLId = row.get(2)?;
-            let editors: EntitySQLId = row.get(3)?;
-            Ok(List::new(
-                euid.clone(),
-                owner.id().into(),
-                row.get(1)?,
-                tasks,
-                readers.id().into(),
-                editors.id().into(),
-            ))
+        Ok(self.get_list_without_tasks(euid)?.with_tasks(tasks))
+    }
+
+    /// Fetch a list without querying its tasks. Only the number of tasks is filled in.
+    pub fn get_list_without_tasks(&self, euid: &ListUid) -> Result<List, Error> {
+        self.get_list_row(euid, true)
+    }
+
+    // Like `get_list_without_tasks`, leaving the number of tasks at 0 unless `count_tasks` is set.
+    // Counting them means going through `tasks`, which is wasted when loading the entity,
+    // since none of its attributes depend on the tasks.
+    fn get_list_row(&self, euid: &ListUid, count_tasks: bool) -> Result<List, Error> {
+        let tenant = self.tenant_condition("tenant");
+        let total_tasks = if count_tasks { format!("(SELECT COUNT(*) FROM tasks WHERE list_uid = {LISTS_VIEW}.uid)") } else { "0".to_string() };
+        self.conn.query_row(&format!("SELECT {LIST_COLUMNS}, {total_tasks} FROM {LISTS_VIEW} WHERE uid = ?{tenant}"), [euid.as_ref().id().as_ref()],
+        |row| Ok(list_from_row(row)?.with_total_tasks(row.get::<_, i64>(11)? as usize)))
+        .optional()
+        .map_err(sql_error)?
+        .ok_or(Error::no_such_entity(euid.clone()))
+        .and_then(|list| Ok(list.with_co_owners(self.get_co_owners(euid)?)))
+    }
+
+    /// Fetch only `fields` of a list, keyed by field name.
+    /// Entity uids are rendered the same way as in a full `List`.
+    pub fn get_list_fields(&self, euid: &ListUid, fields: &[ListField]) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+        let columns = fields.iter()
+            .map(|field| match field {
+                ListField::TotalTasks => format!("(SELECT COUNT(*) FROM tasks WHERE list_uid = {LISTS_VIEW}.uid)"),
+                field => field.name().to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let uid = EntityUid::from(euid.clone());
+        let tenant = self.tenant_condition("tenant");
+        self.conn.query_row(&format!("SELECT {columns} FROM {LISTS_VIEW} WHERE uid = ?{tenant}"), [euid.as_ref().id().as_ref()], |row| {
+            let mut projection = serde_json::Map::new();
+            for (idx, field) in fields.iter().enumerate() {
+                let name = field.name();
+                let entity = |ty: &EntityTypeName| -> rusqlite::Result<serde_json::Value> {
+                    let id: EntitySQLId = decode_column(row, idx, &uid, name)?;
+                    Ok(EntityUid::from(cedar_policy::EntityUid::from_type_name_and_id(ty.clone(), id.id())).to_string().into())
+                };
+                let value = match field {
+                    ListField::Uid => uid.to_string().into(),
+                    ListField::Owner => entity(&TYPE_USER)?,
+                    ListField::Readers | ListField::Editors => entity(&TYPE_TEAM)?,
+                    ListField::Tenant => entity(&TYPE_APP)?,
+                    ListField::Name => decode_column::<String>(row, idx, &uid, name)?.into(),
+                    ListField::IsTemplate | ListField::Locked => decode_column::<bool>(row, idx, &uid, name)?.into(),
+                    ListField::PriorityThreshold | ListField::TotalTasks => decode_column::<i64>(row, idx, &uid, name)?.into(),
+                    ListField::Metadata => metadata_object(decode_column(row, idx, &uid, name)?).into(),
+                };
+                projection.insert(name.to_string(), value);
+            }
+            Ok(projection)
         })
         .optional()
-        .unwrap()
+        .map_err(sql_error)?
         .ok_or(Error::no_such_entity(euid.clone()))
     }
 
+    /// The users sharing ownership of `list`, apart from its primary owner
+    pub fn get_co_owners(&self, list: &ListUid) -> Result<Vec<UserUid>, Error> {
+        let mut stmt = self.conn.prepare("SELECT user_uid FROM owners WHERE list_uid = ? ORDER BY user_uid")?;
+        let r: Result<Vec<UserUid>, rusqlite::Error> = stmt.query_map([list.as_ref().id().as_ref()], |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(uid.id().into())
+        })?
+        .collect();
+        Ok(r?)
+    }
+
+    pub fn add_owner(&self, list: &ListUid, user: &UserUid) -> Result<(), Error> {
+        self.conn.execute("INSERT OR IGNORE INTO owners VALUES (?, ?)", [list.as_ref().id().as_ref(), user.as_ref().id().as_ref()])?;
+        Ok(())
+    }
+
+    pub fn remove_owner(&self, list: &ListUid, user: &UserUid) -> Result<(), Error> {
+        self.conn.execute("DELETE FROM owners WHERE list_uid = ? AND user_uid = ?", [list.as_ref().id().as_ref(), user.as_ref().id().as_ref()])?;
+        Ok(())
+    }
+
+    /// Fetch many lists with one query for the list rows, one for their tasks and one for their co-owners.
+    /// Lists which don't exist are skipped.
+    pub fn get_lists_by_uids(&self, uids: &[ListUid]) -> Result<Vec<List>, Error> {
+        let mut result = Vec::with_capacity(uids.len());
+        // Stay well below SQLite's limit on the number of bound parameters
+        for chunk in uids.chunks(500) {
+            let ids = chunk.iter().map(|uid| uid.as_ref().id().as_ref().to_string()).collect::<Vec<_>>();
+            let placeholders = vec!["?"; ids.len()].join(", ");
+
+            let mut tasks: HashMap<String, Vec<Task>> = HashMap::new();
+            let mut stmt = self.conn.prepare(&format!("SELECT list_uid, task_number, name, state, visible_to FROM tasks WHERE list_uid IN ({placeholders}) ORDER BY task_number"))?;
+            let mut rows = stmt.query(params_from_iter(&ids))?;
+            while let Some(row) = rows.next()? {
+                tasks.entry(row.get(0)?).or_default().push(Task::new(
+                    row.get(1)?,
+                    row.get(2)?,
+                    parse_task_state(row, 3)?
+                ).with_visible_to(row.get::<_, Option<EntitySQLId>>(4)?.map(|team| team.id().into())));
+            }
+
+            let mut co_owners: HashMap<String, Vec<UserUid>> = HashMap::new();
+            let mut stmt = self.conn.prepare(&format!("SELECT list_uid, user_uid FROM owners WHERE list_uid IN ({placeholders}) ORDER BY user_uid"))?;
+            let mut rows = stmt.query(params_from_iter(&ids))?;
+            while let Some(row) = rows.next()? {
+                let owner: EntitySQLId = row.get(1)?;
+                co_owners.entry(row.get(0)?).or_default().push(owner.id().into());
+            }
+
+            let tenant = self.tenant_condition("tenant");
+            let mut stmt = self.conn.prepare(&format!("SELECT {LIST_COLUMNS} FROM {LISTS_VIEW} WHERE uid IN ({placeholders}){tenant}"))?;
+            let lists = stmt.query_map(params_from_iter(&ids), |row| {
+                let list = list_from_row(row)?;
+                let uid = list.uid().as_ref().id().as_ref().to_string();
+                Ok(list
+                    .with_tasks(tasks.remove(&uid).unwrap_or_default())
+                    .with_co_owners(co_owners.remove(&uid).unwrap_or_default()))
+            })?
+            .collect::<Result<Vec<List>, _>>()
+            .map_err(sql_error)?;
+            result.extend(lists);
+        }
+        Ok(result)
+    }
+
+    /// Display names for `uids`: the name of each list and user.
+    /// Teams have no name of their own, so they are labelled with their id. Lists and users which don't exist are skipped.
+    pub fn get_labels(&self, uids: &[EntityUid]) -> Result<HashMap<EntityUid, String>, Error> {
+        let mut labels = HashMap::with_capacity(uids.len());
+        for (table, ty) in [("lists", &*TYPE_LIST), ("users", &*TYPE_USER)] {
+            let ids = uids.iter()
+                .filter(|uid| *uid.type_name() == *ty)
+                .map(|uid| uid.id().as_ref().to_string())
+                .collect::<Vec<_>>();
+            // Stay well below SQLite's limit on the number of bound parameters
+            for chunk in ids.chunks(500) {
+                let placeholders = vec!["?"; chunk.len()].join(", ");
+                let tenant = if table == "lists" { self.tenant_condition("tenant") } else { String::new() };
+                let mut stmt = self.conn.prepare(&format!("SELECT uid, name FROM {table} WHERE uid IN ({placeholders}){tenant}"))?;
+                let mut rows = stmt.query(params_from_iter(chunk))?;
+                while let Some(row) = rows.next()? {
+                    let uid: EntitySQLId = row.get(0)?;
+                    let uid = if table == "lists" { ListUid::from(uid.id()).into() } else { UserUid::from(uid.id()).into() };
+                    labels.insert(uid, row.get(1)?);
+                }
+            }
+        }
+        labels.extend(uids.iter()
+            .filter(|uid| *uid.type_name() == *TYPE_TEAM)
+            .map(|uid| (uid.clone(), uid.id().as_ref().to_string())));
+        Ok(labels)
+    }
+
+    /// Load `uids` in bulk so that authorizing against any of them inside `f` doesn't query the database.
+    /// `f` must not modify the lists, since it would see the stale prefetched copies.
+    /// Calls may be nested, and each one only forgets the lists it loaded.
+    pub fn with_prefetched_lists<T>(&self, uids: &[ListUid], f: impl FnOnce(&Self) -> T) -> Result<T, Error> {
+        let lists = self.get_lists_by_uids(uids)?;
+        let outer = self.prefetched_lists.borrow().clone();
+        self.prefetched_lists.borrow_mut().extend(lists.into_iter().map(|l| (l.uid().clone(), l)));
+        let result = f(self);
+        *self.prefetched_lists.borrow_mut() = outer;
+        Ok(result)
+    }
+
     pub fn get_lists(&self, query: String) -> Result<Vec<EntityUid>, Error> {
+        Ok(self.get_list_uids(query)?.into_iter().map(EntityUid::from).collect())
+    }
+
+    /// Runs `base_select` restricted by `principal_filter`, e.g. from `AppContext::authorized_lists_filter`,
+    /// so integrators can combine the authorization filter with their own joins and columns
+    pub fn run_filtered<T>(
+        &self,
+        mut base_select: SelectStatement,
+        principal_filter: Condition,
+        map: impl Fn(&Row<'_>) -> rusqlite::Result<T>,
+    ) -> Result<Vec<T>, Error> {
+        let query = base_select.cond_where(principal_filter).to_string(SqliteQueryBuilder);
         let mut query_prepared = self.conn.prepare(&query)?;
-        let r: Result<Vec<EntityUid>, rusqlite::Error> = query_prepared.query_map([], |row| {
+        let r: Result<Vec<T>, rusqlite::Error> = query_prepared.query_map([], map)?.collect();
+        Ok(r?)
+    }
+
+    /// Runs a query selecting the `uid` of lists
+    pub fn get_list_uids(&self, query: String) -> Result<Vec<ListUid>, Error> {
+        let mut query_prepared = self.conn.prepare(&query)?;
+        let r: Result<Vec<ListUid>, rusqlite::Error> = query_prepared.query_map([], |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(ListUid::from(uid.id()))
+        })?
+        .collect();
+        Ok(r?)
+    }
+
+    /// Runs a query selecting `uid, name, owner, readers, editors` from `lists`
+    pub fn get_list_summaries(&self, query: String) -> Result<Vec<ListSummary>, Error> {
+        let mut query_prepared = self.conn.prepare(&query)?;
+        let r: Result<Vec<ListSummary>, rusqlite::Error> = query_prepared.query_map([], |row| {
             let uid: EntitySQLId = row.get(0)?;
-            Ok(ListUid::from(uid.id()).into())
+            let owner: EntitySQLId = row.get(2)?;
+            let readers: EntitySQLId = row.get(3)?;
+            let editors: EntitySQLId = row.get(4)?;
+            Ok(ListSummary {
+                uid: uid.id().into(),
+                name: row.get(1)?,
+                owner: owner.id().into(),
+                readers: readers.id().into(),
+                editors: editors.id().into(),
+            })
         })?
         .collect();
         Ok(r?)
     }
 
+    pub fn get_all_lists(&self) -> Result<Vec<ListUid>, Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT uid FROM lists WHERE 1{}", self.live_lists_condition()))?;
+        let r: Result<Vec<ListUid>, rusqlite::Error> = stmt.query_map([], |row| {
+            let uid: EntitySQLId = row.get(0)?;
+            Ok(uid.id().into())
+        })?
+        .collect();
+        Ok(r?)
+    }
+
+    /// Note that `user` viewed `list` just now, forgetting their oldest views beyond `MAX_RECENT_VIEWS`
+    pub fn record_view(&self, user: &UserUid, list: &ListUid) -> Result<(), Error> {
+        let user = user.as_ref().id().as_ref();
+        self.with_transaction(|store| {
+            store.conn.execute("INSERT OR REPLACE INTO recent_views (user_uid, list_uid, viewed_at) VALUES (?, ?, ?)",
+                params![user, list.as_ref().id().as_ref(), now()])?;
+            store.conn.execute("DELETE FROM recent_views WHERE user_uid = ?1 AND list_uid NOT IN
+                (SELECT list_uid FROM recent_views WHERE user_uid = ?1 ORDER BY viewed_at DESC, ROWID DESC LIMIT ?2)",
+                params![user, MAX_RECENT_VIEWS as i64])?;
+            Ok(())
+        })
+    }
+
+    /// Lock or unlock `list`. While locked, the list and its tasks can't be changed, whatever the policies allow.
+    pub fn set_locked(&self, list: &ListUid, locked: bool) -> Result<(), Error> {
+        let num_changed = self.conn.execute("UPDATE lists SET locked = ? WHERE uid = ?", params![locked, list.as_ref().id().as_ref()])?;
+        if num_changed == 0 {
+            Err(Error::no_such_entity(list.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Every method changing a list or its tasks checks this first
+    fn ensure_unlocked(&self, list: &ListUid) -> Result<(), Error> {
+        let tenant = self.tenant_condition("tenant");
+        let locked = self.conn.query_row(&format!("SELECT locked FROM lists WHERE uid = ?{tenant}"), [list.as_ref().id().as_ref()], |row| row.get::<_, bool>(0))
+            .optional()?;
+        match locked {
+            Some(true) => Err(Error::ListLocked(list.clone().into())),
+            // Another tenant's list is out of reach, even where the statement itself isn't scoped
+            None if self.tenant_scoped => Err(Error::no_such_entity(list.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create an empty folder owned by `owner`, under the application
+    pub fn create_folder(&self, owner: &UserUid, name: &str) -> Result<FolderUid, Error> {
+        let fresh_uid = self.insert_with_fresh_uid(fresh_uuid, |uid| {
+            self.conn.execute("INSERT INTO folders (uid, name, owner) VALUES (?, ?, ?)", [uid, name, owner.as_ref().id().as_ref()])
+        })?;
+        Ok(fresh_uid.parse::<EntityId>().unwrap().into())
+    }
+
+    pub fn get_folder(&self, folder: &FolderUid) -> Result<Folder, Error> {
+        let euid = EntityUid::from(folder.clone());
+        self.conn.query_row("SELECT name, owner FROM folders WHERE uid = ?", [folder.as_ref().id().as_ref()], |row| {
+            let owner: EntitySQLId = decode_column(row, 1, &euid, "owner")?;
+            Ok(Folder::new(folder.clone(), decode_column(row, 0, &euid, "name")?, owner.id().into(), self.application.clone()))
+        })
+        .optional()
+        .map_err(sql_error)?
+        .ok_or(Error::no_such_entity(folder.clone()))
+    }
+
+    /// Put `list` in `folder`, taking it out of any folder it was in. With no folder, it's just taken out.
+    pub fn move_list_to_folder(&self, list: &ListUid, folder: Option<&FolderUid>) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        let folder_id = folder.map(|folder| folder.as_ref().id().as_ref());
+        self.with_transaction(|store| {
+            if let Some(folder) = folder {
+                store.get_folder(folder)?;
+            }
+            if store.conn.execute("UPDATE lists SET folder_uid = ? WHERE uid = ?", params![folder_id, list.as_ref().id().as_ref()])? == 0 {
+                return Err(Error::no_such_entity(list.clone()));
+            }
+            Ok(())
+        })
+    }
+
     pub fn update_list(&self, list: &ListUid, name: &str) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
         self.conn.execute("UPDATE lists SET name = ? WHERE uid = ?", &[name, list.as_ref().id().as_ref()])?;
         Ok(())
     }
 
+    pub fn set_template(&self, list: &ListUid, is_template: bool) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        self.conn.execute("UPDATE lists SET is_template = ? WHERE uid = ?", params![is_template, list.as_ref().id().as_ref()])?;
+        Ok(())
+    }
+
+    pub fn set_priority_threshold(&self, list: &ListUid, priority_threshold: i64) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        self.conn.execute("UPDATE lists SET priority_threshold = ? WHERE uid = ?", params![priority_threshold, list.as_ref().id().as_ref()])?;
+        Ok(())
+    }
+
+    pub fn set_metadata(&self, list: &ListUid, metadata: &serde_json::Value) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        self.conn.execute("UPDATE lists SET metadata = ? WHERE uid = ?", params![metadata, list.as_ref().id().as_ref()])?;
+        Ok(())
+    }
+
+    /// Mark `list` deleted. It no longer exists as far as any query is concerned,
+    /// but its rows stay until `purge_deleted` removes them.
     pub fn delete_list(&self, list: &ListUid) -> Result<(), Error> {
-        self.conn.execute("DELETE FROM lists WHERE uid = ?", &[list.as_ref().id().as_ref()])?;
+        self.ensure_unlocked(list)?;
+        self.conn.execute("DELETE FROM recent_views WHERE list_uid = ?", &[list.as_ref().id().as_ref()])?;
+        self.conn.execute("UPDATE lists SET deleted_at = ? WHERE uid = ? AND deleted_at IS NULL", params![now(), list.as_ref().id().as_ref()])?;
         Ok(())
     }
 
+    /// Remove the lists deleted at least `older_than` ago for good, with their tasks, comments and owners.
+    /// Their reader and editor teams go too, along with the teams' memberships, unless something else still
+    /// refers to a team. Returns the number of lists purged.
+    pub fn purge_deleted(&self, older_than: Duration) -> Result<usize, Error> {
+        let cutoff = now().saturating_sub(i64::try_from(older_than.as_secs()).unwrap_or(i64::MAX));
+        let tenant = self.tenant_condition("tenant");
+        self.with_transaction(|store| {
+            let lists = store.conn
+                .prepare(&format!("SELECT uid, readers, editors FROM lists WHERE deleted_at <= ?{tenant}"))?
+                .query_map([cutoff], |row| Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<EntitySQLId>>(1)?,
+                    row.get::<_, Option<EntitySQLId>>(2)?,
+                )))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for (uid, readers, editors) in &lists {
+                store.conn.execute("DELETE FROM task_comments WHERE task_rowid IN (SELECT ROWID FROM tasks WHERE list_uid = ?)", [uid])?;
+                for table in ["tasks", "owners", "recent_views"] {
+                    store.conn.execute(&format!("DELETE FROM {table} WHERE list_uid = ?"), [uid])?;
+                }
+                store.conn.execute("DELETE FROM lists WHERE uid = ?", [uid])?;
+                for team in readers.iter().chain(editors) {
+                    match store.delete_team(&team.id().into(), true) {
+                        // Still used by another list, or already gone as both the readers and the editors
+                        Ok(()) | Err(Error::TeamInUse(..)) | Err(Error::NoSuchEntity(_)) => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Ok(lists.len())
+        })
+    }
+
+    /// Add a task to `list`, returning its number within the list
     pub fn create_task(&self, list: &ListUid, name: String) -> Result<i64, Error> {
-        self.conn.execute("INSERT INTO tasks VALUES (?, ?, ?)",
-            params![name, false, list.as_ref().id().as_ref()])?;
-        Ok(self.conn.query_row("SELECT last_insert_rowid()", [], |row| row.get::<_, i64>(0))?)
+        self.ensure_unlocked(list)?;
+        let list_id = list.as_ref().id().as_ref();
+        self.with_transaction(|store| {
+            let number = store.conn.query_row("UPDATE lists SET last_task_number = last_task_number + 1 WHERE uid = ? RETURNING last_task_number",
+                [list_id], |row| row.get::<_, i64>(0))
+                .optional()?
+                .ok_or_else(|| Error::no_such_entity(list.clone()))?;
+            store.conn.execute("INSERT INTO tasks (name, state, list_uid, task_number) VALUES (?, ?, ?, ?)",
+                params![name, i64::from(TaskState::Unchecked), list_id, number])?;
+            Ok(number)
+        })
+    }
+
+    /// Whether `list` has a task called `name`, ignoring surrounding whitespace, and ASCII case if `ignore_case` is set
+    pub fn has_task_named(&self, list: &ListUid, name: &str, ignore_case: bool) -> Result<bool, Error> {
+        let collation = if ignore_case { " COLLATE NOCASE" } else { "" };
+        let exists = self.conn.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM tasks WHERE list_uid = ? AND trim(name) = trim(?){collation})"),
+            params![list.as_ref().id().as_ref(), name],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    /// Whether `owner` has a list other than `except` called `name`, ignoring surrounding whitespace
+    pub fn has_list_named(&self, owner: &UserUid, name: &str, ignore_case: bool, except: Option<&ListUid>) -> Result<bool, Error> {
+        let collation = if ignore_case { " COLLATE NOCASE" } else { "" };
+        let exists = self.conn.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM lists WHERE owner = ? AND trim(name) = trim(?){collation} AND uid IS NOT ? AND deleted_at IS NULL)"),
+            params![owner.as_ref().id().as_ref(), name, except.map(|list| list.as_ref().id().as_ref().to_string())],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
     }
 
     pub fn update_task(&self, list: &ListUid, uid: i64, new_state: TaskState) -> Result<(), Error> {
-        self.conn.execute("UPDATE tasks SET state = ? WHERE ROWID = ? AND list_uid = ?",
-            params![new_state == TaskState::Checked, uid, list.as_ref().id().as_ref()])?;
+        self.ensure_unlocked(list)?;
+        self.conn.execute("UPDATE tasks SET state = ? WHERE task_number = ? AND list_uid = ?",
+            params![i64::from(new_state), uid, list.as_ref().id().as_ref()])?;
         Ok(())
     }
 
-    pub fn delete_task(&self, list: &ListUid, uid: i64) -> Result<(), Error> {
-        let num_changed = self.conn.execute("DELETE FROM tasks WHERE ROWID = ? AND list_uid = ?", params![uid, list.as_ref().id().as_ref()])?;
+    /// Set every task in `list` to `new_state`, returning the numbers of the tasks which changed
+    pub fn set_all_task_states(&self, list: &ListUid, new_state: TaskState) -> Result<Vec<i64>, Error> {
+        self.ensure_unlocked(list)?;
+        let params = params![i64::from(new_state), list.as_ref().id().as_ref()];
+        let mut changed = if rusqlite::version_number() >= 3_035_000 {
+            let mut stmt = self.conn.prepare("UPDATE tasks SET state = ?1 WHERE list_uid = ?2 AND state != ?1 RETURNING task_number")?;
+            let r: Result<Vec<i64>, rusqlite::Error> = stmt.query_map(params, |row| row.get(0))?.collect();
+            r?
+        } else {
+            // Without `RETURNING`, find the tasks first, in the same transaction as the update
+            self.with_transaction(|store| {
+                let mut stmt = store.conn.prepare("SELECT task_number FROM tasks WHERE list_uid = ?2 AND state != ?1")?;
+                let r: Result<Vec<i64>, rusqlite::Error> = stmt.query_map(params, |row| row.get(0))?.collect();
+                store.conn.execute("UPDATE tasks SET state = ?1 WHERE list_uid = ?2 AND state != ?1", params)?;
+                Ok(r?)
+            })?
+        };
+        changed.sort_unstable();
+        Ok(changed)
+    }
+
+    pub fn rename_task(&self, list: &ListUid, uid: i64, name: &str) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        let num_changed = self.conn.execute("UPDATE tasks SET name = ? WHERE task_number = ? AND list_uid = ?", params![name, uid, list.as_ref().id().as_ref()])?;
         if num_changed == 0 {
             Err(Error::InvalidTaskId(list.clone().into(), uid))
         } else {
             Ok(())
         }
     }
+
+    /// Restrict who can see a task to `team`, or let everyone who can see the list see it if `None`
+    pub fn set_task_visibility(&self, list: &ListUid, uid: i64, team: Option<&TeamUid>) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        let team = team.map(|team| team.as_ref().id().as_ref().to_string());
+        let num_changed = self.conn.execute("UPDATE tasks SET visible_to = ? WHERE task_number = ? AND list_uid = ?", params![team, uid, list.as_ref().id().as_ref()])?;
+        if num_changed == 0 {
+            Err(Error::InvalidTaskId(list.clone().into(), uid))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn delete_task(&self, list: &ListUid, uid: i64) -> Result<(), Error> {
+        self.ensure_unlocked(list)?;
+        self.with_transaction(|store| {
+            store.conn.execute("DELETE FROM task_comments WHERE task_rowid IN (SELECT ROWID FROM tasks WHERE task_number = ? AND list_uid = ?)",
+                params![uid, list.as_ref().id().as_ref()])?;
+            let num_changed = store.conn.execute("DELETE FROM tasks WHERE task_number = ? AND list_uid = ?", params![uid, list.as_ref().id().as_ref()])?;
+            if num_changed == 0 {
+                Err(Error::InvalidTaskId(list.clone().into(), uid))
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// The team a task is restricted to, if any. Fails if `list` has no task numbered `uid`.
+    pub fn get_task_visibility(&self, list: &ListUid, uid: i64) -> Result<Option<TeamUid>, Error> {
+        self.conn.query_row("SELECT visible_to FROM tasks WHERE task_number = ? AND list_uid = ?", params![uid, list.as_ref().id().as_ref()],
+            |row| Ok(row.get::<_, Option<EntitySQLId>>(0)?.map(|team| team.id().into())))
+            .optional()?
+            .ok_or_else(|| Error::InvalidTaskId(list.clone().into(), uid))
+    }
+
+    /// Add a comment by `author` to a task, returning the comment's id
+    pub fn add_task_comment(&self, list: &ListUid, uid: i64, author: &UserUid, body: &str) -> Result<i64, Error> {
+        self.ensure_unlocked(list)?;
+        let num_changed = self.conn.execute("INSERT INTO task_comments (task_rowid, author_uid, body, created_at)
+            SELECT ROWID, ?, ?, ? FROM tasks WHERE task_number = ? AND list_uid = ?",
+            params![author.as_ref().id().as_ref(), body, now(), uid, list.as_ref().id().as_ref()])?;
+        if num_changed == 0 {
+            Err(Error::InvalidTaskId(list.clone().into(), uid))
+        } else {
+            Ok(self.conn.last_insert_rowid())
+        }
+    }
+
+    /// The comments on a task, oldest first
+    pub fn get_task_comments(&self, list: &ListUid, uid: i64) -> Result<Vec<TaskComment>, Error> {
+        let mut stmt = self.conn.prepare("SELECT task_comments.ROWID, author_uid, body, created_at FROM task_comments
+            JOIN tasks ON tasks.ROWID = task_comments.task_rowid
+            WHERE tasks.task_number = ? AND tasks.list_uid = ?
+            ORDER BY created_at, task_comments.ROWID")?;
+        let r: Result<Vec<TaskComment>, rusqlite::Error> = stmt.query_map(params![uid, list.as_ref().id().as_ref()], |row| {
+            let author: EntitySQLId = row.get(1)?;
+            Ok(TaskComment {
+                id: row.get(0)?,
+                author: author.id().into(),
+                body: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        })?
+        .collect();
+        Ok(r?)
+    }
+}
+
+fn fresh_uuid() -> String {
+    Uuid::new_v4().to_string()
+}
+
+// The columns `list_from_row` expects, in order
+const LIST_COLUMNS: &str = "uid, owner, name, readers, editors, is_template, priority_threshold, metadata, tenant, locked, folder_uid";
+
+// Decode a row starting with `LIST_COLUMNS`, leaving out the tasks and co-owners
+fn list_from_row(row: &Row<'_>) -> rusqlite::Result<List> {
+    let uid: EntitySQLId = row.get(0)?;
+    let uid: ListUid = uid.id().into();
+    let euid: EntityUid = uid.clone().into();
+    let owner: EntitySQLId = decode_column(row, 1, &euid, "owner")?;
+    let readers: EntitySQLId = decode_column(row, 3, &euid, "readers")?;
+    let editors: EntitySQLId = decode_column(row, 4, &euid, "editors")?;
+    let tenant: EntitySQLId = decode_column(row, 8, &euid, "tenant")?;
+    let folder: Option<EntitySQLId> = decode_column(row, 10, &euid, "folder_uid")?;
+    Ok(List::new(
+        uid,
+        owner.id().into(),
+        decode_column(row, 2, &euid, "name")?,
+        vec![],
+        readers.id().into(),
+        editors.id().into(),
+    )
+    .with_template(decode_column(row, 5, &euid, "is_template")?)
+    .with_priority_threshold(decode_column(row, 6, &euid, "priority_threshold")?)
+    .with_metadata(metadata_object(decode_column(row, 7, &euid, "metadata")?))
+    .with_locked(decode_column(row, 9, &euid, "locked")?)
+    .with_folder(folder.map(|folder| folder.id().into()))
+    .with_tenant(cedar_policy::EntityUid::from_type_name_and_id(TYPE_APP.clone(), tenant.id()).into()))
+}
+
+// Read column `idx`, which holds attribute `field` of `uid`. If the value can't be converted,
+// the error names the entity, the attribute and the value, and `sql_error` surfaces it.
+fn decode_column<T: FromSql>(row: &Row<'_>, idx: usize, uid: &EntityUid, field: &'static str) -> rusqlite::Result<T> {
+    row.get(idx).map_err(|e| match e {
+        rusqlite::Error::InvalidColumnType(..)
+        | rusqlite::Error::FromSqlConversionFailure(..)
+        | rusqlite::Error::IntegralValueOutOfRange(..) => {
+            let value = row.get_ref(idx).unwrap_or(ValueRef::Null);
+            let expected = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default();
+            let error = EntityDecodeError::WrongType {
+                uid: Some(uid.clone()),
+                field,
+                expected,
+                got: describe_value(value),
+            };
+            rusqlite::Error::FromSqlConversionFailure(idx, value.data_type(), Box::new(error))
+        }
+        e => e,
+    })
+}
+
+// Parse the text in column `idx`, e.g. an entity uid stored with `to_string`
+fn parse_column<T>(row: &Row<'_>, idx: usize) -> rusqlite::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    row.get::<_, String>(idx)?.parse().map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+// Read a decision stored with `decision_str`
+fn parse_decision(row: &Row<'_>, idx: usize) -> rusqlite::Result<Decision> {
+    match row.get::<_, String>(idx)?.as_str() {
+        "allow" => Ok(Decision::Allow),
+        "deny" => Ok(Decision::Deny),
+        got => {
+            let error = EntityDecodeError::BadEnum { enumeration: "Decision", got: got.to_string() };
+            Err(rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(error)))
+        }
+    }
+}
+
+// Read a task state stored as `i64::from(state)`
+fn parse_task_state(row: &Row<'_>, idx: usize) -> rusqlite::Result<TaskState> {
+    TaskState::try_from(row.get::<_, i64>(idx)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Integer, Box::new(e)))
+}
+
+fn describe_value(value: ValueRef<'_>) -> String {
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(t) => format!("{:?}", String::from_utf8_lossy(t)),
+        ValueRef::Blob(b) => format!("a blob of {} bytes", b.len()),
+    }
+}
+
+// Unwrap the `EntityDecodeError`s produced by `decode_column`
+fn sql_error(e: rusqlite::Error) -> Error {
+    match e {
+        rusqlite::Error::FromSqlConversionFailure(idx, ty, inner) => match inner.downcast::<EntityDecodeError>() {
+            Ok(decode) => Error::EntityDecode(*decode),
+            Err(inner) => Error::SQLError(rusqlite::Error::FromSqlConversionFailure(idx, ty, inner)),
+        },
+        e => Error::SQLError(e),
+    }
+}
+
+// Only JSON objects are ever written to `lists.metadata`
+fn metadata_object(value: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+    match value {
+        serde_json::Value::Object(m) => m,
+        _ => serde_json::Map::new(),
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -194,15 +1597,24 @@ pub enum EntityDecodeError {
     MissingAttr(&'static str),
     #[error("Evaluation Failed: {0}")]
     Eval(#[from] EvaluationError),
-    #[error("Field {0} was wrong typed. Expected {0}")]
-    WrongType(&'static str, &'static str),
-    #[error("Enum was not one of required fields. Enum{enumeration}, Got {got}")]
+    #[error("Field `{field}`{} was wrong typed. Expected {expected}, got {got}", of_entity(.uid))]
+    WrongType {
+        uid: Option<EntityUid>,
+        field: &'static str,
+        expected: &'static str,
+        got: String,
+    },
+    #[error("Enum was not one of required fields. Enum {enumeration}, Got {got}")]
     BadEnum {
         enumeration: &'static str,
         got: String,
     },
 }
 
+fn of_entity(uid: &Option<EntityUid>) -> String {
+    uid.as_ref().map(|uid| format!(" of {uid}")).unwrap_or_default()
+}
+
 #[cfg(test)]
 mod test {
     use cedar_policy::{Authorizer, PolicySet, Response, Request, Context};
@@ -252,4 +1664,703 @@ mod test {
             "List::\"bhDbo6AjP613Lccz\"".parse().unwrap()));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_fresh_database() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        // Bootstrapping twice must not duplicate anything
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let memberships: i64 = store.conn.query_row("SELECT COUNT(*) FROM team_memberships", [], |row| row.get(0)).unwrap();
+        assert_eq!(memberships, 1);
+
+        // Teams are found transitively through subteams
+        let team = |name: &str| -> TeamUid { name.parse::<EntityId>().unwrap().into() };
+        store.add_subteam(&team("admin"), &team("temp")).unwrap();
+        let mut teams = store.get_user_teams(&owner).unwrap();
+        teams.sort_by_key(|t| t.as_ref().to_string());
+        assert_eq!(teams, vec![team("admin"), team("temp")]);
+        let closure: i64 = store.conn.query_row("SELECT COUNT(*) FROM team_membership_closure WHERE user_uid = 'alice'", [], |row| row.get(0)).unwrap();
+        assert_eq!(closure, 2);
+        store.ensure_exists(&team("admin").into()).unwrap();
+        let typo: UserUid = "alcie".parse::<EntityId>().unwrap().into();
+        assert!(matches!(store.ensure_exists(&typo.clone().into()), Err(Error::NoSuchEntity(_))));
+        assert!(!store.exists(typo.as_ref()).unwrap());
+        assert!(store.exists(&APPLICATION_TINY_TODO).unwrap());
+
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner.clone(), "Groceries", readers, editors).unwrap();
+        store.create_task(&list, "Milk".into()).unwrap();
+
+        let fetched = store.get_list(&list).unwrap();
+        assert_eq!(fetched.get_name(), "Groceries");
+        assert_eq!(fetched.get_owner(), &owner);
+        assert_eq!(fetched.get_tasks().len(), 1);
+        let without_tasks = store.get_list_without_tasks(&list).unwrap();
+        assert!(without_tasks.get_tasks().is_empty());
+        assert_eq!(without_tasks.get_total_tasks(), 1);
+        assert_eq!(fetched.get_priority_threshold(), 0);
+        assert_eq!(fetched.get_tenant(), &*APPLICATION_TINY_TODO);
+        let acme: EntityUid = r#"Application::"Acme""#.parse().unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let acme_list = store.create_list_in(&acme, owner.clone(), "Payroll", readers, editors).unwrap();
+        assert_eq!(store.get_list(&acme_list).unwrap().get_tenant(), &acme);
+
+        store.set_priority_threshold(&list, 5).unwrap();
+        assert_eq!(store.get_list(&list).unwrap().get_priority_threshold(), 5);
+
+        assert!(fetched.get_metadata().is_empty());
+        store.set_metadata(&list, &serde_json::json!({"color": "red"})).unwrap();
+        assert_eq!(store.get_list(&list).unwrap().get_metadata()["color"], "red");
+
+        let bob: UserUid = "bob".parse::<EntityId>().unwrap().into();
+        store.conn.execute("INSERT INTO users VALUES ('bob', 'Bob')", []).unwrap();
+        store.add_owner(&list, &bob).unwrap();
+        store.add_owner(&list, &bob).unwrap();
+        assert_eq!(store.get_list(&list).unwrap().get_co_owners(), &vec![bob.clone()]);
+        assert_eq!(store.get_lists_by_uids(std::slice::from_ref(&list)).unwrap()[0].get_co_owners(), &vec![bob.clone()]);
+        store.remove_owner(&list, &bob).unwrap();
+        assert!(store.get_list(&list).unwrap().get_co_owners().is_empty());
+    }
+
+    #[test]
+    fn test_subteam_cycles() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        store.bootstrap_defaults(None).unwrap();
+        let team = |name: &str| -> TeamUid { name.parse::<EntityId>().unwrap().into() };
+
+        // interns is already a subteam of temp
+        assert!(matches!(store.add_subteam(&team("temp"), &team("interns")), Err(Error::WouldCreateCycle(..))));
+        assert!(matches!(store.add_subteam(&team("admin"), &team("admin")), Err(Error::WouldCreateCycle(..))));
+        store.add_subteam(&team("temp"), &team("admin")).unwrap();
+        assert!(matches!(store.add_subteam(&team("admin"), &team("interns")), Err(Error::WouldCreateCycle(..))));
+    }
+
+    #[test]
+    fn test_membership_cache() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap()).with_membership_cache();
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let team = |name: &str| -> TeamUid { name.parse::<EntityId>().unwrap().into() };
+        let closure = |teams: &[&str]| -> HashSet<cedar_policy::EntityUid> {
+            teams.iter().map(|name| EntityUid::from(team(name)).into()).collect()
+        };
+        assert_eq!(store.membership_closure(&owner).unwrap(), closure(&["admin"]));
+
+        // Changing the hierarchy through the store drops the cached closure
+        store.add_subteam(&team("admin"), &team("temp")).unwrap();
+        assert_eq!(store.membership_closure(&owner).unwrap(), closure(&["admin", "temp"]));
+
+        // A rolled back change is forgotten too
+        store.begin_transaction().unwrap();
+        store.add_team_member(&owner, &team("interns")).unwrap();
+        assert_eq!(store.membership_closure(&owner).unwrap(), closure(&["admin", "temp", "interns"]));
+        store.end_transaction(false).unwrap();
+        assert_eq!(store.membership_closure(&owner).unwrap(), closure(&["admin", "temp"]));
+    }
+
+    #[test]
+    fn test_purge_deleted() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        let reader: UserUid = "bob".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        store.insert_user(&reader, "Bob").unwrap();
+        let readers = store.create_team().unwrap();
+        let list = store.create_list(owner.clone(), "Groceries", readers.clone(), store.create_team().unwrap()).unwrap();
+        let milk = store.create_task(&list, "Milk".to_string()).unwrap();
+        store.add_task_comment(&list, milk, &owner, "Oat").unwrap();
+        store.add_owner(&list, &reader).unwrap();
+        store.add_team_member(&reader, &readers).unwrap();
+        let count = |table: &str| store.conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| row.get::<_, i64>(0)).unwrap();
+
+        // A deleted list is gone as far as queries go, but its rows are kept
+        store.delete_list(&list).unwrap();
+        assert!(store.get(list.as_ref()).unwrap().is_none());
+        assert!(!store.exists(list.as_ref()).unwrap());
+        assert!(store.get_all_lists().unwrap().is_empty());
+        assert_eq!(store.count_owned_lists(&owner).unwrap(), 0);
+        assert!(!store.has_list_named(&owner, "Groceries", false, None).unwrap());
+        assert_eq!((count("lists"), count("tasks"), count("task_comments")), (1, 1, 1));
+
+        assert_eq!(store.purge_deleted(Duration::from_secs(60 * 60)).unwrap(), 0);
+        assert_eq!(store.purge_deleted(Duration::ZERO).unwrap(), 1);
+        assert_eq!((count("lists"), count("tasks"), count("task_comments"), count("owners")), (0, 0, 0, 0));
+        assert!(!store.exists(&readers.clone().into()).unwrap());
+        assert!(store.get_user_teams(&reader).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_eager_task_loading() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let list = store.create_list(owner, "Groceries", store.create_team().unwrap(), store.create_team().unwrap()).unwrap();
+        store.create_task(&list, "Milk".to_string()).unwrap();
+        let entity = store.get(list.as_ref()).unwrap().unwrap();
+        assert!(entity.get("tasks").is_none());
+
+        let store = store.with_eager_task_loading();
+        let entity = store.get(list.as_ref()).unwrap().unwrap();
+        assert!(entity.get("tasks").is_some());
+        let prefetched = store.with_prefetched_lists(std::slice::from_ref(&list), |store| store.get(list.as_ref()).unwrap().unwrap().get("tasks").is_some());
+        assert!(prefetched.unwrap());
+
+        // Lazily, the list's tasks aren't even read, so a bad one can't get in the way
+        store.conn.execute("UPDATE tasks SET state = 99", []).unwrap();
+        assert!(store.get(list.as_ref()).unwrap().is_none());
+        let store = EntityStore { eager_task_loading: false, ..store };
+        assert!(store.get(list.as_ref()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_add_team_member() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+
+        // Sharing twice with the same user leaves one membership
+        store.add_team_member(&owner, &readers).unwrap();
+        store.add_team_member(&owner, &readers).unwrap();
+        let members: i64 = store.conn.query_row("SELECT COUNT(*) FROM team_memberships WHERE team_uid = ?",
+            [readers.as_ref().id().as_ref()], |row| row.get(0)).unwrap();
+        assert_eq!(members, 1);
+        assert!(store.get_user_teams(&owner).unwrap().contains(&readers));
+    }
+
+    #[test]
+    fn test_remove_team_member() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        store.add_team_member(&owner, &readers).unwrap();
+
+        // Unsharing removes the membership, and unsharing again is a no-op
+        store.remove_team_member(&owner, &readers).unwrap();
+        assert!(!store.get_user_teams(&owner).unwrap().contains(&readers));
+        store.remove_team_member(&owner, &readers).unwrap();
+        assert!(!store.get_user_teams(&owner).unwrap().contains(&readers));
+    }
+
+    #[test]
+    fn test_delete_team() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let admin: TeamUid = "admin".parse::<EntityId>().unwrap().into();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        store.create_list(owner.clone(), "Groceries", readers.clone(), editors).unwrap();
+        store.add_subteam(&admin, &readers).unwrap();
+
+        assert!(matches!(store.delete_team(&readers, true), Err(Error::TeamInUse(_, "a list"))));
+        assert!(matches!(store.delete_team(&admin, false), Err(Error::TeamInUse(_, "its members"))));
+        store.delete_team(&admin, true).unwrap();
+        assert!(store.get_user_teams(&owner).unwrap().is_empty());
+        let edges: i64 = store.conn.query_row("SELECT COUNT(*) FROM subteams WHERE child_team = 'admin'", [], |row| row.get(0)).unwrap();
+        assert_eq!(edges, 0);
+        assert!(matches!(store.delete_team(&admin, true), Err(Error::NoSuchEntity(_))));
+    }
+
+    #[test]
+    fn test_uid_collision() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        store.bootstrap_defaults(None).unwrap();
+        let insert = |uid: &str| store.conn.execute("INSERT INTO teams VALUES (?)", [uid]);
+
+        // The first uid collides with a default team, so the second one is used
+        let mut uids = ["admin", "fresh"].into_iter().map(String::from);
+        assert_eq!(store.insert_with_fresh_uid(|| uids.next().unwrap(), insert).unwrap(), "fresh");
+
+        // Give up rather than retrying forever
+        assert!(matches!(store.insert_with_fresh_uid(|| "admin".to_string(), insert), Err(Error::SQLError(_))));
+    }
+
+    #[test]
+    fn test_get_lists_by_uids() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let mut lists = vec![];
+        for name in ["Groceries", "Chores"] {
+            let readers = store.create_team().unwrap();
+            let editors = store.create_team().unwrap();
+            let list = store.create_list(owner.clone(), name, readers, editors.clone()).unwrap();
+            store.create_task(&list, format!("{name} 1")).unwrap();
+            let hidden = store.create_task(&list, format!("{name} 2")).unwrap();
+            store.set_task_visibility(&list, hidden, Some(&editors)).unwrap();
+            lists.push(list);
+        }
+        let folder = store.create_folder(&owner, "Home").unwrap();
+        store.move_list_to_folder(&lists[0], Some(&folder)).unwrap();
+        lists.push("missing".parse::<EntityId>().unwrap().into());
+
+        let fetched = store.get_lists_by_uids(&lists).unwrap();
+        assert_eq!(fetched.len(), 2);
+        for list in fetched {
+            let expected = store.get_list(list.uid()).unwrap();
+            assert_eq!(list.get_name(), expected.get_name());
+            assert_eq!(list.get_tasks(), expected.get_tasks());
+            assert_eq!(list.get_folder(), expected.get_folder());
+            assert!(expected.get_tasks()[1].get_visible_to().is_some());
+        }
+        let entity = store.get(lists[0].as_ref()).unwrap().unwrap();
+        assert!(entity.ancestors().any(|parent| *parent == EntityUid::from(folder.clone()).0));
+
+        // A nested prefetch only forgets its own lists
+        store.with_prefetched_lists(&lists[..1], |store| {
+            store.with_prefetched_lists(&lists[1..], |store| assert_eq!(store.prefetched_lists.borrow().len(), 2)).unwrap();
+            assert_eq!(store.prefetched_lists.borrow().len(), 1);
+        }).unwrap();
+        assert!(store.prefetched_lists.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_folders() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner.clone(), "Groceries", readers, editors).unwrap();
+        let folder = store.create_folder(&owner, "Home").unwrap();
+        assert_eq!(store.get_folder(&folder).unwrap().get_owner(), &owner);
+
+        store.move_list_to_folder(&list, Some(&folder)).unwrap();
+        let fetched = store.get_list(&list).unwrap();
+        assert_eq!(fetched.get_folder(), Some(&folder));
+        let entity = ParsedEntity::from(fetched);
+        assert!(entity.ancestors().any(|parent| *parent == folder.as_ref().0));
+        assert_eq!(store.get_lists_by_uids(std::slice::from_ref(&list)).unwrap()[0].get_folder(), Some(&folder));
+
+        let missing: FolderUid = "nowhere".parse::<EntityId>().unwrap().into();
+        assert!(matches!(store.move_list_to_folder(&list, Some(&missing)), Err(Error::NoSuchEntity(_))));
+        store.move_list_to_folder(&list, None).unwrap();
+        assert_eq!(store.get_list(&list).unwrap().get_folder(), None);
+    }
+
+    #[test]
+    fn test_task_numbers() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let new_list = || {
+            let readers = store.create_team().unwrap();
+            let editors = store.create_team().unwrap();
+            store.create_list(owner.clone(), "Groceries", readers, editors).unwrap()
+        };
+        let (groceries, chores) = (new_list(), new_list());
+
+        assert_eq!(store.create_task(&groceries, "Milk".to_string()).unwrap(), 1);
+        assert_eq!(store.create_task(&chores, "Dishes".to_string()).unwrap(), 1);
+        assert_eq!(store.create_task(&groceries, "Eggs".to_string()).unwrap(), 2);
+        store.delete_task(&groceries, 2).unwrap();
+        // Numbers aren't reused
+        assert_eq!(store.create_task(&groceries, "Bread".to_string()).unwrap(), 3);
+        store.rename_task(&groceries, 3, "Rye bread").unwrap();
+        store.update_task(&groceries, 3, TaskState::InProgress).unwrap();
+        assert_eq!(store.set_all_task_states(&chores, TaskState::Checked).unwrap(), [1]);
+        assert!(store.set_all_task_states(&chores, TaskState::Checked).unwrap().is_empty());
+
+        let tasks = store.get_list(&groceries).unwrap().get_tasks().iter()
+            .map(|t| t.get_name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(tasks, ["Milk", "Rye bread"]);
+        let states = store.get_list(&groceries).unwrap().get_tasks().iter().map(Task::get_state).collect::<Vec<_>>();
+        assert_eq!(states, [TaskState::Unchecked, TaskState::InProgress]);
+        assert!(matches!(store.delete_task(&chores, 2), Err(Error::InvalidTaskId(_, 2))));
+    }
+
+    #[test]
+    fn test_application_root() {
+        let acme: EntityUid = r#"Application::"Acme""#.parse().unwrap();
+        let store = EntityStore::new(Connection::open_in_memory().unwrap()).with_application(acme.clone());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+
+        let application = store.get(&acme.0).unwrap().unwrap();
+        assert_eq!(application.uid(), acme.0);
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        assert_eq!(store.get_list(&list).unwrap().get_tenant(), &acme);
+    }
+
+    #[test]
+    fn test_list_fields() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        store.create_task(&list, "Milk".to_string()).unwrap();
+
+        let fields = store.get_list_fields(&list, &[ListField::Name, ListField::Owner, ListField::TotalTasks]).unwrap();
+        assert_eq!(serde_json::Value::Object(fields), serde_json::json!({
+            "name": "Groceries",
+            "owner": r#"User::"alice""#,
+            "total_tasks": 1,
+        }));
+    }
+
+    #[test]
+    fn test_task_comments() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner.clone(), "Groceries", readers, editors).unwrap();
+        let milk = store.create_task(&list, "Milk".to_string()).unwrap();
+        let eggs = store.create_task(&list, "Eggs".to_string()).unwrap();
+
+        let first = store.add_task_comment(&list, milk, &owner, "Oat or dairy?").unwrap();
+        let second = store.add_task_comment(&list, milk, &owner, "Oat").unwrap();
+        store.add_task_comment(&list, eggs, &owner, "A dozen").unwrap();
+        let comments = store.get_task_comments(&list, milk).unwrap();
+        assert_eq!(comments.iter().map(|c| c.id).collect::<Vec<_>>(), [first, second]);
+        assert_eq!(comments[1].body, "Oat");
+        assert_eq!(comments[1].author, owner);
+        assert!(matches!(store.add_task_comment(&list, 3, &owner, "?"), Err(Error::InvalidTaskId(_, 3))));
+
+        // Comments go with their task
+        store.delete_task(&list, milk).unwrap();
+        assert!(store.get_task_comments(&list, milk).unwrap().is_empty());
+        assert_eq!(store.get_task_comments(&list, eggs).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_locked_list() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        let task = store.create_task(&list, "Milk".to_string()).unwrap();
+
+        store.set_locked(&list, true).unwrap();
+        assert!(store.get_list(&list).unwrap().is_locked());
+        assert!(matches!(store.create_task(&list, "Eggs".to_string()), Err(Error::ListLocked(_))));
+        assert!(matches!(store.update_task(&list, task, TaskState::Checked), Err(Error::ListLocked(_))));
+        assert!(matches!(store.update_list(&list, "Shopping"), Err(Error::ListLocked(_))));
+        assert!(matches!(store.delete_list(&list), Err(Error::ListLocked(_))));
+
+        store.set_locked(&list, false).unwrap();
+        store.update_list(&list, "Shopping").unwrap();
+        assert_eq!(store.get_list(&list).unwrap().get_name(), "Shopping");
+    }
+
+    #[test]
+    fn test_has_task_named() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        store.create_task(&list, "Milk".to_string()).unwrap();
+
+        assert!(store.has_task_named(&list, " Milk ", false).unwrap());
+        assert!(!store.has_task_named(&list, "milk", false).unwrap());
+        assert!(store.has_task_named(&list, "milk", true).unwrap());
+        assert!(!store.has_task_named(&list, "Eggs", true).unwrap());
+    }
+
+    #[test]
+    fn test_stats() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let before = store.stats().unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        store.create_task(&list, "Milk".to_string()).unwrap();
+        store.create_task(&list, "Eggs".to_string()).unwrap();
+
+        let after = store.stats().unwrap();
+        assert_eq!(after.users, before.users);
+        assert_eq!(after.teams, before.teams + 2);
+        assert_eq!(after.lists, before.lists + 1);
+        assert_eq!(after.tasks, before.tasks + 2);
+    }
+
+    #[test]
+    fn test_get_tasks_in() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let first = store.create_list(owner.clone(), "Groceries", readers.clone(), editors.clone()).unwrap();
+        let second = store.create_list(owner, "Chores", readers, editors).unwrap();
+        store.create_task(&second, "Sweep".to_string()).unwrap();
+        store.create_task(&first, "Milk".to_string()).unwrap();
+        store.create_task(&first, "Eggs".to_string()).unwrap();
+
+        let only_first = Condition::all().add(Expr::col((Alias::new("tasks"), Alias::new("list_uid"))).eq(first.as_ref().id().as_ref()));
+        let (tasks, total) = store.get_tasks_in(only_first, 0, 10).unwrap();
+        let names = tasks.iter().map(|(list, task)| (list.clone(), task.get_name().to_string())).collect::<Vec<_>>();
+        assert_eq!(names, vec![(first.clone(), "Milk".to_string()), (first, "Eggs".to_string())]);
+        assert_eq!(total, 2);
+        let (page, total) = store.get_tasks_in(Condition::all(), 1, 1).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn test_audit_events() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let alice: EntityUid = r#"User::"alice""#.parse().unwrap();
+        let bob: EntityUid = r#"User::"bob""#.parse().unwrap();
+        let action: EntityUid = r#"Action::"GetList""#.parse().unwrap();
+        for (principal, decision) in [(&alice, Decision::Allow), (&bob, Decision::Allow), (&alice, Decision::Deny)] {
+            store.record_audit(&AuditEvent::new(principal, &action, &*APPLICATION_TINY_TODO, decision).impersonated_by(Some(&bob))).unwrap();
+        }
+
+        let (events, total) = store.get_audit_events(&alice, 0, 1).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].decision, Decision::Deny);
+        assert_eq!(events[0].impersonator.as_ref(), Some(&bob));
+        let (events, _) = store.get_audit_events(&alice, 1, 10).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].decision, Decision::Allow);
+
+        for _ in 0..MAX_AUDIT_PAGE {
+            store.record_audit(&AuditEvent::new(&alice, &action, &*APPLICATION_TINY_TODO, Decision::Allow)).unwrap();
+        }
+        let (events, total) = store.get_audit_events(&alice, 0, usize::MAX).unwrap();
+        assert_eq!(total, MAX_AUDIT_PAGE + 2);
+        assert_eq!(events.len(), MAX_AUDIT_PAGE);
+    }
+
+    #[test]
+    fn test_tuning() {
+        let tuning = SqliteTuning { cache_size: Some(-4096), mmap_size: Some(0) };
+        let store = EntityStore::new(Connection::open_in_memory().unwrap()).with_tuning(tuning).unwrap();
+        let cache_size: i64 = store.conn.pragma_query_value(None, "cache_size", |row| row.get(0)).unwrap();
+        assert_eq!(cache_size, -4096);
+        let too_large = SqliteTuning { mmap_size: Some(u64::MAX), ..tuning };
+        assert!(EntityStore::new(Connection::open_in_memory().unwrap()).with_tuning(too_large).is_err());
+    }
+
+    #[test]
+    fn test_read_only() {
+        // Shared-cache in-memory databases ignore the read-only flag, so this needs a real file
+        let path = std::env::temp_dir().join(format!("tinytodo-read-only-{}.db", std::process::id()));
+        let writer = EntityStore::from_file(&path);
+        writer.initialize_schema().unwrap();
+        writer.bootstrap_defaults(None).unwrap();
+        let team = writer.create_team().unwrap();
+
+        let reader = EntityStore::open_read_only(&path).unwrap();
+        let read = reader.ensure_exists(&team.into());
+        let writes = [reader.create_team().map(|_| ()), reader.conn.execute("DELETE FROM teams", []).map(|_| ()).map_err(Error::from)];
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_ok());
+        assert!(writes.iter().all(Result::is_err));
+    }
+
+    #[test]
+    fn test_attribute_defaults() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        // A database created elsewhere may allow NULL names
+        store.conn.execute_batch("CREATE TABLE lists (uid text PRIMARY KEY, owner REFERENCES users, name text, readers REFERENCES teams, editors REFERENCES teams);").unwrap();
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        store.conn.execute("UPDATE lists SET name = NULL", []).unwrap();
+
+        assert_eq!(store.get_list(&list).unwrap().get_name(), "");
+        assert_eq!(store.get_lists_by_uids(&[list]).unwrap()[0].get_name(), "");
+    }
+
+    #[test]
+    fn test_attribute_defaults_on_replica() {
+        let path = std::env::temp_dir().join(format!("tinytodo-replica-defaults-{}.db", std::process::id()));
+        let writer = EntityStore::from_file(&path);
+        writer.conn.execute_batch("CREATE TABLE users (uid text PRIMARY KEY, name text);").unwrap();
+        writer.initialize_schema().unwrap();
+        writer.conn.execute_batch("INSERT INTO users VALUES ('alice', NULL); DROP VIEW users_with_defaults;").unwrap();
+
+        let reader = EntityStore::open_read_only(&path).unwrap()
+            .with_attribute_defaults(vec![AttributeDefault::new("users", "name", "'Anonymous'")]);
+        reader.create_defaults_views().unwrap();
+        let name: String = reader.conn.query_row("SELECT name FROM users_with_defaults WHERE uid = 'alice'", [], |row| row.get(0)).unwrap();
+        drop((reader, writer));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(name, "Anonymous");
+    }
+
+    #[test]
+    fn test_decode_error_names_entity() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = store.create_team().unwrap();
+        let editors = store.create_team().unwrap();
+        let list = store.create_list(owner, "Groceries", readers, editors).unwrap();
+        store.conn.execute("UPDATE lists SET is_template = 'yes'", []).unwrap();
+
+        let message = store.get_list(&list).unwrap_err().to_string();
+        assert!(message.contains("is_template"), "{message}");
+        assert!(message.contains(&list.as_ref().to_string()), "{message}");
+        assert!(message.contains("\"yes\""), "{message}");
+    }
+
+    #[test]
+    fn test_bulk_import() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+
+        let tasks = vec![("Milk".to_string(), TaskState::Unchecked), ("Eggs".to_string(), TaskState::Checked)];
+        let lists = store.bulk_import(vec![
+            BulkList { owner: owner.clone(), name: "Groceries".into(), tasks },
+            BulkList { owner: owner.clone(), name: "Empty".into(), tasks: vec![] },
+        ], |_, _, _| Ok(())).unwrap();
+        assert_eq!(lists.len(), 2);
+        let groceries = store.get_list(&lists[0]).unwrap();
+        assert_eq!(groceries.get_name(), "Groceries");
+        assert_eq!(groceries.get_tasks().len(), 2);
+        assert_eq!(store.count_owned_lists(&owner).unwrap(), 2);
+        // Numbering carries on after the imported tasks
+        assert_eq!(store.create_task(&lists[0], "Bread".into()).unwrap(), 3);
+        assert_eq!(store.create_task(&lists[1], "Anything".into()).unwrap(), 1);
+        let indexes: i64 = store.conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name IN ('lists_by_owner', 'task_comments_by_task')", [], |row| row.get(0)).unwrap();
+        assert_eq!(indexes, 2);
+
+        // A missing owner fails the whole import
+        let nobody: UserUid = "nobody".parse::<EntityId>().unwrap().into();
+        let result = store.bulk_import(vec![
+            BulkList { owner: owner.clone(), name: "Fine".into(), tasks: vec![] },
+            BulkList { owner: nobody, name: "Orphan".into(), tasks: vec![] },
+        ], |_, _, _| Ok(()));
+        assert!(result.is_err());
+        assert_eq!(store.count_owned_lists(&owner).unwrap(), 2);
+
+        store.begin_transaction().unwrap();
+        let result = store.bulk_import(vec![BulkList { owner: owner.clone(), name: "Nested".into(), tasks: vec![] }], |_, _, _| Ok(()));
+        assert!(matches!(result, Err(Error::InvalidInput(_))));
+        store.end_transaction(false).unwrap();
+    }
+
+    #[test]
+    fn test_recent_views() {
+        let store = EntityStore::new(Connection::open_in_memory().unwrap());
+        store.initialize_schema().unwrap();
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        store.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let lists = store.bulk_import((0..MAX_RECENT_VIEWS + 2)
+            .map(|i| BulkList { owner: owner.clone(), name: format!("List {i}"), tasks: vec![] })
+            .collect(), |_, _, _| Ok(())).unwrap();
+        for list in &lists {
+            store.record_view(&owner, list).unwrap();
+        }
+        // Viewing a list again moves it to the front rather than adding another row
+        store.record_view(&owner, &lists[5]).unwrap();
+        let recent = || -> Vec<String> {
+            let mut stmt = store.conn.prepare("SELECT list_uid FROM recent_views WHERE user_uid = 'alice' ORDER BY viewed_at DESC, ROWID DESC").unwrap();
+            stmt.query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+        };
+        let id = |list: &ListUid| list.as_ref().id().as_ref().to_string();
+        assert_eq!(recent().len(), MAX_RECENT_VIEWS);
+        assert_eq!(recent()[0], id(&lists[5]));
+        assert!(!recent().contains(&id(&lists[0])));
+
+        store.delete_list(&lists[5]).unwrap();
+        assert!(!recent().contains(&id(&lists[5])));
+    }
+
+    #[test]
+    fn test_checkpoint_wal() {
+        let dir = std::env::temp_dir().join(format!("tinytodo-wal-{}", fresh_uuid()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = EntityStore::from_file(dir.join("entities.db"));
+        store.conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+        store.initialize_schema().unwrap();
+        store.bootstrap_defaults(None).unwrap();
+        assert!(std::fs::metadata(dir.join("entities.db-wal")).unwrap().len() > 0);
+        store.checkpoint_wal().unwrap();
+        assert_eq!(std::fs::metadata(dir.join("entities.db-wal")).unwrap().len(), 0);
+
+        store.begin_transaction().unwrap();
+        assert!(matches!(store.checkpoint_wal(), Err(Error::InvalidInput(_))));
+        store.end_transaction(false).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_connection() {
+        let shared = SharedConnection::new(EntityStore::new(Connection::open_in_memory().unwrap())).unwrap();
+        let acme: EntityUid = r#"Application::"Acme""#.parse().unwrap();
+        let mut tiny_todo = shared.store_for(APPLICATION_TINY_TODO.clone());
+        let mut acme_store = shared.store_for(acme.clone());
+        assert_eq!(acme_store.tenant_scope(), Some(&acme));
+
+        tiny_todo.check_out().await;
+        let owner: UserUid = "alice".parse::<EntityId>().unwrap().into();
+        tiny_todo.bootstrap_defaults(Some((&owner, "Alice"))).unwrap();
+        let readers = tiny_todo.create_team().unwrap();
+        let editors = tiny_todo.create_team().unwrap();
+        let list = tiny_todo.create_list(owner.clone(), "Groceries", readers.clone(), editors.clone()).unwrap();
+        assert_eq!(tiny_todo.get_all_lists().unwrap(), vec![list.clone()]);
+        tiny_todo.check_in(true);
+
+        acme_store.check_out().await;
+        let data_version = acme_store.data_version().unwrap();
+        let acme_list = acme_store.create_list(owner.clone(), "Payroll", readers, editors).unwrap();
+        assert_eq!(acme_store.get_all_lists().unwrap(), vec![acme_list.clone()]);
+        assert!(matches!(acme_store.get_list(&list), Err(Error::NoSuchEntity(_))));
+        assert!(matches!(acme_store.update_list(&list, "Mine now"), Err(Error::NoSuchEntity(_))));
+        assert!(!acme_store.exists(list.as_ref()).unwrap());
+        assert_eq!(acme_store.count_owned_lists(&owner).unwrap(), 1);
+        acme_store.check_in(true);
+
+        // The other tenant's write is noticed, though it went through the same connection
+        tiny_todo.check_out().await;
+        assert_ne!(tiny_todo.data_version().unwrap(), data_version);
+        assert_eq!(tiny_todo.get_list(&list).unwrap().get_name(), "Groceries");
+        assert_eq!(tiny_todo.get_lists_by_uids(&[list.clone(), acme_list]).unwrap().len(), 1);
+        tiny_todo.check_in(false);
+    }
+
+    #[test]
+    #[should_panic(expected = "without being checked out")]
+    fn test_shared_connection_needs_check_out() {
+        let shared = SharedConnection::new(EntityStore::new(Connection::open_in_memory().unwrap())).unwrap();
+        let _ = shared.store_for(APPLICATION_TINY_TODO.clone()).get_all_lists();
+    }
+}
@@ -0,0 +1,36 @@
+/*
+ * Copyright 2022-2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Debug;
+
+use cedar_policy::Decision;
+
+use crate::{context::Error, util::EntityUid};
+
+/// Code run around every authorization check, for concerns like rate limiting or feature flags
+/// which apply to all handlers alike. Register hooks in `AppConfig::authorization_hooks`; they run
+/// in that order. Like audit sinks, hooks are called from the application thread, so they shouldn't block.
+pub trait AuthorizationHook: Debug + Send {
+    /// Called before the policies are consulted. An error, e.g. `Error::Busy` from a rate limiter,
+    /// fails the check with that error, without deciding or auditing the request.
+    fn before_authorize(&self, _principal: &EntityUid, _action: &EntityUid, _resource: &EntityUid) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called with the policies' decision, before it is audited. `fail_open` is set when the decision
+    /// is a denial which `AppConfig::fail_open_actions` is about to override.
+    fn after_authorize(&self, _principal: &EntityUid, _action: &EntityUid, _resource: &EntityUid, _decision: Decision, _fail_open: bool) {}
+}
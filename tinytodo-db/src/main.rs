@@ -14,15 +14,27 @@
  * limitations under the License.
  */
 
+// The HTTP routes are one deeply nested warp filter
+#![recursion_limit = "256"]
+
 mod api;
+mod audit;
+mod config;
 mod context;
+mod encoding;
 mod entitystore;
+mod hooks;
 mod objects;
 mod policy_store;
+mod rpc;
+mod stats;
 mod util;
 
+use api::ShareRole;
+use audit::{ChannelAuditSink, DbAuditSink};
+use config::{AppConfig, LogRedaction};
 use context::AppContext;
-use std::num::ParseIntError;
+use std::{num::ParseIntError, time::Duration};
 use thiserror::Error;
 use tracing::Level;
 
@@ -33,13 +45,39 @@ async fn main() {
 
     let entities_file = args.get(2).map(String::as_str).unwrap_or("./huge_entities.db");
 
+    let config = match get_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
     let app = AppContext::spawn(
         entities_file,
         "./tinytodo.cedarschema.json",
         "./policies.cedar",
+        config,
     )
     .unwrap();
 
+    match get_rpc_port() {
+        Ok(Some(port)) => {
+            let app = app.clone();
+            let token = std::env::var("TINYTODO_RPC_TOKEN").ok();
+            tokio::spawn(async move {
+                if let Err(e) = rpc::serve_rpc(app, port, token).await {
+                    eprintln!("JSON-RPC server stopped: {e}");
+                }
+            });
+        }
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("Bad TINYTODO_RPC_PORT: {e}");
+            std::process::exit(1);
+        }
+    }
+
     match get_port(&args) {
         Ok(port) => crate::api::serve_api(app, port).await,
         Err(e) => {
@@ -73,6 +111,16 @@ fn init_logger() {
 enum ArgError {
     #[error("Couldn't parse port number. Expected a valid integer port number. {0}")]
     Parse(#[from] ParseIntError),
+    #[error("Couldn't parse action `{0}` in TINYTODO_FAIL_OPEN_ACTIONS. Expected a comma separated list of entity uids.")]
+    FailOpenAction(String),
+    #[error("Unknown audit sink `{0}` in TINYTODO_AUDIT_SINK. Expected `db` or `stdout`.")]
+    AuditSink(String),
+    #[error("Unknown redaction `{0}` in TINYTODO_LOG_REDACTION. Expected `none`, `hash` or `redact`.")]
+    LogRedaction(String),
+    #[error("Unknown role `{0}` in TINYTODO_DEFAULT_SHARE_ROLE. Expected `reader` or `editor`.")]
+    ShareRole(String),
+    #[error("Couldn't parse `{0}` in TINYTODO_APPLICATION. Expected an application id like `TinyTodo`.")]
+    Application(String),
 }
 
 fn get_port(args: &[String]) -> Result<u16, ArgError> {
@@ -80,3 +128,112 @@ fn get_port(args: &[String]) -> Result<u16, ArgError> {
     let port: u16 = arg.parse()?;
     Ok(port)
 }
+
+// The JSON-RPC interface is only served if `TINYTODO_RPC_PORT` is set,
+// and only runs operator commands that carry `TINYTODO_RPC_TOKEN`
+fn get_rpc_port() -> Result<Option<u16>, ArgError> {
+    match std::env::var("TINYTODO_RPC_PORT") {
+        Ok(var) => Ok(Some(var.parse()?)),
+        Err(_) => Ok(None),
+    }
+}
+
+// `TINYTODO_FAIL_OPEN_ACTIONS` lets an operator temporarily allow actions during a policy migration
+fn get_config() -> Result<AppConfig, ArgError> {
+    let mut config = AppConfig::default();
+    if let Ok(var) = std::env::var("TINYTODO_FAIL_OPEN_ACTIONS") {
+        for action in var.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            let euid = action
+                .parse()
+                .map_err(|_| ArgError::FailOpenAction(action.to_string()))?;
+            config.fail_open_actions.insert(euid);
+        }
+    }
+    if let Ok(var) = std::env::var("TINYTODO_MAX_QUERY_COMPLEXITY") {
+        config.max_query_complexity = var.parse()?;
+    }
+    if let Ok(var) = std::env::var("TINYTODO_MAX_NAME_LENGTH") {
+        config.max_name_length = var.parse()?;
+    }
+    // `TINYTODO_READ_ONLY` serves a read replica, which can't hold the audit log
+    if std::env::var("TINYTODO_READ_ONLY").is_ok_and(|var| var == "1" || var == "true") {
+        config.read_only = true;
+        config.audit_sink = Box::new(ChannelAuditSink::stdout());
+    }
+    // `TINYTODO_AUDIT_SINK` picks where authorization decisions are recorded
+    if let Ok(var) = std::env::var("TINYTODO_AUDIT_SINK") {
+        config.audit_sink = match var.as_str() {
+            "db" => Box::new(DbAuditSink),
+            "stdout" => Box::new(ChannelAuditSink::stdout()),
+            _ => return Err(ArgError::AuditSink(var)),
+        };
+    }
+    if let Ok(var) = std::env::var("TINYTODO_AUDIT_TASK_FILTERING") {
+        config.audit_task_filtering = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_SQLITE_CACHE_SIZE") {
+        config.sqlite.cache_size = Some(var.parse()?);
+    }
+    if let Ok(var) = std::env::var("TINYTODO_SQLITE_MMAP_SIZE") {
+        config.sqlite.mmap_size = Some(var.parse()?);
+    }
+    if let Ok(var) = std::env::var("TINYTODO_DECISION_CACHE_TTL_MS") {
+        config.decision_cache_ttl = Some(Duration::from_millis(var.parse()?));
+    }
+    if let Ok(var) = std::env::var("TINYTODO_DENY_CACHE_TTL_MS") {
+        config.deny_cache_ttl = Some(Duration::from_millis(var.parse()?));
+    }
+    if let Ok(var) = std::env::var("TINYTODO_WAL_CHECKPOINT_INTERVAL_MS") {
+        config.wal_checkpoint_interval = Some(Duration::from_millis(var.parse()?));
+    }
+    if let Ok(var) = std::env::var("TINYTODO_DELETED_LIST_RETENTION_MS") {
+        config.deleted_list_retention = Some(Duration::from_millis(var.parse()?));
+    }
+    if let Ok(var) = std::env::var("TINYTODO_CASE_INSENSITIVE_NAMES") {
+        config.case_insensitive_names = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_DEDUP_TASK_NAMES") {
+        config.dedup_task_names = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_UNIQUE_LIST_NAMES") {
+        config.unique_list_names = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_REPORT_MISSING_RESOURCES") {
+        config.report_missing_resources = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_SHARE_CONNECTION") {
+        config.share_connection = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_MEMBERSHIP_CACHE") {
+        config.membership_cache = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_EAGER_TASK_LOADING") {
+        config.eager_task_loading = var == "1" || var == "true";
+    }
+    if let Ok(var) = std::env::var("TINYTODO_REQUEST_RETRIES") {
+        config.request_retries = var.parse()?;
+    }
+    // `TINYTODO_APPLICATION` names the root application, so several apps can share the binary
+    if let Ok(var) = std::env::var("TINYTODO_APPLICATION") {
+        config.application = format!("Application::{var:?}")
+            .parse()
+            .map_err(|_| ArgError::Application(var))?;
+    }
+    if let Ok(var) = std::env::var("TINYTODO_DEFAULT_SHARE_ROLE") {
+        config.default_share_role = match var.to_lowercase().as_str() {
+            "reader" => ShareRole::Reader,
+            "editor" => ShareRole::Editor,
+            _ => return Err(ArgError::ShareRole(var)),
+        };
+    }
+    // `TINYTODO_LOG_REDACTION` keeps user ids out of production logs
+    if let Ok(var) = std::env::var("TINYTODO_LOG_REDACTION") {
+        config.log_redaction = match var.as_str() {
+            "none" => LogRedaction::None,
+            "hash" => LogRedaction::Hash,
+            "redact" => LogRedaction::Redact,
+            _ => return Err(ArgError::LogRedaction(var)),
+        };
+    }
+    Ok(config)
+}
@@ -18,12 +18,13 @@ use std::collections::{HashMap, HashSet};
 
 use cedar_policy::{Entity, EvalResult, ParsedEntity, PartialValue, Value};
 use serde::{Deserialize, Serialize};
+use serde_json::Map;
 
 use crate::{
     api::ShareRole,
     context::APPLICATION_TINY_TODO,
     entitystore::EntityDecodeError,
-    util::{EntityUid, ListUid, TeamUid, UserUid},
+    util::{fnv1a, EntityUid, FolderUid, ListUid, TeamUid, UserUid},
 };
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -32,6 +33,10 @@ pub struct Application {
 }
 
 impl Application {
+    pub fn new(euid: EntityUid) -> Self {
+        Self { euid }
+    }
+
     pub fn euid(&self) -> &EntityUid {
         &self.euid
     }
@@ -81,11 +86,10 @@ impl User {
         &self.euid
     }
 
-    pub fn new(euid: UserUid) -> Self {
-        let parent = Application::default().euid().clone();
+    pub fn new(euid: UserUid, application: EntityUid) -> Self {
         Self {
             euid,
-            parents: [parent].into_iter().collect(),
+            parents: [application].into_iter().collect(),
         }
     }
 }
@@ -118,11 +122,10 @@ pub struct Team {
 }
 
 impl Team {
-    pub fn new(euid: TeamUid) -> Team {
-        let parent = Application::default().euid().clone();
+    pub fn new(euid: TeamUid, application: EntityUid) -> Team {
         Self {
             uid: euid,
-            parents: [parent].into_iter().collect(),
+            parents: [application].into_iter().collect(),
         }
     }
 
@@ -158,8 +161,33 @@ pub struct List {
     owner: UserUid,
     name: String,
     tasks: Vec<Task>, // Invariant, `tasks` must be sorted
+    // Equal to `tasks.len()`, unless the list was fetched without its tasks
+    #[serde(default)]
+    total_tasks: usize,
     readers: TeamUid,
     editors: TeamUid,
+    // Users with the same rights as `owner`, which remains the primary owner
+    #[serde(default)]
+    co_owners: Vec<UserUid>,
+    // The application the list belongs to, which is its parent entity
+    #[serde(default = "default_tenant")]
+    tenant: EntityUid,
+    // The folder holding the list, which is another parent entity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    folder: Option<FolderUid>,
+    #[serde(default)]
+    is_template: bool,
+    #[serde(default)]
+    priority_threshold: i64,
+    // A locked list and its tasks can be read, but not changed
+    #[serde(default)]
+    locked: bool,
+    // Opaque to Cedar: display data for clients, like a color
+    #[serde(default)]
+    metadata: Map<String, serde_json::Value>,
+    // Hash of everything above, set by `with_version`, for use as an ETag
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    version: String,
 }
 
 impl List {
@@ -168,24 +196,113 @@ impl List {
             uid,
             owner,
             name,
+            total_tasks: tasks.len(),
             tasks,
             readers,
             editors,
+            co_owners: vec![],
+            tenant: default_tenant(),
+            folder: None,
+            is_template: false,
+            priority_threshold: 0,
+            locked: false,
+            metadata: Map::new(),
+            version: String::new(),
         }
     }
 
+    pub fn with_template(self, is_template: bool) -> Self {
+        Self { is_template, ..self }
+    }
+
+    pub fn with_tasks(self, tasks: Vec<Task>) -> Self {
+        Self { total_tasks: tasks.len(), tasks, ..self }
+    }
+
+    pub fn with_co_owners(self, co_owners: Vec<UserUid>) -> Self {
+        Self { co_owners, ..self }
+    }
+
+    pub fn with_tenant(self, tenant: EntityUid) -> Self {
+        Self { tenant, ..self }
+    }
+
+    pub fn with_folder(self, folder: Option<FolderUid>) -> Self {
+        Self { folder, ..self }
+    }
+
+    pub fn with_total_tasks(self, total_tasks: usize) -> Self {
+        Self { total_tasks, ..self }
+    }
+
+    pub fn with_priority_threshold(self, priority_threshold: i64) -> Self {
+        Self { priority_threshold, ..self }
+    }
+
+    pub fn with_metadata(self, metadata: Map<String, serde_json::Value>) -> Self {
+        Self { metadata, ..self }
+    }
+
+    pub fn with_locked(self, locked: bool) -> Self {
+        Self { locked, ..self }
+    }
+
+    /// Compute `version`, which changes whenever any other part of the list does,
+    /// and stays the same across server restarts
+    pub fn with_version(self) -> Self {
+        let unversioned = Self { version: String::new(), ..self };
+        let json = serde_json::to_vec(&unversioned).expect("Lists are always serializable");
+        Self { version: format!("{:016x}", fnv1a(&json)), ..unversioned }
+    }
+
+    pub fn get_version(&self) -> &str {
+        &self.version
+    }
+
     pub fn get_name(&self) -> &str {
         &self.name
     }
 
+    pub fn is_template(&self) -> bool {
+        self.is_template
+    }
+
+    pub fn get_priority_threshold(&self) -> i64 {
+        self.priority_threshold
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn get_metadata(&self) -> &Map<String, serde_json::Value> {
+        &self.metadata
+    }
+
     pub fn get_owner(&self) -> &UserUid {
         &self.owner
     }
 
+    pub fn get_tenant(&self) -> &EntityUid {
+        &self.tenant
+    }
+
+    pub fn get_co_owners(&self) -> &Vec<UserUid> {
+        &self.co_owners
+    }
+
+    pub fn get_folder(&self) -> Option<&FolderUid> {
+        self.folder.as_ref()
+    }
+
     pub fn get_tasks(&self) -> &Vec<Task> {
         &self.tasks
     }
 
+    pub fn get_total_tasks(&self) -> usize {
+        self.total_tasks
+    }
+
     pub fn get_readers(&self) -> &TeamUid {
         &self.readers
     }
@@ -221,47 +338,216 @@ impl List {
             ShareRole::Editor => &self.editors,
         }
     }
+
+    /// Render as a Markdown checklist, headed by the list's name
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!("# {}\n\n", self.name);
+        for task in &self.tasks {
+            let mark = match task.state {
+                TaskState::Checked => 'x',
+                TaskState::InProgress => '~',
+                TaskState::Unchecked => ' ',
+            };
+            md.push_str(&format!("- [{mark}] {}\n", task.name));
+        }
+        md
+    }
+
+    /// Render the name and tasks as JSON, leaving out everything specific to this server
+    pub fn to_export_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "tasks": self.tasks.iter().map(|t| serde_json::json!({
+                "name": t.name,
+                "state": t.state,
+            })).collect::<Vec<_>>(),
+        })
+    }
 }
 
-impl From<List> for ParsedEntity {
-    fn from(value: List) -> Self {
+fn default_tenant() -> EntityUid {
+    APPLICATION_TINY_TODO.clone()
+}
+
+/// The attributes of a list which `GetList::fields` can select
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListField {
+    Uid,
+    Owner,
+    Name,
+    Readers,
+    Editors,
+    Tenant,
+    IsTemplate,
+    PriorityThreshold,
+    Locked,
+    Metadata,
+    TotalTasks,
+}
+
+impl ListField {
+    pub const ALL: [ListField; 11] = [
+        ListField::Uid,
+        ListField::Owner,
+        ListField::Name,
+        ListField::Readers,
+        ListField::Editors,
+        ListField::Tenant,
+        ListField::IsTemplate,
+        ListField::PriorityThreshold,
+        ListField::Locked,
+        ListField::Metadata,
+        ListField::TotalTasks,
+    ];
+
+    /// The field's name, as serialized in a full `List`
+    pub fn name(self) -> &'static str {
+        match self {
+            ListField::Uid => "uid",
+            ListField::Owner => "owner",
+            ListField::Name => "name",
+            ListField::Readers => "readers",
+            ListField::Editors => "editors",
+            ListField::Tenant => "tenant",
+            ListField::IsTemplate => "is_template",
+            ListField::PriorityThreshold => "priority_threshold",
+            ListField::Locked => "locked",
+            ListField::Metadata => "metadata",
+            ListField::TotalTasks => "total_tasks",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name() == name)
+    }
+}
+
+/// A folder lists can be moved into. Policies can grant access to every list in it
+/// with `resource in Folder::"..."`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Folder {
+    uid: FolderUid,
+    name: String,
+    owner: UserUid,
+    application: EntityUid,
+}
+
+impl Folder {
+    pub fn new(uid: FolderUid, name: String, owner: UserUid, application: EntityUid) -> Self {
+        Self { uid, name, owner, application }
+    }
+
+    pub fn uid(&self) -> &FolderUid {
+        &self.uid
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_owner(&self) -> &UserUid {
+        &self.owner
+    }
+}
+
+impl From<Folder> for ParsedEntity {
+    fn from(value: Folder) -> Self {
         let attrs: HashMap<String, PartialValue> = [
-            (
-                "owner",
-                EntityUid::from(value.owner).0.into()
-            ),
+            ("owner", EntityUid::from(value.owner).0.into()),
             ("name", PartialValue::Value(Value::Lit(value.name.into()))),
-            (
-                "readers",
-                EntityUid::from(value.readers).0.into(),
-            ),
-            (
-                "editors",
-                EntityUid::from(value.editors).0.into(),
-            ),
         ]
         .into_iter()
         .map(|(x, v)| (x.into(), v))
         .collect();
-
         let euid: EntityUid = value.uid.into();
+        ParsedEntity::new(euid.into(), attrs, [value.application.into()].into_iter().collect())
+    }
+}
 
+/// A list without its tasks, along with the teams it is shared through
+#[derive(Debug, Clone, Serialize)]
+pub struct ListSummary {
+    pub uid: ListUid,
+    pub name: String,
+    pub owner: UserUid,
+    pub readers: TeamUid,
+    pub editors: TeamUid,
+}
 
-        // We always have the single parent of the application and the list itself,
-        // so we just hard code that here
-        let parents = [APPLICATION_TINY_TODO.clone().into(), euid.clone().into()]
-            .into_iter()
-            .collect::<HashSet<_>>();
+impl List {
+    /// The list's entity along with its `tasks` attribute, which converting with `From` leaves out.
+    /// The tasks must have been loaded, as by `EntityStore::get_list`.
+    pub fn into_entity_with_tasks(self) -> ParsedEntity {
+        let tasks = Value::set(self.tasks.iter().map(|task| Value::record([
+            ("name", Value::Lit(task.name.clone().into())),
+            ("id", Value::Lit(task.id.into())),
+            ("state", Value::Lit(task.state.to_string().into())),
+        ])));
+        list_entity(self, Some(tasks))
+    }
+}
+
+impl From<List> for ParsedEntity {
+    fn from(value: List) -> Self {
+        list_entity(value, None)
+    }
+}
 
-        ParsedEntity::new(euid.into(), attrs, parents)
+fn list_entity(value: List, tasks: Option<Value>) -> ParsedEntity {
+    // The primary owner is included, so policies only need to check `owners`
+    let owners = Value::set(
+        std::iter::once(value.owner.clone())
+            .chain(value.co_owners)
+            .map(|owner| EntityUid::from(owner).0.into()),
+    );
+    let mut attrs: HashMap<String, PartialValue> = [
+        (
+            "owner",
+            EntityUid::from(value.owner).0.into()
+        ),
+        ("owners", PartialValue::Value(owners)),
+        ("name", PartialValue::Value(Value::Lit(value.name.into()))),
+        ("is_template", PartialValue::Value(Value::Lit(value.is_template.into()))),
+        ("priority_threshold", PartialValue::Value(Value::Lit(value.priority_threshold.into()))),
+        (
+            "readers",
+            EntityUid::from(value.readers).0.into(),
+        ),
+        (
+            "editors",
+            EntityUid::from(value.editors).0.into(),
+        ),
+    ]
+    .into_iter()
+    .map(|(x, v)| (x.into(), v))
+    .collect();
+    if let Some(tasks) = tasks {
+        attrs.insert("tasks".into(), PartialValue::Value(tasks));
     }
+
+    let euid: EntityUid = value.uid.into();
+
+
+    // A list is always under its application, and in itself, as well as in its folder if it has one
+    let parents = [value.tenant, euid.clone()]
+        .into_iter()
+        .chain(value.folder.map(EntityUid::from))
+        .map(Into::into)
+        .collect::<HashSet<_>>();
+
+    ParsedEntity::new(euid.into(), attrs, parents)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Task {
+    // The task's number within its list, starting from 1
     id: i64,
     name: String,
     state: TaskState,
+    // Only members of this team (and whoever else policy lets `ViewTask`) can see the task.
+    // `None` means anyone who can see the list can see the task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    visible_to: Option<TeamUid>,
 }
 
 impl Task {
@@ -270,9 +556,26 @@ impl Task {
             id,
             name,
             state,
+            visible_to: None,
         }
     }
 
+    pub fn with_visible_to(self, visible_to: Option<TeamUid>) -> Self {
+        Self { visible_to, ..self }
+    }
+
+    pub fn get_visible_to(&self) -> Option<&TeamUid> {
+        self.visible_to.as_ref()
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_state(&self) -> TaskState {
+        self.state
+    }
+
     pub fn set_name(&mut self, new: String) {
         self.name = new;
     }
@@ -282,6 +585,16 @@ impl Task {
     }
 }
 
+/// A comment in the discussion on a task
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskComment {
+    pub id: i64,
+    pub author: UserUid,
+    pub body: String,
+    /// Seconds since the unix epoch
+    pub created_at: i64,
+}
+
 impl PartialOrd for Task {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         self.id.partial_cmp(&other.id)
@@ -294,9 +607,41 @@ impl Ord for Task {
     }
 }
 
+/// Parse the checkbox lines (`- [ ] name`, `- [~] name` or `- [x] name`) of a Markdown checklist.
+/// Blank lines and headings are ignored; the number of other lines that couldn't be parsed is returned too.
+pub fn parse_markdown_checklist(md: &str) -> (Vec<(String, TaskState)>, usize) {
+    let mut tasks = vec![];
+    let mut skipped = 0;
+    for line in md.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let item = line.strip_prefix("- ").or_else(|| line.strip_prefix("* "));
+        let task = item.and_then(|item| {
+            let (state, name) = if let Some(name) = item.strip_prefix("[ ]") {
+                (TaskState::Unchecked, name)
+            } else if let Some(name) = item.strip_prefix("[~]") {
+                (TaskState::InProgress, name)
+            } else if let Some(name) = item.strip_prefix("[x]").or_else(|| item.strip_prefix("[X]")) {
+                (TaskState::Checked, name)
+            } else {
+                return None;
+            };
+            let name = name.trim();
+            (!name.is_empty()).then(|| (name.to_string(), state))
+        });
+        match task {
+            Some(task) => tasks.push(task),
+            None => skipped += 1,
+        }
+    }
+    (tasks, skipped)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TaskState {
     Checked,
+    InProgress,
     Unchecked,
 }
 
@@ -304,11 +649,39 @@ impl std::fmt::Display for TaskState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TaskState::Checked => write!(f, "checked"),
+            TaskState::InProgress => write!(f, "in_progress"),
             TaskState::Unchecked => write!(f, "unchecked"),
         }
     }
 }
 
+// Tasks used to be stored as a bool, so `Unchecked` and `Checked` keep the values 0 and 1
+impl From<TaskState> for i64 {
+    fn from(value: TaskState) -> Self {
+        match value {
+            TaskState::Unchecked => 0,
+            TaskState::Checked => 1,
+            TaskState::InProgress => 2,
+        }
+    }
+}
+
+impl TryFrom<i64> for TaskState {
+    type Error = EntityDecodeError;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TaskState::Unchecked),
+            1 => Ok(TaskState::Checked),
+            2 => Ok(TaskState::InProgress),
+            _ => Err(EntityDecodeError::BadEnum {
+                enumeration: "TaskState",
+                got: value.to_string(),
+            }),
+        }
+    }
+}
+
 impl From<bool> for TaskState {
     fn from(value: bool) -> Self {
         if value {
@@ -326,13 +699,59 @@ impl TryFrom<&EvalResult> for TaskState {
         match value {
             EvalResult::String(s) => match s.as_str() {
                 "checked" => Ok(TaskState::Checked),
+                "in_progress" => Ok(TaskState::InProgress),
                 "unchecked" => Ok(TaskState::Unchecked),
                 _ => Err(EntityDecodeError::BadEnum {
                     enumeration: "TaskState",
                     got: s.clone(),
                 }),
             },
-            _ => Err(EntityDecodeError::WrongType("state", "String")),
+            _ => Err(EntityDecodeError::WrongType {
+                uid: None,
+                field: "state",
+                expected: "String",
+                got: format!("{value:?}"),
+            }),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_markdown_checklist() {
+        let md = "# Groceries\n\n- [ ] Milk\n- [x] Eggs\n* [X] Bread\n- [~] Flour\nnot a task\n- [?] Butter\n- [ ]\n";
+        let (tasks, skipped) = parse_markdown_checklist(md);
+        assert_eq!(
+            tasks,
+            vec![
+                ("Milk".to_string(), TaskState::Unchecked),
+                ("Eggs".to_string(), TaskState::Checked),
+                ("Bread".to_string(), TaskState::Checked),
+                ("Flour".to_string(), TaskState::InProgress),
+            ]
+        );
+        assert_eq!(skipped, 3);
+    }
+
+    #[test]
+    fn test_list_version() {
+        let uid = |id: &str| -> EntityUid { format!("Team::\"{id}\"").parse().unwrap() };
+        let list = List::new(
+            r#"List::"0""#.parse::<EntityUid>().unwrap().try_into().unwrap(),
+            r#"User::"alice""#.parse::<EntityUid>().unwrap().try_into().unwrap(),
+            "Groceries".to_string(),
+            vec![Task::new(1, "Milk".to_string(), TaskState::Unchecked)],
+            uid("readers").try_into().unwrap(),
+            uid("editors").try_into().unwrap(),
+        );
+        let version = list.clone().with_version().get_version().to_string();
+        assert_eq!(version.len(), 16);
+        // Recomputing the version of a versioned list must not change it
+        assert_eq!(list.clone().with_version().with_version().get_version(), version);
+        let renamed = List { name: "Errands".to_string(), ..list };
+        assert_ne!(renamed.with_version().get_version(), version);
+    }
+}
@@ -16,6 +16,7 @@
 
 use std::{
     fmt::Display,
+    io::ErrorKind,
     path::{Path, PathBuf},
     time::{Duration, SystemTime},
 };
@@ -23,10 +24,16 @@ use std::{
 use cedar_policy::{ParseErrors, PolicySet, Schema, SchemaError, ValidationError, Validator};
 use thiserror::Error;
 use tokio::sync::mpsc::Sender;
-use tracing::{debug, error};
+use tracing::{debug, error, info};
 
 use crate::context::{AppQuery, AppQueryKind};
 
+// Changes to the policies file are coalesced until it has stopped changing for this long,
+// so editors that write several times (or write then rename) trigger a single reload
+const DEBOUNCE: Duration = Duration::from_millis(500);
+// A failed reload is retried in case we read the file while it was only partially written
+const RELOAD_ATTEMPTS: usize = 3;
+
 #[derive(Debug, Clone)]
 struct PolicySetWatcher {
     policy_set: PathBuf,
@@ -95,6 +102,7 @@ async fn watcher_supervisor(w: PolicySetWatcher) {
             },
             Err(e) => error!("Join Error: {e}"),
         }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
 
@@ -104,22 +112,55 @@ async fn watcher(w: PolicySetWatcher) -> Result<Empty> {
     let mut last_modified = get_last_modified(&w.policy_set).await?;
     loop {
         tokio::time::sleep(Duration::from_secs(1)).await;
-        let time = get_last_modified(&w.policy_set).await?;
+        let time = match get_last_modified(&w.policy_set).await {
+            Ok(time) => time,
+            // The file is briefly missing while an editor replaces it
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
         if time != last_modified {
-            last_modified = time;
-            match attempt_policy_reload(&w).await {
+            last_modified = wait_until_stable(&w.policy_set, time).await?;
+            match reload_with_retry(&w).await {
                 Ok(policies) => {
                     send_query(policies, &w.tx).await?;
+                    info!("Reloaded policies from {}", w.policy_set.display());
                 }
-                Err(e) => error!("Error reloading policies: {e}"),
+                Err(e) => error!("Error reloading policies, keeping the current policy set: {e}"),
             };
         }
     }
 }
 
+// Wait until the modification time stops changing, returning the final one
+async fn wait_until_stable(path: &Path, mut time: SystemTime) -> Result<SystemTime> {
+    loop {
+        tokio::time::sleep(DEBOUNCE).await;
+        match get_last_modified(path).await {
+            Ok(t) if t == time => return Ok(t),
+            Ok(t) => time = t,
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+async fn reload_with_retry(w: &PolicySetWatcher) -> Result<PolicySet> {
+    let mut attempt = 1;
+    loop {
+        match attempt_policy_reload(w).await {
+            Err(e) if attempt < RELOAD_ATTEMPTS => {
+                debug!("Reload attempt {attempt} failed: {e}, retrying...");
+                attempt += 1;
+                tokio::time::sleep(DEBOUNCE).await;
+            }
+            r => return r,
+        }
+    }
+}
+
 async fn send_query(p: PolicySet, tx: &Sender<AppQuery>) -> Result<()> {
     let (send, recv) = tokio::sync::oneshot::channel();
-    let query = AppQuery::new(AppQueryKind::UpdatePolicySet(p), send);
+    let query = AppQuery::new(AppQueryKind::UpdatePolicySet(p.into()), send);
     tx.send(query).await?;
     let _ = recv.await?;
     Ok(())
@@ -0,0 +1,238 @@
+/*
+ * Copyright 2022-2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// A line-delimited JSON-RPC 2.0 interface to the application server.
+// Each line is a request such as
+//   {"jsonrpc": "2.0", "id": 1, "method": "GetList", "params": {"uid": "User::\"kesha\"", "list": "List::\"l0\""}}
+// where `method` names an `AppQueryKind` variant and `params` is its payload.
+// An optional `on_behalf_of` user uid runs the request as that user, see `AppContext::impersonate`.
+// A request without an `id` is a notification: it is queued without waiting for it to run, and gets no response.
+// An explicit `"id": null` is not a notification, and is answered with a null `id`.
+// Queries made by a user are authorized as that user, as over HTTP. Operator commands like `UpdatePolicySet`
+// (whose params are Cedar policy source text) must also carry the `token` the server was started with,
+// `TINYTODO_RPC_TOKEN`, and are refused if it isn't set. The socket is only bound to localhost either way.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+use crate::{
+    api::{send_query, send_query_detached, AppChannel},
+    context::{AppQueryKind, AppResponse},
+    util::UserUid,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    // `None` for a notification. `"id": null` is `Some(None)`, a request like any other.
+    #[serde(default, deserialize_with = "deserialize_id")]
+    id: Option<Option<Value>>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    on_behalf_of: Option<UserUid>,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+fn deserialize_id<'de, D>(d: D) -> Result<Option<Option<Value>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Value>::deserialize(d).map(Some)
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<AppResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcResponse {
+    fn result(id: Value, result: AppResponse) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, code: i64, message: impl ToString) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(RpcError {
+                code,
+                message: message.to_string(),
+            }),
+        }
+    }
+}
+
+pub async fn serve_rpc(chan: AppChannel, port: u16, token: Option<String>) -> std::io::Result<()> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    let listener = TcpListener::bind(socket).await?;
+    info!("Serving JSON-RPC on {socket}");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let chan = chan.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, chan, token.as_deref()).await {
+                debug!("JSON-RPC connection from {peer} closed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, chan: AppChannel, token: Option<&str>) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(response) = dispatch(&chan, &line, token).await else {
+            continue;
+        };
+        let mut out = serde_json::to_string(&response).unwrap_or_else(|e| {
+            serde_json::to_string(&RpcResponse::error(Value::Null, SERVER_ERROR, e)).unwrap()
+        });
+        out.push('\n');
+        write.write_all(out.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Run a single JSON-RPC request line against the application server.
+/// Notifications only get a response if they can't be parsed, authorized or queued.
+/// Operator commands are only run if `token` is set and the request carries it.
+pub async fn dispatch(chan: &AppChannel, line: &str, token: Option<&str>) -> Option<RpcResponse> {
+    let request: RpcRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(RpcResponse::error(Value::Null, PARSE_ERROR, e)),
+    };
+    let id = request.id.clone().flatten().unwrap_or(Value::Null);
+    let mut kind: AppQueryKind =
+        match serde_json::from_value(json!({ "method": request.method, "params": request.params })) {
+            Ok(kind) => kind,
+            Err(e) => return Some(RpcResponse::error(id, INVALID_PARAMS, e)),
+        };
+    if kind.principal_mut().is_none() && (token.is_none() || request.token.as_deref() != token) {
+        let message = format!("{} is an operator command, and needs the server's RPC token", kind.name());
+        return Some(RpcResponse::error(id, UNAUTHORIZED, message));
+    }
+    if request.id.is_none() {
+        return send_query_detached(chan, kind, request.on_behalf_of)
+            .err()
+            .map(|e| RpcResponse::error(id, SERVER_ERROR, e));
+    }
+    Some(match send_query(chan, kind, request.on_behalf_of).await {
+        Ok(response) => RpcResponse::result(id, response),
+        Err(e) => RpcResponse::error(id, SERVER_ERROR, e),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::context::AppQuery;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_null_id_is_not_a_notification() {
+        let (chan, mut recv) = mpsc::channel::<AppQuery>(4);
+        tokio::spawn(async move {
+            while let Some(q) = recv.recv().await {
+                q.answer_with(|_| Ok(AppResponse::Teams(vec![])));
+            }
+        });
+        let line = r#"{"jsonrpc": "2.0", "id": null, "method": "GetMyTeams", "params": {"uid": "User::\"kesha\""}}"#;
+        let response = dispatch(&chan, line, None).await.unwrap();
+        assert_eq!(response.id, Value::Null);
+        assert!(response.result.is_some());
+
+        let line = r#"{"jsonrpc": "2.0", "method": "GetMyTeams", "params": {"uid": "User::\"kesha\""}}"#;
+        assert!(dispatch(&chan, line, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_operator_commands_need_token() {
+        let (chan, mut recv) = mpsc::channel(4);
+        let line = r#"{"jsonrpc": "2.0", "method": "UpdatePolicySetSrc", "params": "permit(principal, action, resource);"}"#;
+        let response = dispatch(&chan, line, None).await.unwrap();
+        assert_eq!(response.error.unwrap().code, UNAUTHORIZED);
+        let response = dispatch(&chan, line, Some("secret")).await.unwrap();
+        assert_eq!(response.error.unwrap().code, UNAUTHORIZED);
+        assert!(recv.try_recv().is_err());
+
+        let line = r#"{"jsonrpc": "2.0", "method": "UpdatePolicySetSrc", "params": "permit(principal, action, resource);", "token": "secret"}"#;
+        assert!(dispatch(&chan, line, Some("secret")).await.is_none());
+        assert!(recv.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lists_for_principals_needs_token() {
+        let (chan, mut recv) = mpsc::channel(4);
+        let line = r#"{"jsonrpc": "2.0", "id": 1, "method": "GetListsForPrincipals", "params": {"principals": ["User::\"kesha\""]}}"#;
+        let response = dispatch(&chan, line, Some("secret")).await.unwrap();
+        assert_eq!(response.error.unwrap().code, UNAUTHORIZED);
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preview_policy_needs_token() {
+        let (chan, mut recv) = mpsc::channel(4);
+        let line = r#"{"jsonrpc": "2.0", "id": 1, "method": "PreviewPolicy",
+            "params": {"principal": "User::\"kesha\"", "policies": "permit(principal, action, resource);"}}"#;
+        let response = dispatch(&chan, line, None).await.unwrap();
+        assert_eq!(response.error.unwrap().code, UNAUTHORIZED);
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_needs_token() {
+        let (chan, mut recv) = mpsc::channel(4);
+        let line = r#"{"jsonrpc": "2.0", "id": 1, "method": "SetMaintenance", "params": true, "on_behalf_of": "User::\"kesha\""}"#;
+        let response = dispatch(&chan, line, Some("secret")).await.unwrap();
+        assert_eq!(response.error.unwrap().code, UNAUTHORIZED);
+        assert!(recv.try_recv().is_err());
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright 2022-2023 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      https://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+// Bucket `i` counts latencies below 2^i microseconds, so the last bucket covers everything over ~35 minutes
+const BUCKETS: usize = 32;
+
+/// A fixed-size latency histogram with power-of-two buckets.
+/// Percentiles are reported as the upper bound of the bucket they fall in.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    count: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().max(1);
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// `q` is a fraction between 0 and 1
+    pub fn percentile(&self, q: f64) -> Duration {
+        let target = ((self.count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryStats {
+    pub kind: &'static str,
+    pub count: u64,
+    pub p50_micros: u128,
+    pub p95_micros: u128,
+}
+
+impl QueryStats {
+    pub fn new(kind: &'static str, histogram: &LatencyHistogram) -> Self {
+        Self {
+            kind,
+            count: histogram.count(),
+            p50_micros: histogram.percentile(0.5).as_micros(),
+            p95_micros: histogram.percentile(0.95).as_micros(),
+        }
+    }
+}
+
+/// Where the server spent its time on one query, for clients that ask with `include_timing`
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RequestTiming {
+    pub total_micros: u128,
+    /// Deciding requests, including loading the entities the policies refer to
+    pub authorization_micros: u128,
+    /// Running the SQL queries which find the lists a user may access
+    pub sql_micros: u128,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DecisionCacheStats {
+    pub hits: u64,
+    /// The hits which were denials, e.g. a client retrying a request it isn't allowed to make
+    pub deny_hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// How many of each kind of entity the store holds, for watching the database grow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    pub users: i64,
+    pub teams: i64,
+    pub lists: i64,
+    pub tasks: i64,
+    /// Users in teams plus subteams in teams
+    pub memberships: i64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let mut h = LatencyHistogram::default();
+        assert_eq!(h.percentile(0.5), Duration::ZERO);
+        for _ in 0..90 {
+            h.record(Duration::from_micros(3));
+        }
+        for _ in 0..10 {
+            h.record(Duration::from_millis(10));
+        }
+        assert_eq!(h.count(), 100);
+        // 3us falls below 4us, 10ms below 2^14us
+        assert_eq!(h.percentile(0.5), Duration::from_micros(4));
+        assert_eq!(h.percentile(0.95), Duration::from_micros(1 << 14));
+    }
+}
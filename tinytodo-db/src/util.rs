@@ -14,9 +14,9 @@
  * limitations under the License.
  */
 
-use std::{ops::Deref, str::FromStr};
+use std::{collections::HashMap, ops::Deref, str::FromStr};
 
-use cedar_policy::{EntityTypeName, ParseErrors, RestrictedExpression, EntityId};
+use cedar_policy::{EntityTypeName, ParseErrors, PolicySet, RestrictedExpression, EntityId};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -104,6 +104,7 @@ lazy_static! {
     pub static ref TYPE_USER: EntityTypeName = "User".parse().unwrap();
     pub static ref TYPE_TEAM: EntityTypeName = "Team".parse().unwrap();
     pub static ref TYPE_APP: EntityTypeName = "Application".parse().unwrap();
+    pub static ref TYPE_FOLDER: EntityTypeName = "Folder".parse().unwrap();
 }
 
 // Here we defined a bunch of typed wrappers around `EntityUid`.
@@ -300,6 +301,37 @@ impl From<TeamUid> for EntityUid {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "EntityUid")]
+#[serde(into = "EntityUid")]
+#[repr(transparent)]
+pub struct FolderUid(EntityUid);
+
+impl TryFrom<EntityUid> for FolderUid {
+    type Error = EntityTypeError;
+    fn try_from(got: EntityUid) -> Result<Self, Self::Error> {
+        entity_type_check(&TYPE_FOLDER, got, Self)
+    }
+}
+
+impl AsRef<EntityUid> for FolderUid {
+    fn as_ref(&self) -> &EntityUid {
+        &self.0
+    }
+}
+
+impl From<EntityId> for FolderUid {
+    fn from(id: EntityId) -> Self {
+        Self(EntityUid(cedar_policy::EntityUid::from_type_name_and_id((*TYPE_FOLDER).clone(), id)))
+    }
+}
+
+impl From<FolderUid> for EntityUid {
+    fn from(value: FolderUid) -> Self {
+        value.0
+    }
+}
+
 fn entity_type_check<T>(
     expected: &'static EntityTypeName,
     got: EntityUid,
@@ -342,14 +374,77 @@ impl std::fmt::Display for EntityUid {
     }
 }
 
+/// 64-bit FNV-1a, for hashes which must be the same across restarts and builds
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Lists {
+    lists: Vec<EntityUid>,
+    pagination: Option<Pagination>,
+    labels: Option<HashMap<EntityUid, String>>,
+}
+
 #[derive(Debug, Clone, Serialize)]
-#[repr(transparent)]
-#[serde(transparent)]
-pub struct Lists(Vec<EntityUid>);
+pub struct Pagination {
+    pub total: usize,
+    pub offset: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    pub has_more: bool,
+}
+
+impl Lists {
+    /// The lists from `offset` on, at most `limit` of them, along with where they are in `all`
+    pub fn page(all: Vec<EntityUid>, offset: usize, limit: Option<usize>) -> Self {
+        let total = all.len();
+        let lists = all.into_iter().skip(offset).take(limit.unwrap_or(usize::MAX)).collect::<Vec<_>>();
+        let has_more = offset.saturating_add(lists.len()) < total;
+        Self {
+            lists,
+            pagination: Some(Pagination { total, offset, limit, has_more }),
+            labels: None,
+        }
+    }
+
+    pub fn lists(&self) -> &[EntityUid] {
+        &self.lists
+    }
+
+    /// Include display names for the lists, keyed by uid
+    pub fn with_labels(self, labels: HashMap<EntityUid, String>) -> Self {
+        Self { labels: Some(labels), ..self }
+    }
+}
 
 impl From<Vec<EntityUid>> for Lists {
     fn from(value: Vec<EntityUid>) -> Self {
-        Self(value)
+        Self { lists: value, pagination: None, labels: None }
+    }
+}
+
+// Without pagination or labels, this is the bare array of uids it always was, so existing clients keep working
+impl Serialize for Lists {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct Detailed<'a> {
+            lists: &'a [EntityUid],
+            #[serde(flatten, skip_serializing_if = "Option::is_none")]
+            pagination: Option<&'a Pagination>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            labels: Option<&'a HashMap<EntityUid, String>>,
+        }
+
+        match (&self.pagination, &self.labels) {
+            (None, None) => self.lists.serialize(s),
+            (pagination, labels) => Detailed { lists: &self.lists, pagination: pagination.as_ref(), labels: labels.as_ref() }.serialize(s),
+        }
     }
 }
 
@@ -429,3 +524,68 @@ where
 
     d.deserialize_str(Visitor)
 }
+
+// Policy sets travel over the wire as Cedar source text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct PolicySetSrc(
+    #[serde(serialize_with = "serialize_policy_set")]
+    #[serde(deserialize_with = "deserialize_policy_set")]
+    PolicySet,
+);
+
+impl From<PolicySet> for PolicySetSrc {
+    fn from(value: PolicySet) -> Self {
+        Self(value)
+    }
+}
+
+impl From<PolicySetSrc> for PolicySet {
+    fn from(value: PolicySetSrc) -> Self {
+        value.0
+    }
+}
+
+fn serialize_policy_set<S>(policies: &PolicySet, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(&format!("{policies}"))
+}
+
+fn deserialize_policy_set<'de, D>(d: D) -> Result<PolicySet, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let src = String::deserialize(d)?;
+    src.parse()
+        .map_err(|e| serde::de::Error::custom(format!("{e}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lists_serialization() {
+        let uids = ["a", "b", "c"]
+            .map(|id| format!(r#"List::"{id}""#).parse::<EntityUid>().unwrap())
+            .to_vec();
+        let plain = serde_json::to_value(Lists::from(uids.clone())).unwrap();
+        assert!(plain.is_array());
+
+        let page = serde_json::to_value(Lists::page(uids.clone(), 1, Some(1))).unwrap();
+        assert_eq!(page["lists"].as_array().unwrap().len(), 1);
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["offset"], 1);
+        assert_eq!(page["limit"], 1);
+        assert_eq!(page["has_more"], true);
+
+        let labels = uids.iter().map(|uid| (uid.clone(), uid.id().to_string())).collect();
+        let labelled = serde_json::to_value(Lists::from(uids).with_labels(labels)).unwrap();
+        assert_eq!(labelled["lists"].as_array().unwrap().len(), 3);
+        assert_eq!(labelled["labels"][r#"List::"b""#], "b");
+        assert!(labelled.get("total").is_none());
+    }
+}
@@ -288,7 +288,8 @@ def display_list(l):
 
 
 def display_task(index, task):
-    return '%d: %s %s' % (index, '[ ]' if task['state'] == 'Unchecked' else '[X]', task['name'])
+    marks = {'Unchecked': '[ ]', 'InProgress': '[~]', 'Checked': '[X]'}
+    return '%d: %s %s' % (index, marks[task['state']], task['name'])
 
 
 
@@ -390,7 +391,7 @@ def unshare_list(user, list_id, unshare_with, read_only = True):
 
 
 def toggle_state(s):
-    if s == 'Unchecked':
+    if s == 'Unchecked' or s == 'InProgress':
         return 'Checked'
     elif s == 'Checked':
         return 'Unchecked'